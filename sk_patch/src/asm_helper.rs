@@ -1,12 +1,4 @@
-use std::{
-    env::{self},
-    fs::{DirBuilder, OpenOptions},
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
-    process::{Command, Output},
-};
-
-use anyhow::{anyhow, Ok};
+use crate::assembler::{assemble, assemble_at, Assembly};
 
 #[cfg(not(windows))]
 pub const LINE_ENDLING: &str = "\n";
@@ -48,68 +40,13 @@ macro_rules! aarch64 {
     }};
 }
 
-pub fn asm_to_be_bytes(asm_text: &str) -> anyhow::Result<Vec<u8>> {
-    let current_dir = env::current_dir()?;
-    let temp_dir = &current_dir.join("temp");
-    let _ = DirBuilder::new().recursive(true).create(temp_dir);
-
-    let temp = temp_dir;
-    let input = &temp.join("input.txt");
-    let mut input_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(input)?;
-    input_file.write_all(asm_text.as_bytes())?;
-
-    let output = &temp.join("output.txt");
-    {
-        let _ = OpenOptions::new().create(true).truncate(true).open(output);
-    }
-
-    let output = run_aarch64_as(input.to_str().unwrap())?;
-
-    if output.status.code().unwrap() == 0 {
-        read_bytes_from(&output)
-    } else {
-        Err(anyhow!("output does not exist"))
-    }
-}
-
-fn read_bytes_from(output: &Output) -> anyhow::Result<Vec<u8>> {
-    let mut bytes = vec![];
-    let lines = BufReader::new(output.stdout.as_slice()).lines();
-    for line in lines {
-        let line = line?;
-        if line.trim().is_empty() {
-            continue;
-        }
-        if line.starts_with("AARCH64") {
-            continue;
-        }
-        let fragments = line.split_ascii_whitespace().collect::<Vec<_>>();
-        let hex = fragments[2];
-        let mut inst_be_bytes = u32::from_str_radix(hex, 16)?.to_be_bytes().to_vec();
-        bytes.append(&mut inst_be_bytes);
-    }
-    Ok(bytes)
+/// Assembles `asm_text` into the bytes it occupies in the image, little-endian words.
+pub fn asm_to_bytes(asm_text: &str) -> anyhow::Result<Vec<u8>> {
+    assemble(asm_text)
 }
 
-fn run_aarch64_as(input_file: &str) -> anyhow::Result<Output> {
-    let my_path = env!("PATH");
-    let ndk_home = PathBuf::from(env!("NDK_HOME"));
-    let prebuilt_bin = if cfg!(target_os = "linux") {
-        ndk_home.join("toolchains/aarch64-linux-android-4.9/prebuilt/linux-x86_64/bin")
-    } else {
-        ndk_home.join("toolchains/aarch64-linux-android-4.9/prebuilt/windows-x86_64/bin")
-    };
-    let mut new_path = String::from(prebuilt_bin.to_str().unwrap());
-    new_path.push(':');
-    new_path.push_str(my_path);
-    let mut cmd = Command::new("aarch64-linux-android-as");
-    Ok(cmd
-        .arg("-ahlm")
-        .arg(input_file)
-        .env("PATH", &new_path)
-        .output()?)
+/// Assembles `asm_text` as if it were placed at image offset `base`, keeping its labels
+/// so callers can look up where a given statement ended up.
+pub fn asm_to_assembly(asm_text: &str, base: usize) -> anyhow::Result<Assembly> {
+    assemble_at(asm_text, base as u64)
 }
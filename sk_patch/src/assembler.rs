@@ -0,0 +1,1406 @@
+use std::{collections::HashMap, ops::Range};
+
+use anyhow::{anyhow, Ok};
+
+const COND_CODES: [&str; 16] = [
+    "EQ", "NE", "CS", "CC", "MI", "PL", "VS", "VC", "HI", "LS", "GE", "LT", "GT", "LE", "AL", "NV",
+];
+
+/// (name, op0, op1, CRn, CRm, op2)
+const SYS_REGS: [(&str, u32, u32, u32, u32, u32); 12] = [
+    ("SP_EL0", 3, 0, 4, 1, 0),
+    ("CURRENTEL", 3, 0, 4, 2, 2),
+    ("DAIF", 3, 3, 4, 2, 1),
+    ("NZCV", 3, 3, 4, 2, 0),
+    ("MIDR_EL1", 3, 0, 0, 0, 0),
+    ("MPIDR_EL1", 3, 0, 0, 0, 5),
+    ("SCTLR_EL1", 3, 0, 1, 0, 0),
+    ("TPIDR_EL0", 3, 3, 13, 0, 2),
+    ("TPIDRRO_EL0", 3, 3, 13, 0, 3),
+    ("TPIDR_EL1", 3, 0, 13, 0, 4),
+    ("CNTVCT_EL0", 3, 3, 14, 0, 2),
+    ("CNTFRQ_EL0", 3, 3, 14, 0, 0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg {
+    pub num: u32,
+    pub is64: bool,
+    pub is_sp: bool,
+}
+
+impl Reg {
+    fn sf(&self) -> u32 {
+        if self.is64 {
+            1 << 31
+        } else {
+            0
+        }
+    }
+
+    fn width(&self) -> u32 {
+        if self.is64 {
+            64
+        } else {
+            32
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShiftKind {
+    Lsl,
+    Lsr,
+    Asr,
+    Ror,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtendKind {
+    Uxtw,
+    Lsl,
+    Sxtw,
+    Sxtx,
+}
+
+impl ExtendKind {
+    fn option(&self) -> u32 {
+        match self {
+            ExtendKind::Uxtw => 0b010,
+            ExtendKind::Lsl => 0b011,
+            ExtendKind::Sxtw => 0b110,
+            ExtendKind::Sxtx => 0b111,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemIndex {
+    None,
+    Imm(i64),
+    Reg(Reg, ExtendKind, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem {
+        base: Reg,
+        index: MemIndex,
+        pre_index: bool,
+    },
+    Shift(ShiftKind, u32),
+    Extend(ExtendKind, u32),
+    Ident(String),
+}
+
+/// Machine code produced by [`assemble_at`] together with the resolved symbols.
+#[derive(Debug)]
+pub struct Assembly {
+    pub base: u64,
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u64>,
+    /// Byte ranges emitted by data directives rather than instructions.
+    pub data: Vec<Range<usize>>,
+}
+
+impl Assembly {
+    /// Returns the offset of `label` from the start of the assembled bytes.
+    pub fn offset_of(&self, label: &str) -> anyhow::Result<usize> {
+        match self.symbols.get(label) {
+            Some(&addr) if addr >= self.base => Ok((addr - self.base) as usize),
+            _ => Err(anyhow!("label `{}` is not defined in this assembly", label)),
+        }
+    }
+}
+
+enum Item {
+    Inst {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Data {
+        size: usize,
+        values: Vec<Operand>,
+    },
+    Bytes(Vec<u8>),
+}
+
+struct Statement {
+    line_no: usize,
+    text: String,
+    offset: u64,
+    item: Item,
+}
+
+/// Assembles `asm_text` (one statement per line) into little-endian machine code.
+///
+/// Branch and `ADR`/`ADRP` immediates are byte offsets relative to the instruction itself.
+pub fn assemble(asm_text: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(assemble_at(asm_text, 0)?.bytes)
+}
+
+/// Assembles `asm_text` as if it were placed at `base`.
+///
+/// Besides instructions this understands `label:` definitions, `.equ`/`.set`, `.ascii`,
+/// `.asciz`, `.byte`, `.hword`, `.word`, `.quad`, `.zero`, `.align` and `.balign`.
+/// Labels and `.equ` symbols live in the same address space as `base`, so a branch to
+/// a symbol defined with `.equ` jumps to that absolute address.
+pub fn assemble_at(asm_text: &str, base: u64) -> anyhow::Result<Assembly> {
+    let mut symbols = HashMap::new();
+    let mut statements = vec![];
+    let mut offset = 0u64;
+    for (line_no, line) in asm_text.lines().enumerate() {
+        let at_line = |e: anyhow::Error| anyhow!("line {}: `{}`: {}", line_no + 1, line.trim(), e);
+        let mut line = strip_comment(line).trim();
+        while let Some((label, rest)) = split_label(line) {
+            if symbols.insert(label.to_string(), base + offset).is_some() {
+                return Err(at_line(anyhow!("symbol `{}` redefined", label)));
+            }
+            line = rest;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let item = match parse_statement(line, base + offset, &mut symbols).map_err(at_line)? {
+            Some(item) => item,
+            None => continue,
+        };
+        let size = match &item {
+            Item::Inst { .. } => 4,
+            Item::Data { size, values } => size * values.len(),
+            Item::Bytes(bytes) => bytes.len(),
+        } as u64;
+        statements.push(Statement {
+            line_no,
+            text: line.to_string(),
+            offset,
+            item,
+        });
+        offset += size;
+    }
+
+    let mut bytes = Vec::with_capacity(offset as usize);
+    let mut data: Vec<Range<usize>> = vec![];
+    for statement in statements {
+        let at_line = |e: anyhow::Error| {
+            anyhow!(
+                "line {}: `{}`: {}",
+                statement.line_no + 1,
+                statement.text,
+                e
+            )
+        };
+        let pc = base + statement.offset;
+        let is_inst = matches!(statement.item, Item::Inst { .. });
+        match statement.item {
+            Item::Inst { mnemonic, operands } => {
+                let operands =
+                    resolve_operands(&mnemonic, operands, pc, &symbols).map_err(at_line)?;
+                let inst = encode(&mnemonic, &operands).map_err(at_line)?;
+                bytes.extend_from_slice(&inst.to_le_bytes());
+            }
+            Item::Data { size, values } => {
+                for value in values {
+                    let value = resolve_value(&value, &symbols).map_err(at_line)?;
+                    bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+                }
+            }
+            Item::Bytes(item_bytes) => bytes.extend_from_slice(&item_bytes),
+        }
+        let start = statement.offset as usize;
+        match data.last_mut() {
+            _ if is_inst || bytes.len() == start => (),
+            Some(last) if last.end == start => last.end = bytes.len(),
+            _ => data.push(start..bytes.len()),
+        }
+    }
+    Ok(Assembly {
+        base,
+        bytes,
+        symbols,
+        data,
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, ch) in line.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '/' if !in_string && line[idx..].starts_with("//") => return &line[..idx],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn is_symbol_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let idx = line.find(':')?;
+    let label = line[..idx].trim();
+    if is_symbol_name(label) {
+        Some((label, line[idx + 1..].trim()))
+    } else {
+        None
+    }
+}
+
+fn parse_statement(
+    line: &str,
+    pc: u64,
+    symbols: &mut HashMap<String, u64>,
+) -> anyhow::Result<Option<Item>> {
+    let (mnemonic, rest) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    if !mnemonic.starts_with('.') {
+        let operands = parse_operands(rest)?;
+        return Ok(Some(Item::Inst { mnemonic, operands }));
+    }
+
+    let args = split_operands(rest);
+    let item = match mnemonic.as_str() {
+        ".ASCII" | ".ASCIZ" | ".STRING" => {
+            let mut data = vec![];
+            for arg in args {
+                data.append(&mut parse_string(arg)?);
+                if mnemonic != ".ASCII" {
+                    data.push(0);
+                }
+            }
+            Item::Bytes(data)
+        }
+        ".BYTE" | ".HWORD" | ".SHORT" | ".WORD" | ".LONG" | ".INST" | ".QUAD" | ".XWORD"
+        | ".DWORD" => {
+            let size = match mnemonic.as_str() {
+                ".BYTE" => 1,
+                ".HWORD" | ".SHORT" => 2,
+                ".WORD" | ".LONG" | ".INST" => 4,
+                _ => 8,
+            };
+            let values = args
+                .into_iter()
+                .map(parse_operand)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Item::Data { size, values }
+        }
+        ".ZERO" | ".SKIP" | ".SPACE" => {
+            let size = match args.as_slice() {
+                [size] => parse_imm(size)?,
+                _ => return Err(anyhow!("{} expects a size", mnemonic)),
+            };
+            Item::Bytes(vec![0; usize::try_from(size)?])
+        }
+        ".ALIGN" | ".P2ALIGN" | ".BALIGN" => {
+            let value = match args.first() {
+                Some(value) => parse_imm(value)?,
+                None => return Err(anyhow!("{} expects an alignment", mnemonic)),
+            };
+            let align = if mnemonic == ".BALIGN" {
+                value as u64
+            } else if (0..=16).contains(&value) {
+                1u64 << value
+            } else {
+                return Err(anyhow!("alignment {} out of range", value));
+            };
+            if align == 0 || !align.is_power_of_two() {
+                return Err(anyhow!("alignment {} is not a power of two", align));
+            }
+            let padding = (align - pc % align) % align;
+            Item::Bytes(vec![0; padding as usize])
+        }
+        ".EQU" | ".SET" => {
+            let (name, value) = match args.as_slice() {
+                [name, value] if is_symbol_name(name) => (*name, parse_operand(value)?),
+                _ => return Err(anyhow!("{} expects a name and a value", mnemonic)),
+            };
+            let value = resolve_value(&value, symbols)?;
+            if symbols.insert(name.to_string(), value).is_some() {
+                return Err(anyhow!("symbol `{}` redefined", name));
+            }
+            return Ok(None);
+        }
+        _ => return Err(anyhow!("unsupported directive `{}`", mnemonic)),
+    };
+    Ok(Some(item))
+}
+
+fn parse_string(text: &str) -> anyhow::Result<Vec<u8>> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("expected a quoted string, got `{}`", text))?;
+    let mut data = vec![];
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        let ch = if ch == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(ch @ ('\\' | '"')) => ch,
+                other => return Err(anyhow!("invalid escape {:?}", other)),
+            }
+        } else {
+            ch
+        };
+        let mut buf = [0u8; 4];
+        data.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+    }
+    Ok(data)
+}
+
+fn lookup(name: &str, symbols: &HashMap<String, u64>) -> anyhow::Result<u64> {
+    symbols
+        .get(name)
+        .copied()
+        .ok_or_else(|| anyhow!("undefined symbol `{}`", name))
+}
+
+fn resolve_value(value: &Operand, symbols: &HashMap<String, u64>) -> anyhow::Result<u64> {
+    match value {
+        Operand::Imm(imm) => Ok(*imm as u64),
+        Operand::Ident(name) => lookup(name, symbols),
+        other => Err(anyhow!("expected a value, got {:?}", other)),
+    }
+}
+
+fn is_pc_relative(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "B" | "BL" | "CBZ" | "CBNZ" | "TBZ" | "TBNZ" | "ADR" | "ADRP" | "LDR" | "LDRSW"
+    ) || parse_branch_cond(mnemonic).is_some()
+}
+
+/// Replaces symbol operands with immediates: PC-relative instructions get the distance
+/// from `pc`, everything else the symbol value.
+fn resolve_operands(
+    mnemonic: &str,
+    operands: Vec<Operand>,
+    pc: u64,
+    symbols: &HashMap<String, u64>,
+) -> anyhow::Result<Vec<Operand>> {
+    if mnemonic == "MRS" || mnemonic == "MSR" {
+        return Ok(operands);
+    }
+    operands
+        .into_iter()
+        .map(|operand| match operand {
+            Operand::Ident(name) => {
+                let value = lookup(&name, symbols)?;
+                let imm = if mnemonic == "ADRP" {
+                    ((value & !0xFFF) as i64).wrapping_sub((pc & !0xFFF) as i64)
+                } else if is_pc_relative(mnemonic) {
+                    (value as i64).wrapping_sub(pc as i64)
+                } else {
+                    value as i64
+                };
+                Ok(Operand::Imm(imm))
+            }
+            operand => Ok(operand),
+        })
+        .collect()
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut in_string = false;
+    for (idx, ch) in text.char_indices() {
+        match ch {
+            '"' => in_string = !in_string,
+            _ if in_string => (),
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(text[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    let last = text[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+fn parse_operands(text: &str) -> anyhow::Result<Vec<Operand>> {
+    split_operands(text)
+        .into_iter()
+        .map(parse_operand)
+        .collect()
+}
+
+fn parse_operand(text: &str) -> anyhow::Result<Operand> {
+    if text.starts_with('[') {
+        return parse_mem(text);
+    }
+    if let Some(reg) = parse_reg(text) {
+        return Ok(Operand::Reg(reg));
+    }
+    let upper = text.to_ascii_uppercase();
+    let mut words = upper.split_ascii_whitespace();
+    let head = words.next().unwrap_or_default();
+    let amount = match words.next() {
+        Some(amount) => parse_imm(amount)? as u32,
+        None => 0,
+    };
+    let shift = match head {
+        "LSL" => Some(ShiftKind::Lsl),
+        "LSR" => Some(ShiftKind::Lsr),
+        "ASR" => Some(ShiftKind::Asr),
+        "ROR" => Some(ShiftKind::Ror),
+        _ => None,
+    };
+    if let Some(shift) = shift {
+        return Ok(Operand::Shift(shift, amount));
+    }
+    let extend = match head {
+        "UXTW" => Some(ExtendKind::Uxtw),
+        "UXTX" => Some(ExtendKind::Lsl),
+        "SXTW" => Some(ExtendKind::Sxtw),
+        "SXTX" => Some(ExtendKind::Sxtx),
+        _ => None,
+    };
+    if let Some(extend) = extend {
+        return Ok(Operand::Extend(extend, amount));
+    }
+    if let std::result::Result::Ok(imm) = parse_imm(text) {
+        return Ok(Operand::Imm(imm));
+    }
+    let name = text.strip_prefix('#').unwrap_or(text).trim();
+    if is_symbol_name(name) {
+        return Ok(Operand::Ident(name.to_string()));
+    }
+    Err(anyhow!("invalid operand `{}`", text))
+}
+
+fn parse_mem(text: &str) -> anyhow::Result<Operand> {
+    let (inner, pre_index) = if let Some(inner) = text.strip_suffix("]!") {
+        (&inner[1..], true)
+    } else if let Some(inner) = text.strip_suffix(']') {
+        (&inner[1..], false)
+    } else {
+        return Err(anyhow!("unterminated memory operand `{}`", text));
+    };
+    let parts = split_operands(inner);
+    let base = parts
+        .first()
+        .and_then(|base| parse_reg(base))
+        .filter(|base| base.is64)
+        .ok_or_else(|| anyhow!("invalid base register in `{}`", text))?;
+    let index = match parts.get(1) {
+        None => MemIndex::None,
+        Some(index) => match parse_reg(index) {
+            Some(reg) => {
+                let (extend, amount) = match parts.get(2).map(|ext| parse_operand(ext)) {
+                    None => (
+                        if reg.is64 {
+                            ExtendKind::Lsl
+                        } else {
+                            ExtendKind::Uxtw
+                        },
+                        0,
+                    ),
+                    Some(Result::Ok(Operand::Shift(ShiftKind::Lsl, amount))) => {
+                        (ExtendKind::Lsl, amount)
+                    }
+                    Some(Result::Ok(Operand::Extend(extend, amount))) => (extend, amount),
+                    _ => return Err(anyhow!("invalid index extend in `{}`", text)),
+                };
+                MemIndex::Reg(reg, extend, amount)
+            }
+            None => MemIndex::Imm(parse_imm(index)?),
+        },
+    };
+    Ok(Operand::Mem {
+        base,
+        index,
+        pre_index,
+    })
+}
+
+pub fn parse_reg(text: &str) -> Option<Reg> {
+    let upper = text.trim().to_ascii_uppercase();
+    let reg = |num, is64, is_sp| Some(Reg { num, is64, is_sp });
+    match upper.as_str() {
+        "SP" => return reg(31, true, true),
+        "WSP" => return reg(31, false, true),
+        "XZR" => return reg(31, true, false),
+        "WZR" => return reg(31, false, false),
+        "LR" => return reg(30, true, false),
+        "FP" => return reg(29, true, false),
+        _ => (),
+    }
+    let is64 = match upper.as_bytes().first() {
+        Some(b'X') => true,
+        Some(b'W') => false,
+        _ => return None,
+    };
+    match upper[1..].parse::<u32>() {
+        std::result::Result::Ok(num) if num < 31 && !upper[1..].starts_with('+') => {
+            reg(num, is64, false)
+        }
+        _ => None,
+    }
+}
+
+pub fn parse_imm(text: &str) -> anyhow::Result<i64> {
+    let text = text.trim();
+    let text = text.strip_prefix('#').unwrap_or(text).trim();
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)?
+    } else {
+        text.parse::<u64>()?
+    } as i64;
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn parse_cond(text: &str) -> Option<u32> {
+    let text = match text {
+        "HS" => "CS",
+        "LO" => "CC",
+        _ => text,
+    };
+    COND_CODES
+        .iter()
+        .position(|&cond| cond == text)
+        .map(|cond| cond as u32)
+}
+
+/// Parses `B.<cond>` as well as the dot-less `B<cond>` spelling.
+fn parse_branch_cond(mnemonic: &str) -> Option<u32> {
+    mnemonic
+        .strip_prefix("B.")
+        .or_else(|| mnemonic.strip_prefix('B').filter(|c| c.len() == 2))
+        .and_then(parse_cond)
+}
+
+fn parse_sys_reg(text: &str) -> anyhow::Result<u32> {
+    let upper = text.to_ascii_uppercase();
+    let fields = if let Some(&(_, op0, op1, crn, crm, op2)) =
+        SYS_REGS.iter().find(|(name, ..)| *name == upper)
+    {
+        [op0, op1, crn, crm, op2]
+    } else {
+        // generic form: S<op0>_<op1>_C<n>_C<m>_<op2>
+        let parts = upper
+            .strip_prefix('S')
+            .map(|rest| rest.split('_').collect::<Vec<_>>())
+            .unwrap_or_default();
+        if parts.len() != 5 {
+            return Err(anyhow!("unknown system register `{}`", text));
+        }
+        let field = |s: &str| {
+            s.trim_start_matches('C')
+                .parse::<u32>()
+                .map_err(|_| anyhow!("unknown system register `{}`", text))
+        };
+        [
+            field(parts[0])?,
+            field(parts[1])?,
+            field(parts[2])?,
+            field(parts[3])?,
+            field(parts[4])?,
+        ]
+    };
+    let [op0, op1, crn, crm, op2] = fields;
+    if !(2..=3).contains(&op0) || op1 > 7 || crn > 15 || crm > 15 || op2 > 7 {
+        return Err(anyhow!("invalid system register `{}`", text));
+    }
+    Ok((op0 - 2) << 19 | op1 << 16 | crn << 12 | crm << 8 | op2 << 5)
+}
+
+/// Encodes `imm` as an AArch64 logical (bitmask) immediate, returning `N:immr:imms`.
+pub fn encode_bitmask_imm(imm: u64, is64: bool) -> Option<u32> {
+    let imm = if is64 {
+        imm
+    } else {
+        if imm >> 32 != 0 {
+            return None;
+        }
+        imm | imm << 32
+    };
+    if imm == 0 || imm == u64::MAX {
+        return None;
+    }
+    let mut size = 64;
+    while size > 2 {
+        let half = size / 2;
+        let mask = (1u64 << half) - 1;
+        if imm & mask != (imm >> half) & mask {
+            break;
+        }
+        size = half;
+    }
+    let mask = if size == 64 {
+        u64::MAX
+    } else {
+        (1u64 << size) - 1
+    };
+    let elem = imm & mask;
+    let ones = elem.count_ones();
+    let pattern = (1u64 << ones) - 1;
+    let ror = |value: u64, amount: u32| {
+        if amount == 0 {
+            value
+        } else {
+            ((value >> amount) | (value << (size - amount))) & mask
+        }
+    };
+    let rotation = (0..size).find(|&r| ror(pattern, r) == elem)?;
+    let n = (size == 64) as u32;
+    let imms = ((!(size - 1) << 1) | (ones - 1)) & 0x3f;
+    Some(n << 12 | rotation << 6 | imms)
+}
+
+fn expect_reg(operands: &[Operand], idx: usize) -> anyhow::Result<Reg> {
+    match operands.get(idx) {
+        Some(Operand::Reg(reg)) => Ok(*reg),
+        other => Err(anyhow!(
+            "operand {} expects a register, got {:?}",
+            idx + 1,
+            other
+        )),
+    }
+}
+
+fn expect_imm(operands: &[Operand], idx: usize) -> anyhow::Result<i64> {
+    match operands.get(idx) {
+        Some(Operand::Imm(imm)) => Ok(*imm),
+        other => Err(anyhow!(
+            "operand {} expects an immediate, got {:?}",
+            idx + 1,
+            other
+        )),
+    }
+}
+
+fn expect_count(operands: &[Operand], counts: &[usize]) -> anyhow::Result<()> {
+    if counts.contains(&operands.len()) {
+        Ok(())
+    } else {
+        Err(anyhow!("unexpected operand count {}", operands.len()))
+    }
+}
+
+fn same_width(regs: &[Reg]) -> anyhow::Result<()> {
+    if regs.windows(2).all(|w| w[0].is64 == w[1].is64) {
+        Ok(())
+    } else {
+        Err(anyhow!("mixed W and X registers"))
+    }
+}
+
+fn signed_field(value: i64, bits: u32, scale: i64) -> anyhow::Result<u32> {
+    if value % scale != 0 {
+        return Err(anyhow!("offset {} is not a multiple of {}", value, scale));
+    }
+    let value = value / scale;
+    let limit = 1i64 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(anyhow!("offset {} out of range", value * scale));
+    }
+    Ok((value as u32) & ((1u32 << bits) - 1))
+}
+
+fn encode(mnemonic: &str, operands: &[Operand]) -> anyhow::Result<u32> {
+    match mnemonic {
+        "NOP" => Ok(0xD503201F),
+        "PACIASP" => Ok(0xD503233F),
+        "AUTIASP" => Ok(0xD50323BF),
+        "HINT" => {
+            let imm = expect_imm(operands, 0)?;
+            if !(0..128).contains(&imm) {
+                return Err(anyhow!("hint {} out of range", imm));
+            }
+            Ok(0xD503201F | (imm as u32) << 5)
+        }
+        "BRK" => {
+            let imm = expect_imm(operands, 0)?;
+            if !(0..0x10000).contains(&imm) {
+                return Err(anyhow!("brk {} out of range", imm));
+            }
+            Ok(0xD4200000 | (imm as u32) << 5)
+        }
+        "RET" => {
+            let rn = match operands.first() {
+                Some(_) => expect_reg(operands, 0)?.num,
+                None => 30,
+            };
+            Ok(0xD65F0000 | rn << 5)
+        }
+        "BR" => Ok(0xD61F0000 | expect_reg(operands, 0)?.num << 5),
+        "BLR" => Ok(0xD63F0000 | expect_reg(operands, 0)?.num << 5),
+        "B" | "BL" => {
+            expect_count(operands, &[1])?;
+            let imm26 = signed_field(expect_imm(operands, 0)?, 26, 4)?;
+            let base = if mnemonic == "B" {
+                0x14000000
+            } else {
+                0x94000000
+            };
+            Ok(base | imm26)
+        }
+        "CBZ" | "CBNZ" => {
+            expect_count(operands, &[2])?;
+            let rt = expect_reg(operands, 0)?;
+            let imm19 = signed_field(expect_imm(operands, 1)?, 19, 4)?;
+            let base = if mnemonic == "CBZ" {
+                0x34000000
+            } else {
+                0x35000000
+            };
+            Ok(base | rt.sf() | imm19 << 5 | rt.num)
+        }
+        "TBZ" | "TBNZ" => {
+            expect_count(operands, &[3])?;
+            let rt = expect_reg(operands, 0)?;
+            let bit = expect_imm(operands, 1)?;
+            if bit < 0 || bit >= rt.width() as i64 {
+                return Err(anyhow!("bit {} out of range", bit));
+            }
+            let bit = bit as u32;
+            let imm14 = signed_field(expect_imm(operands, 2)?, 14, 4)?;
+            let base = if mnemonic == "TBZ" {
+                0x36000000
+            } else {
+                0x37000000
+            };
+            Ok(base | (bit >> 5) << 31 | (bit & 0x1f) << 19 | imm14 << 5 | rt.num)
+        }
+        "ADR" | "ADRP" => {
+            expect_count(operands, &[2])?;
+            let rd = expect_reg(operands, 0)?;
+            let offset = expect_imm(operands, 1)?;
+            let (base, imm) = if mnemonic == "ADR" {
+                (0x10000000, signed_field(offset, 21, 1)?)
+            } else {
+                (0x90000000, signed_field(offset, 21, 4096)?)
+            };
+            Ok(base | (imm & 3) << 29 | (imm >> 2) << 5 | rd.num)
+        }
+        "MRS" => {
+            expect_count(operands, &[2])?;
+            let rt = expect_reg(operands, 0)?;
+            match &operands[1] {
+                Operand::Ident(name) => Ok(0xD5300000 | parse_sys_reg(name)? | rt.num),
+                other => Err(anyhow!("invalid system register {:?}", other)),
+            }
+        }
+        "MSR" => {
+            expect_count(operands, &[2])?;
+            let rt = expect_reg(operands, 1)?;
+            match &operands[0] {
+                Operand::Ident(name) => Ok(0xD5100000 | parse_sys_reg(name)? | rt.num),
+                other => Err(anyhow!("invalid system register {:?}", other)),
+            }
+        }
+        "MOV" => encode_mov(operands),
+        "MOVZ" | "MOVN" | "MOVK" => {
+            let rd = expect_reg(operands, 0)?;
+            let imm = expect_imm(operands, 1)?;
+            let shift = match operands.get(2) {
+                None => 0,
+                Some(Operand::Shift(ShiftKind::Lsl, amount)) => *amount,
+                Some(other) => return Err(anyhow!("invalid shift {:?}", other)),
+            };
+            if shift % 16 != 0 || shift >= rd.width() || !(0..0x10000).contains(&imm) {
+                return Err(anyhow!("invalid wide immediate"));
+            }
+            let base = match mnemonic {
+                "MOVZ" => 0x52800000,
+                "MOVN" => 0x12800000,
+                _ => 0x72800000,
+            };
+            Ok(base | rd.sf() | (shift / 16) << 21 | (imm as u32) << 5 | rd.num)
+        }
+        "ADD" | "ADDS" | "SUB" | "SUBS" => {
+            expect_count(operands, &[3, 4])?;
+            let rd = expect_reg(operands, 0)?;
+            let rn = expect_reg(operands, 1)?;
+            encode_add_sub(mnemonic, rd, rn, &operands[2..])
+        }
+        "CMP" | "CMN" => {
+            expect_count(operands, &[2, 3])?;
+            let rn = expect_reg(operands, 0)?;
+            let zr = Reg {
+                num: 31,
+                is64: rn.is64,
+                is_sp: false,
+            };
+            let mnemonic = if mnemonic == "CMP" { "SUBS" } else { "ADDS" };
+            encode_add_sub(mnemonic, zr, rn, &operands[1..])
+        }
+        "AND" | "ANDS" | "ORR" | "EOR" | "BIC" | "BICS" | "ORN" | "EON" => {
+            expect_count(operands, &[3, 4])?;
+            let rd = expect_reg(operands, 0)?;
+            let rn = expect_reg(operands, 1)?;
+            encode_logical(mnemonic, rd, rn, &operands[2..])
+        }
+        "TST" => {
+            expect_count(operands, &[2, 3])?;
+            let rn = expect_reg(operands, 0)?;
+            let zr = Reg {
+                num: 31,
+                is64: rn.is64,
+                is_sp: false,
+            };
+            encode_logical("ANDS", zr, rn, &operands[1..])
+        }
+        "MVN" => {
+            expect_count(operands, &[2, 3])?;
+            let rd = expect_reg(operands, 0)?;
+            let zr = Reg {
+                num: 31,
+                is64: rd.is64,
+                is_sp: false,
+            };
+            encode_logical("ORN", rd, zr, &operands[1..])
+        }
+        "LSL" | "LSR" | "ASR" => {
+            expect_count(operands, &[3])?;
+            let rd = expect_reg(operands, 0)?;
+            let rn = expect_reg(operands, 1)?;
+            same_width(&[rd, rn])?;
+            let width = rd.width();
+            let shift = expect_imm(operands, 2)?;
+            if shift < 0 || shift >= width as i64 {
+                return Err(anyhow!("shift {} out of range", shift));
+            }
+            let shift = shift as u32;
+            let (base, immr, imms) = match mnemonic {
+                "LSL" => (0x53000000, (width - shift) % width, width - 1 - shift),
+                "LSR" => (0x53000000, shift, width - 1),
+                _ => (0x13000000, shift, width - 1),
+            };
+            let n = if rd.is64 { 1 << 22 } else { 0 };
+            Ok(base | rd.sf() | n | immr << 16 | imms << 10 | rn.num << 5 | rd.num)
+        }
+        "LDXR" | "LDAXR" => {
+            expect_count(operands, &[2])?;
+            let rt = expect_reg(operands, 0)?;
+            let rn = expect_exclusive_base(operands, 1)?;
+            let base = if mnemonic == "LDXR" {
+                0x885F7C00
+            } else {
+                0x885FFC00
+            };
+            Ok(base | (rt.is64 as u32) << 30 | rn.num << 5 | rt.num)
+        }
+        "STXR" | "STLXR" => {
+            expect_count(operands, &[3])?;
+            let rs = expect_reg(operands, 0)?;
+            let rt = expect_reg(operands, 1)?;
+            let rn = expect_exclusive_base(operands, 2)?;
+            if rs.is64 {
+                return Err(anyhow!("status register must be a W register"));
+            }
+            let base = if mnemonic == "STXR" {
+                0x88007C00
+            } else {
+                0x8800FC00
+            };
+            Ok(base | (rt.is64 as u32) << 30 | rs.num << 16 | rn.num << 5 | rt.num)
+        }
+        "LDP" | "STP" => encode_load_store_pair(mnemonic == "LDP", operands),
+        "LDR" | "STR" | "LDRB" | "STRB" | "LDRH" | "STRH" | "LDRSW" | "LDUR" | "STUR" | "LDURB"
+        | "STURB" | "LDURH" | "STURH" => encode_load_store(mnemonic, operands),
+        _ => match parse_branch_cond(mnemonic) {
+            Some(cond) => {
+                expect_count(operands, &[1])?;
+                let imm19 = signed_field(expect_imm(operands, 0)?, 19, 4)?;
+                Ok(0x54000000 | imm19 << 5 | cond)
+            }
+            None => Err(anyhow!("unsupported instruction `{}`", mnemonic)),
+        },
+    }
+}
+
+fn expect_exclusive_base(operands: &[Operand], idx: usize) -> anyhow::Result<Reg> {
+    match operands.get(idx) {
+        Some(Operand::Mem {
+            base,
+            index: MemIndex::None,
+            pre_index: false,
+        }) => Ok(*base),
+        Some(Operand::Mem {
+            base,
+            index: MemIndex::Imm(0),
+            pre_index: false,
+        }) => Ok(*base),
+        other => Err(anyhow!("invalid exclusive address {:?}", other)),
+    }
+}
+
+fn encode_mov(operands: &[Operand]) -> anyhow::Result<u32> {
+    expect_count(operands, &[2])?;
+    let rd = expect_reg(operands, 0)?;
+    match &operands[1] {
+        Operand::Reg(rm) => {
+            same_width(&[rd, *rm])?;
+            if rd.is_sp || rm.is_sp {
+                Ok(0x11000000 | rd.sf() | rm.num << 5 | rd.num)
+            } else {
+                Ok(0x2A0003E0 | rd.sf() | rm.num << 16 | rd.num)
+            }
+        }
+        Operand::Imm(imm) => {
+            let imm = *imm as u64;
+            let mask = if rd.is64 { u64::MAX } else { 0xFFFF_FFFF };
+            let value = if rd.is64 {
+                imm
+            } else if imm >> 32 == 0 || imm >> 31 == 0x1_FFFF_FFFF {
+                imm & mask
+            } else {
+                return Err(anyhow!("immediate {:#x} too large for W register", imm));
+            };
+            let chunks = rd.width() / 16;
+            for (base, candidate) in [(0x52800000, value), (0x12800000, !value & mask)] {
+                for hw in 0..chunks {
+                    if candidate & !(0xFFFFu64 << (hw * 16)) == 0 {
+                        let imm16 = ((candidate >> (hw * 16)) & 0xFFFF) as u32;
+                        return Ok(base | rd.sf() | hw << 21 | imm16 << 5 | rd.num);
+                    }
+                }
+            }
+            match encode_bitmask_imm(value, rd.is64) {
+                Some(bitmask) => Ok(0x320003E0 | rd.sf() | bitmask << 10 | rd.num),
+                None => Err(anyhow!(
+                    "immediate {:#x} cannot be moved in a single instruction",
+                    value
+                )),
+            }
+        }
+        other => Err(anyhow!("invalid mov source {:?}", other)),
+    }
+}
+
+fn encode_add_sub(mnemonic: &str, rd: Reg, rn: Reg, rest: &[Operand]) -> anyhow::Result<u32> {
+    let (op, set_flags) = match mnemonic {
+        "ADD" => (0, false),
+        "ADDS" => (0, true),
+        "SUB" => (1, false),
+        _ => (1, true),
+    };
+    match rest.first() {
+        Some(Operand::Imm(imm)) => {
+            same_width(&[rd, rn])?;
+            let op = if *imm < 0 { op ^ 1 } else { op };
+            let imm = imm.unsigned_abs();
+            let (shift, imm12) = match rest.get(1) {
+                None if imm < 0x1000 => (0, imm),
+                None if imm & 0xFFF == 0 && imm < 0x1000000 => (1, imm >> 12),
+                Some(Operand::Shift(ShiftKind::Lsl, 12)) if imm < 0x1000 => (1, imm),
+                Some(Operand::Shift(ShiftKind::Lsl, 0)) if imm < 0x1000 => (0, imm),
+                _ => return Err(anyhow!("immediate {} out of range", imm)),
+            };
+            Ok(0x11000000
+                | rd.sf()
+                | op << 30
+                | (set_flags as u32) << 29
+                | shift << 22
+                | (imm12 as u32) << 10
+                | rn.num << 5
+                | rd.num)
+        }
+        Some(Operand::Reg(rm)) => {
+            let base = rd.sf() | op << 30 | (set_flags as u32) << 29;
+            if rd.is_sp || rn.is_sp {
+                // extended register form, required when SP is involved
+                let option = if rm.is64 { 0b011 } else { 0b010 };
+                let amount = match rest.get(1) {
+                    None => 0,
+                    Some(Operand::Shift(ShiftKind::Lsl, amount)) if *amount <= 4 => *amount,
+                    Some(Operand::Extend(_, amount)) if *amount <= 4 => *amount,
+                    Some(other) => return Err(anyhow!("invalid extend {:?}", other)),
+                };
+                return Ok(0x0B200000
+                    | base
+                    | rm.num << 16
+                    | option << 13
+                    | amount << 10
+                    | rn.num << 5
+                    | rd.num);
+            }
+            same_width(&[rd, rn, *rm])?;
+            let (shift, amount) = parse_reg_shift(rest.get(1), rd.width())?;
+            if shift == ShiftKind::Ror {
+                return Err(anyhow!("ROR is not allowed here"));
+            }
+            Ok(0x0B000000
+                | base
+                | (shift as u32) << 22
+                | rm.num << 16
+                | amount << 10
+                | rn.num << 5
+                | rd.num)
+        }
+        other => Err(anyhow!("invalid operand {:?}", other)),
+    }
+}
+
+fn parse_reg_shift(operand: Option<&Operand>, width: u32) -> anyhow::Result<(ShiftKind, u32)> {
+    match operand {
+        None => Ok((ShiftKind::Lsl, 0)),
+        Some(Operand::Shift(shift, amount)) if *amount < width => Ok((*shift, *amount)),
+        Some(other) => Err(anyhow!("invalid shift {:?}", other)),
+    }
+}
+
+fn encode_logical(mnemonic: &str, rd: Reg, rn: Reg, rest: &[Operand]) -> anyhow::Result<u32> {
+    let (opc, invert) = match mnemonic {
+        "AND" => (0b00, false),
+        "BIC" => (0b00, true),
+        "ORR" => (0b01, false),
+        "ORN" => (0b01, true),
+        "EOR" => (0b10, false),
+        "EON" => (0b10, true),
+        "ANDS" => (0b11, false),
+        _ => (0b11, true),
+    };
+    match rest.first() {
+        Some(Operand::Imm(imm)) => {
+            same_width(&[rd, rn])?;
+            if rest.len() > 1 {
+                return Err(anyhow!("logical immediate takes no shift"));
+            }
+            let mask = if rd.is64 { u64::MAX } else { 0xFFFF_FFFF };
+            let imm = *imm as u64 & mask;
+            let imm = if invert { !imm & mask } else { imm };
+            let bitmask = encode_bitmask_imm(imm, rd.is64)
+                .ok_or_else(|| anyhow!("immediate {:#x} is not a valid bitmask", imm))?;
+            Ok(0x12000000 | rd.sf() | opc << 29 | bitmask << 10 | rn.num << 5 | rd.num)
+        }
+        Some(Operand::Reg(rm)) => {
+            same_width(&[rd, rn, *rm])?;
+            let (shift, amount) = parse_reg_shift(rest.get(1), rd.width())?;
+            Ok(0x0A000000
+                | rd.sf()
+                | opc << 29
+                | (shift as u32) << 22
+                | (invert as u32) << 21
+                | rm.num << 16
+                | amount << 10
+                | rn.num << 5
+                | rd.num)
+        }
+        other => Err(anyhow!("invalid operand {:?}", other)),
+    }
+}
+
+fn encode_load_store_pair(load: bool, operands: &[Operand]) -> anyhow::Result<u32> {
+    expect_count(operands, &[3, 4])?;
+    let rt = expect_reg(operands, 0)?;
+    let rt2 = expect_reg(operands, 1)?;
+    same_width(&[rt, rt2])?;
+    let scale = if rt.is64 { 8 } else { 4 };
+    let (base, offset, mode) = match (&operands[2], operands.get(3)) {
+        (
+            Operand::Mem {
+                base,
+                index,
+                pre_index,
+            },
+            None,
+        ) => {
+            let offset = match index {
+                MemIndex::None => 0,
+                MemIndex::Imm(imm) => *imm,
+                _ => return Err(anyhow!("register offset not allowed for pair")),
+            };
+            (*base, offset, if *pre_index { 0b011 } else { 0b010 })
+        }
+        (
+            Operand::Mem {
+                base,
+                index: MemIndex::None,
+                pre_index: false,
+            },
+            Some(Operand::Imm(imm)),
+        ) => (*base, *imm, 0b001),
+        _ => return Err(anyhow!("invalid pair addressing mode")),
+    };
+    let imm7 = signed_field(offset, 7, scale)?;
+    let opc = if rt.is64 { 0b10 } else { 0b00 };
+    Ok(opc << 30
+        | 0x28000000
+        | mode << 23
+        | (load as u32) << 22
+        | imm7 << 15
+        | rt2.num << 10
+        | base.num << 5
+        | rt.num)
+}
+
+fn encode_load_store(mnemonic: &str, operands: &[Operand]) -> anyhow::Result<u32> {
+    expect_count(operands, &[2, 3])?;
+    let rt = expect_reg(operands, 0)?;
+    // (size, opc)
+    let (size, opc, unscaled_only) = match mnemonic {
+        "LDR" | "LDUR" => (if rt.is64 { 3 } else { 2 }, 0b01, mnemonic == "LDUR"),
+        "STR" | "STUR" => (if rt.is64 { 3 } else { 2 }, 0b00, mnemonic == "STUR"),
+        "LDRB" | "LDURB" => (0, 0b01, mnemonic == "LDURB"),
+        "STRB" | "STURB" => (0, 0b00, mnemonic == "STURB"),
+        "LDRH" | "LDURH" => (1, 0b01, mnemonic == "LDURH"),
+        "STRH" | "STURH" => (1, 0b00, mnemonic == "STURH"),
+        _ => {
+            if !rt.is64 {
+                return Err(anyhow!("LDRSW needs an X register"));
+            }
+            (2, 0b10, false)
+        }
+    };
+    if size < 2 && rt.is64 {
+        return Err(anyhow!("{} needs a W register", mnemonic));
+    }
+    let scale = 1i64 << size;
+    let base_bits = size << 30 | 0x38000000 | opc << 22;
+    match (&operands[1], operands.get(2)) {
+        (Operand::Imm(offset), None) if !unscaled_only && opc == 0b01 || mnemonic == "LDRSW" => {
+            // literal
+            let imm19 = signed_field(*offset, 19, 4)?;
+            let base = match (mnemonic, rt.is64) {
+                ("LDRSW", _) => 0x98000000,
+                (_, true) => 0x58000000,
+                _ => 0x18000000,
+            };
+            if size < 2 {
+                return Err(anyhow!("{} has no literal form", mnemonic));
+            }
+            Ok(base | imm19 << 5 | rt.num)
+        }
+        (
+            Operand::Mem {
+                base,
+                index,
+                pre_index,
+            },
+            None,
+        ) => match index {
+            MemIndex::None | MemIndex::Imm(_) => {
+                let offset = match index {
+                    MemIndex::Imm(imm) => *imm,
+                    _ => 0,
+                };
+                if *pre_index {
+                    let imm9 = signed_field(offset, 9, 1)?;
+                    Ok(base_bits | imm9 << 12 | 0b11 << 10 | base.num << 5 | rt.num)
+                } else if !unscaled_only
+                    && offset >= 0
+                    && offset % scale == 0
+                    && offset / scale < 0x1000
+                {
+                    Ok(base_bits
+                        | 0x01000000
+                        | ((offset / scale) as u32) << 10
+                        | base.num << 5
+                        | rt.num)
+                } else {
+                    let imm9 = signed_field(offset, 9, 1)?;
+                    Ok(base_bits | imm9 << 12 | base.num << 5 | rt.num)
+                }
+            }
+            MemIndex::Reg(rm, extend, amount) => {
+                if unscaled_only {
+                    return Err(anyhow!("{} has no register offset form", mnemonic));
+                }
+                let s = match *amount {
+                    0 => 0,
+                    amount if amount == size => 1,
+                    _ => return Err(anyhow!("invalid index shift {}", amount)),
+                };
+                Ok(base_bits
+                    | 0x00200800
+                    | rm.num << 16
+                    | extend.option() << 13
+                    | s << 12
+                    | base.num << 5
+                    | rt.num)
+            }
+        },
+        (
+            Operand::Mem {
+                base,
+                index: MemIndex::None,
+                pre_index: false,
+            },
+            Some(Operand::Imm(offset)),
+        ) if !unscaled_only => {
+            let imm9 = signed_field(*offset, 9, 1)?;
+            Ok(base_bits | imm9 << 12 | 0b01 << 10 | base.num << 5 | rt.num)
+        }
+        _ => Err(anyhow!("invalid addressing mode")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assemble_one(text: &str) -> u32 {
+        let bytes = assemble(text).unwrap();
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_encode_hook_instructions() {
+        let cases = [
+            ("MOV X0, X0", 0xAA0003E0),
+            ("STP X7, X8, [sp, #-16]!", 0xA9BF23E7),
+            ("LDP X11, X12, [sp], #16", 0xA8C133EB),
+            ("MOV X7, 0xFFFFFFFFFFFFF001", 0x9281FFC7),
+            ("CMP X1, X7", 0xEB07003F),
+            ("BCS #120", 0x540003C2),
+            ("LDR X7, [X1]", 0xF9400027),
+            ("CBZ X7, #112", 0xB4000387),
+            ("ADR X8, #-84", 0x10FFFD68),
+            ("MOV X9, #0", 0xD2800009),
+            ("LDRB W10, [X7, X9]", 0x386968EA),
+            ("CBZ W10, #96", 0x3400030A),
+            ("CMP W10, W11", 0x6B0B015F),
+            ("B.NE #80", 0x54000281),
+            ("ADD X9, X9, 1", 0x91000529),
+            ("CMP X9, #48", 0xF100C13F),
+            ("BLT #-32", 0x54FFFF0B),
+            ("MRS X8, SP_EL0", 0xD5384108),
+            ("LDR X10, [X8, #1560]", 0xF9430D0A),
+            ("MOV W9, WZR", 0x2A1F03E9),
+            ("STR W9, [X10, X7]", 0xB8276949),
+            ("MOV W9, 0xFFFFFFFF", 0x12800009),
+            ("LDXR W10, [X8]", 0x885F7D0A),
+            ("BIC W10, W10,#0xFFF", 0x12144D4A),
+            ("STXR W11, W10, [X8]", 0x880B7D0A),
+            ("STR WZR, [X8, #2048]", 0xB908011F),
+            ("STR XZR, [X8, #2056]", 0xF904051F),
+            ("B #-1024", 0x17FFFF00),
+            ("MOV X9, 0x3FFFFFFFFF", 0xB24097E9),
+            ("B.CC #28", 0x540000E3),
+            ("MOV W0, WZR", 0x2A1F03E0),
+            ("RET", 0xD65F03C0),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(assemble_one(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_encode_general_subset() {
+        let cases = [
+            ("SUB SP, SP, #0x40", 0xD10103FF),
+            ("ADD X0, SP, #16", 0x910043E0),
+            ("MOV X29, SP", 0x910003FD),
+            ("LDR W1, [X2, #-4]", 0xB85FC041),
+            ("STR X3, [X4, #8]!", 0xF8008C83),
+            ("LDR X5, [X6], #-8", 0xF85F84C5),
+            ("LDR X0, [X1, X2, LSL #3]", 0xF8627820),
+            ("LDRSW X0, [X1, #4]", 0xB9800420),
+            ("STRH W0, [X1, #2]", 0x79000420),
+            ("LDP W0, W1, [X2, #8]", 0x29410440),
+            ("BL #-4", 0x97FFFFFF),
+            ("TBNZ W7, #0, #0x40", 0x37000207),
+            ("TBZ X0, #33, #-8", 0xB60FFFC0),
+            ("CBNZ X1, #8", 0xB5000041),
+            ("ADRP X0, #0x3000", 0xF0000000),
+            ("MOVK X0, #0x1234, LSL #16", 0xF2A24680),
+            ("ORR X0, X1, #0xFF", 0xB2401C20),
+            ("AND W0, W1, W2, LSR #4", 0x0A421020),
+            ("TST W0, #1", 0x7200001F),
+            ("CMN X1, #0xFFF", 0xB13FFC3F),
+            ("LSL X0, X1, #3", 0xD37DF020),
+            ("MSR TPIDR_EL1, X0", 0xD518D080),
+            ("LDAXR X0, [X1]", 0xC85FFC20),
+            ("STLXR W2, X0, [X1]", 0xC802FC20),
+            ("PACIASP", 0xD503233F),
+            ("NOP", 0xD503201F),
+            ("BLR X8", 0xD63F0100),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(assemble_one(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_encode_error() {
+        assert!(assemble("MOV X0, #0x123456789").is_err());
+        assert!(assemble("B #2").is_err());
+        assert!(assemble("FOO X0").is_err());
+        assert!(assemble("ADD X0, W1, #1").is_err());
+        assert!(assemble("ADD X0, X1, #-0x8000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_assemble_labels_and_data() {
+        let assembly = assemble_at(
+            r#"
+            .equ back, 0x1000
+            entry:
+                ADR X8, key         // forward reference
+                CBZ X0, end
+            loop: SUB X0, X0, #1
+                B.NE loop
+            end:
+                B back
+            key:
+                .asciz "a//b"
+                .align 2
+                .quad entry
+            "#,
+            0x2000,
+        )
+        .unwrap();
+        let words = assembly
+            .bytes
+            .chunks(4)
+            .take(5)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            words,
+            [0x100000A8, 0xB4000060, 0xD1000400, 0x54FFFFE1, 0x17FFFBFC]
+        );
+        assert_eq!(assembly.offset_of("key").unwrap(), 20);
+        assert_eq!(&assembly.bytes[20..25], b"a//b\0");
+        assert_eq!(assembly.bytes.len(), 20 + 8 + 8);
+        assert_eq!(&assembly.bytes[28..], &0x2000u64.to_le_bytes());
+        assert_eq!(assembly.data, vec![20..36]);
+    }
+
+    #[test]
+    fn test_assemble_symbol_error() {
+        assert!(assemble("B nowhere").is_err());
+        assert!(assemble("a:\na:").is_err());
+        assert!(assemble(".align 2\n.unknown 1").is_err());
+    }
+
+    #[test]
+    fn test_encode_bitmask_imm() {
+        assert_eq!(encode_bitmask_imm(0x5555555555555555, true), Some(0x03C));
+        assert_eq!(encode_bitmask_imm(0xFFFFF000, false), Some(0x513));
+        assert_eq!(encode_bitmask_imm(0, true), None);
+        assert_eq!(encode_bitmask_imm(0x1234, true), None);
+    }
+}
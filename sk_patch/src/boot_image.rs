@@ -0,0 +1,307 @@
+//! Android boot image (`boot.img`) header versions 0 to 4.
+//!
+//! The header occupies the first page and is followed by page aligned sections, the
+//! kernel first. Repacking keeps every header field, section and trailing byte and only
+//! updates what depends on the kernel size.
+
+use std::fmt;
+
+use anyhow::anyhow;
+use sha1::{Digest, Sha1};
+
+const BOOT_MAGIC: &[u8] = b"ANDROID!";
+const VENDOR_BOOT_MAGIC: &[u8] = b"VNDRBOOT";
+/// Header versions 3 and 4 have a fixed page size.
+const V3_PAGE_SIZE: usize = 4096;
+const HEADER_VERSION: usize = 40;
+const PAGE_SIZE: usize = 36;
+const KERNEL_SIZE: usize = 8;
+/// `id` of versions 0 to 2, a SHA-1 of the sections padded to 32 bytes.
+const ID: std::ops::Range<usize> = 576..608;
+const RECOVERY_DTBO_OFFSET: usize = 1636;
+const AVB_FOOTER_MAGIC: &[u8] = b"AVBf";
+const AVB_FOOTER_SIZE: usize = 64;
+
+/// Offsets of the section size fields in the header, in image order.
+fn section_sizes(header_version: u32) -> anyhow::Result<&'static [(&'static str, usize)]> {
+    const V0: &[(&str, usize)] = &[("kernel", 8), ("ramdisk", 16), ("second", 24)];
+    const V1: &[(&str, usize)] = &[
+        ("kernel", 8),
+        ("ramdisk", 16),
+        ("second", 24),
+        ("recovery_dtbo", 1632),
+    ];
+    const V2: &[(&str, usize)] = &[
+        ("kernel", 8),
+        ("ramdisk", 16),
+        ("second", 24),
+        ("recovery_dtbo", 1632),
+        ("dtb", 1648),
+    ];
+    const V3: &[(&str, usize)] = &[("kernel", 8), ("ramdisk", 12)];
+    const V4: &[(&str, usize)] = &[("kernel", 8), ("ramdisk", 12), ("signature", 1580)];
+    match header_version {
+        0 => Ok(V0),
+        1 => Ok(V1),
+        2 => Ok(V2),
+        3 => Ok(V3),
+        4 => Ok(V4),
+        v => Err(anyhow!("unsupported boot image header version {}", v)),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> anyhow::Result<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("boot image header truncated at 0x{:x}", offset))
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn align_up(size: usize, align: usize) -> usize {
+    size.div_ceil(align) * align
+}
+
+/// The `id` mkbootimg stores in headers up to version 2.
+fn sha1_id(header_version: u32, sections: &[Vec<u8>]) -> [u8; 32] {
+    let mut sha1 = Sha1::new();
+    // recovery_dtbo is hashed from version 1 on and dtb from version 2 on, which are
+    // exactly the sections the header has.
+    let hashed = match header_version {
+        0 => 3,
+        1 => 4,
+        _ => 5,
+    };
+    for section in sections.iter().take(hashed) {
+        sha1.update(section);
+        sha1.update((section.len() as u32).to_le_bytes());
+    }
+    let mut id = [0u8; 32];
+    id[..20].copy_from_slice(&sha1.finalize());
+    id
+}
+
+/// The `vendor_boot` header holds the vendor ramdisk, dtb and the rest of the cmdline,
+/// never the kernel.
+fn vendor_boot_error(bytes: &[u8]) -> anyhow::Error {
+    let header_version = read_u32(bytes, 8).unwrap_or_default();
+    let cmdline = bytes.get(28..28 + 2048).map(c_string).unwrap_or_default();
+    anyhow!(
+        "this is a vendor_boot image (header version {}, cmdline \"{}\"), it carries no kernel, pass boot.img instead",
+        header_version,
+        cmdline
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct BootImage {
+    pub header_version: u32,
+    pub page_size: usize,
+    /// The whole first page, header included.
+    header: Vec<u8>,
+    /// Sections in image order, the kernel first.
+    sections: Vec<Vec<u8>>,
+    /// Whatever follows the last section, e.g. an AVB footer.
+    tail: Vec<u8>,
+    /// Whether `id` is the SHA-1 mkbootimg computes and so has to be updated.
+    sha1_id: bool,
+}
+
+impl fmt::Display for BootImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "boot.img header v{}, page size 0x{:x}, kernel 0x{:x} bytes",
+            self.header_version,
+            self.page_size,
+            self.kernel().len()
+        )
+    }
+}
+
+impl BootImage {
+    pub fn is_boot_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(BOOT_MAGIC) || bytes.starts_with(VENDOR_BOOT_MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.starts_with(VENDOR_BOOT_MAGIC) {
+            return Err(vendor_boot_error(bytes));
+        }
+        if !bytes.starts_with(BOOT_MAGIC) {
+            return Err(anyhow!("not an Android boot image"));
+        }
+        let header_version = read_u32(bytes, HEADER_VERSION)?;
+        let sizes = section_sizes(header_version)?;
+        let page_size = if header_version >= 3 {
+            V3_PAGE_SIZE
+        } else {
+            read_u32(bytes, PAGE_SIZE)? as usize
+        };
+        if !page_size.is_power_of_two() || page_size < 2048 {
+            return Err(anyhow!("invalid boot image page size 0x{:x}", page_size));
+        }
+        let header = bytes
+            .get(..page_size)
+            .ok_or_else(|| anyhow!("boot image header truncated"))?
+            .to_vec();
+
+        let mut sections = vec![];
+        let mut offset = page_size;
+        for &(name, field) in sizes {
+            let size = read_u32(bytes, field)? as usize;
+            let section = bytes.get(offset..offset + size).ok_or_else(|| {
+                anyhow!(
+                    "{} section 0x{:x}-0x{:x} is beyond the end of the boot image",
+                    name,
+                    offset,
+                    offset + size
+                )
+            })?;
+            sections.push(section.to_vec());
+            offset += align_up(size, page_size);
+        }
+        let tail = bytes.get(offset..).unwrap_or_default().to_vec();
+        let sha1_id = header_version <= 2 && header[ID] == sha1_id(header_version, &sections);
+        Ok(BootImage {
+            header_version,
+            page_size,
+            header,
+            sections,
+            tail,
+            sha1_id,
+        })
+    }
+
+    pub fn kernel(&self) -> &[u8] {
+        &self.sections[0]
+    }
+
+    /// The kernel command line, with versions 3 and 4 the vendor_boot one is appended
+    /// to it by the bootloader.
+    pub fn cmdline(&self) -> String {
+        match self.header_version {
+            0..=2 => c_string(&self.header[64..576]) + &c_string(&self.header[608..1632]),
+            _ => c_string(&self.header[44..1580]),
+        }
+    }
+
+    /// Builds the boot image again around `kernel`.
+    pub fn repack(&self, kernel: &[u8]) -> Vec<u8> {
+        let mut header = self.header.clone();
+        let mut sections = self.sections.clone();
+        sections[0] = kernel.to_vec();
+        write_u32(&mut header, KERNEL_SIZE, kernel.len() as u32);
+
+        if matches!(self.header_version, 1 | 2) && !sections[3].is_empty() {
+            let offset = self.page_size
+                + sections[..3]
+                    .iter()
+                    .map(|section| align_up(section.len(), self.page_size))
+                    .sum::<usize>();
+            header[RECOVERY_DTBO_OFFSET..RECOVERY_DTBO_OFFSET + 8]
+                .copy_from_slice(&(offset as u64).to_le_bytes());
+        }
+        if self.sha1_id {
+            header[ID].copy_from_slice(&sha1_id(self.header_version, &sections));
+        } else if self.header_version <= 2 {
+            log::warn!("boot image id is not a SHA-1 of its sections, left unchanged");
+        }
+        if self.header_version == 4 && !sections[2].is_empty() {
+            log::warn!("boot signature no longer matches the patched kernel");
+        }
+        if self.tail.len() >= AVB_FOOTER_SIZE
+            && self.tail[self.tail.len() - AVB_FOOTER_SIZE..].starts_with(AVB_FOOTER_MAGIC)
+        {
+            log::warn!("AVB footer no longer matches the patched boot image");
+        }
+
+        let mut image = header;
+        for section in &sections {
+            image.extend_from_slice(section);
+            image.resize(align_up(image.len(), self.page_size), 0);
+        }
+        image.extend_from_slice(&self.tail);
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn boot_image(header_version: u32, page_size: usize, sections: &[&[u8]]) -> Vec<u8> {
+        let mut image = vec![0u8; page_size];
+        image[..8].copy_from_slice(BOOT_MAGIC);
+        write_u32(&mut image, HEADER_VERSION, header_version);
+        if header_version <= 2 {
+            write_u32(&mut image, PAGE_SIZE, page_size as u32);
+            image[64..64 + 12].copy_from_slice(b"console=null");
+        } else {
+            image[44..44 + 12].copy_from_slice(b"console=null");
+        }
+        for (&(name, field), section) in section_sizes(header_version).unwrap().iter().zip(sections)
+        {
+            if name == "recovery_dtbo" {
+                let offset = image.len() as u64;
+                image[RECOVERY_DTBO_OFFSET..RECOVERY_DTBO_OFFSET + 8]
+                    .copy_from_slice(&offset.to_le_bytes());
+            }
+            write_u32(&mut image, field, section.len() as u32);
+            image.extend_from_slice(section);
+            image.resize(align_up(image.len(), page_size), 0);
+        }
+        if header_version <= 2 {
+            let sections: Vec<Vec<u8>> = sections.iter().map(|s| s.to_vec()).collect();
+            image[ID].copy_from_slice(&sha1_id(header_version, &sections));
+        }
+        image
+    }
+
+    #[test]
+    fn test_repack_boot_image() {
+        for (header_version, page_size) in [(0, 2048), (1, 4096), (2, 2048), (3, 4096), (4, 4096)] {
+            let sections: &[&[u8]] = &[b"kernel", b"ramdisk", b"second", b"dtbo", b"dtb"];
+            let count = section_sizes(header_version).unwrap().len();
+            let image = boot_image(header_version, page_size, &sections[..count]);
+
+            let boot = BootImage::parse(&image).unwrap();
+            assert_eq!(boot.header_version, header_version);
+            assert_eq!(boot.page_size, page_size);
+            assert_eq!(boot.kernel(), b"kernel");
+            assert_eq!(boot.cmdline(), "console=null");
+            assert_eq!(boot.repack(b"kernel"), image);
+
+            let kernel = vec![0xAAu8; page_size + 1];
+            let mut patched = sections[..count].to_vec();
+            patched[0] = &kernel;
+            let expected = boot_image(header_version, page_size, &patched);
+            assert_eq!(boot.repack(&kernel), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_boot_image_errors() {
+        let mut vendor_boot = vec![0u8; 4096];
+        vendor_boot[..8].copy_from_slice(VENDOR_BOOT_MAGIC);
+        write_u32(&mut vendor_boot, 8, 4);
+        let e = BootImage::parse(&vendor_boot).unwrap_err();
+        assert!(e.to_string().contains("vendor_boot"));
+
+        let mut truncated = boot_image(2, 2048, &[b"kernel", b"", b"", b"", b""]);
+        truncated.truncate(2048 + 2);
+        assert!(BootImage::parse(&truncated).is_err());
+
+        let mut v5 = boot_image(4, 4096, &[b"kernel", b"", b""]);
+        write_u32(&mut v5, HEADER_VERSION, 5);
+        assert!(BootImage::parse(&v5).is_err());
+    }
+}
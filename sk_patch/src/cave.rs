@@ -0,0 +1,241 @@
+//! Finds zero filled code caves inside the kernel text to hold the hookers.
+//!
+//! On arm64 `_etext` is aligned to `SEGMENT_ALIGN`, so the padding between the last
+//! function and `_etext` is mapped executable and is where the hookers usually go.
+
+use std::{fmt, ops::Range};
+
+use anyhow::anyhow;
+
+use crate::kallsyms::Kallsyms;
+
+const PAGE_SIZE: usize = 0x1000;
+/// Alignment of `_etext` (`SEGMENT_ALIGN`).
+const SEGMENT_ALIGN: usize = 0x10000;
+/// Alignment of a cave start, leaving room after the instruction preceding it.
+const CAVE_ALIGN: usize = 0x10;
+/// Smaller zero runs are function padding and not worth reporting.
+const MIN_CAVE_SIZE: usize = 0x100;
+/// Pages without a `RET` tolerated inside the text of the fallback estimate.
+const MAX_DATA_LIKE_PAGES: usize = 4;
+const RET: u32 = 0xD65F03C0;
+const RETAA: u32 = 0xD65F0BFF;
+
+/// File offsets of the executable kernel text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextRange {
+    pub start: usize,
+    pub end: usize,
+    /// Where the range comes from: the symbols, the `.text` section or an estimate.
+    pub source: &'static str,
+}
+
+impl fmt::Display for TextRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}-0x{:x} ({})", self.start, self.end, self.source)
+    }
+}
+
+impl TextRange {
+    /// Takes `_stext`..`_etext` from the symbols, or the `.text` section of a vmlinux,
+    /// or estimates the range from the code.
+    pub fn of(
+        image: &[u8],
+        kallsyms: Option<&Kallsyms>,
+        text_section: Option<Range<usize>>,
+    ) -> Self {
+        let symbols = kallsyms.and_then(|kallsyms| {
+            let start = kallsyms
+                .file_offset("_stext")
+                .or_else(|| kallsyms.file_offset("_text"))?;
+            let end = kallsyms.file_offset("_etext")?;
+            Some((start, end.min(image.len()), kallsyms.source))
+        });
+        match (symbols, text_section) {
+            (Some((start, end, source)), _) if start < end => TextRange { start, end, source },
+            (_, Some(section)) if section.start < section.end => TextRange {
+                start: section.start,
+                end: section.end.min(image.len()),
+                source: ".text section",
+            },
+            _ => TextRange {
+                start: 0,
+                end: estimate_text_end(image),
+                source: "estimated",
+            },
+        }
+    }
+
+    pub fn contains(&self, offset: usize, size: usize) -> bool {
+        offset >= self.start && offset.saturating_add(size) <= self.end
+    }
+}
+
+fn page_words(page: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    page.chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+}
+
+/// The text runs from the start of the Image through pages containing returns, and
+/// ends after the zero padding following the last of them, at most up to the next
+/// `SEGMENT_ALIGN` boundary.
+fn estimate_text_end(image: &[u8]) -> usize {
+    let mut last_code_end = 0;
+    let mut data_like = 0;
+    for (idx, page) in image.chunks(PAGE_SIZE).enumerate() {
+        if page_words(page).any(|w| w == RET || w == RETAA) {
+            last_code_end = idx * PAGE_SIZE + page.len();
+            data_like = 0;
+        } else if page.iter().any(|&b| b != 0) {
+            data_like += 1;
+            if data_like > MAX_DATA_LIKE_PAGES {
+                break;
+            }
+        }
+    }
+    let limit = last_code_end
+        .div_ceil(SEGMENT_ALIGN)
+        .saturating_mul(SEGMENT_ALIGN)
+        .min(image.len());
+    let zeros = image[last_code_end..limit]
+        .iter()
+        .position(|&b| b != 0)
+        .unwrap_or(limit - last_code_end);
+    last_code_end + zeros
+}
+
+/// A zero filled region inside the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cave {
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl fmt::Display for Cave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x} (0x{:x} bytes)", self.offset, self.size)
+    }
+}
+
+/// Returns the caves inside `range`, largest first.
+pub fn find_caves(image: &[u8], range: &TextRange) -> Vec<Cave> {
+    let mut caves = vec![];
+    let end = range.end.min(image.len());
+    let mut cursor = range.start;
+    while cursor < end {
+        let zero_start = match image[cursor..end].iter().position(|&b| b == 0) {
+            Some(pos) => cursor + pos,
+            None => break,
+        };
+        let zero_end = image[zero_start..end]
+            .iter()
+            .position(|&b| b != 0)
+            .map_or(end, |pos| zero_start + pos);
+        // Keep the first bytes, they may belong to the preceding instruction or data.
+        let offset = (zero_start + CAVE_ALIGN).div_ceil(CAVE_ALIGN) * CAVE_ALIGN;
+        if zero_end >= offset + MIN_CAVE_SIZE {
+            caves.push(Cave {
+                offset,
+                size: zero_end - offset,
+            });
+        }
+        cursor = zero_end;
+    }
+    caves.sort_by(|a, b| b.size.cmp(&a.size).then(a.offset.cmp(&b.offset)));
+    caves
+}
+
+/// Picks the largest cave holding at least `size` bytes.
+pub fn pick_cave(caves: &[Cave], size: usize) -> Option<Cave> {
+    caves.iter().find(|cave| cave.size >= size).copied()
+}
+
+/// Checks that `size` bytes at `offset` are zero, inside the text and start at an
+/// instruction boundary.
+pub fn check_cave(
+    image: &[u8],
+    range: &TextRange,
+    offset: usize,
+    size: usize,
+) -> anyhow::Result<()> {
+    if !offset.is_multiple_of(4) {
+        return Err(anyhow!(
+            "cave offset 0x{:x} is not aligned to 4 bytes, the hookers are instructions",
+            offset
+        ));
+    }
+    if !range.contains(offset, size) {
+        return Err(anyhow!(
+            "0x{:x}-0x{:x} is outside of the kernel text {}",
+            offset,
+            offset + size,
+            range
+        ));
+    }
+    match image[offset..offset + size].iter().position(|&b| b != 0) {
+        Some(pos) => Err(anyhow!(
+            "0x{:x} has non-zero data, 0x{:x} bytes are needed from 0x{:x}",
+            offset + pos,
+            size,
+            offset
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x20000];
+        // Two code pages, a zero run inside the first one, then padding and data.
+        for offset in (0..0x2000).step_by(4) {
+            image[offset..offset + 4].copy_from_slice(&0xD503201Fu32.to_le_bytes());
+        }
+        image[0x1000..0x1004].copy_from_slice(&RET.to_le_bytes());
+        image[0x1FFC..0x2000].copy_from_slice(&RET.to_le_bytes());
+        image[0x400..0x600].fill(0);
+        image[0x10000..].fill(b'A');
+        image
+    }
+
+    #[test]
+    fn test_find_caves() {
+        let image = image();
+        let range = TextRange::of(&image, None, None);
+        assert_eq!(range.start, 0);
+        assert_eq!(range.end, 0x10000);
+        assert_eq!(range.source, "estimated");
+
+        let caves = find_caves(&image, &range);
+        assert_eq!(
+            caves,
+            vec![
+                Cave {
+                    offset: 0x2010,
+                    size: 0xDFF0
+                },
+                Cave {
+                    offset: 0x410,
+                    size: 0x1F0
+                },
+            ]
+        );
+        assert_eq!(pick_cave(&caves, 0x1000), Some(caves[0]));
+        assert_eq!(pick_cave(&caves, 0x10000), None);
+    }
+
+    #[test]
+    fn test_check_cave() {
+        let image = image();
+        let range = TextRange::of(&image, None, None);
+        assert!(check_cave(&image, &range, 0x2010, 0x100).is_ok());
+        assert!(check_cave(&image, &range, 0x1F00, 0x200).is_err());
+        assert!(check_cave(&image, &range, 0xFF00, 0x200).is_err());
+        assert!(check_cave(&image, &range, 0x2012, 0x100)
+            .unwrap_err()
+            .to_string()
+            .contains("not aligned"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::profile::{Location, Profile};
+
+/// Patches the SKRoot hooks into an arm64 kernel Image
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub commands: Option<Commands>,
+    #[command(flatten)]
+    pub patch: PatchArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Report the offsets, hook entries and code caves found in a kernel Image
+    Find {
+        /// The kernel Image
+        #[arg(default_value = "raw_kernel")]
+        image: String,
+    },
+    /// Check the SKRoot hooks in a patched kernel Image and print their root key and offsets
+    Verify {
+        /// The patched kernel Image
+        #[arg(default_value = "raw_kernel")]
+        image: String,
+    },
+    /// Remove the SKRoot hooks from a patched kernel Image, without needing the backup
+    Unpatch {
+        /// The patched kernel Image
+        #[arg(default_value = "raw_kernel")]
+        image: String,
+        /// Print the restoring patches instead of applying them
+        #[arg(long)]
+        dry_run: bool,
+        /// Restore the Image without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Patch a stock kernel Image again as a manifest records, checking the result is identical
+    Replay {
+        /// The manifest written when the Image was patched
+        manifest: PathBuf,
+        /// The stock kernel Image, patched in place [default: the backup of the image named by
+        /// the manifest, the result then replaces that image]
+        image: Option<String>,
+        /// The root keys in their manifest order, needed when the manifest only holds
+        /// their fingerprints
+        #[arg(short, long)]
+        root_key: Vec<String>,
+        /// Write the patches to the Image without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+/// Options of the patch mode, anything not given is taken from the profile or asked for.
+#[derive(clap::Args, Debug, Default)]
+pub struct PatchArgs {
+    /// The kernel Image to patch [default: raw_kernel]
+    pub image: Option<String>,
+    /// TOML or JSON patch profile providing the options below
+    #[arg(short, long)]
+    pub profile: Option<PathBuf>,
+    /// Hex file offset or kernel virtual address the hookers are written to
+    #[arg(long)]
+    pub cave_offset: Option<String>,
+    /// The root key, 48 letters and digits, repeat it to accept several keys
+    #[arg(short, long, conflicts_with = "generate_root_key")]
+    pub root_key: Vec<String>,
+    /// Only grant root to these uids, as a uid, a first-last range or an app like u0_a123,
+    /// u0_a* or u*_a123 where * is any user or app; repeat it for several ranges
+    #[arg(long, value_name = "UIDS")]
+    pub allow_uid: Vec<String>,
+    /// Only store a keyed digest of the root keys in the kernel, not the keys themselves
+    #[arg(long)]
+    pub key_digest: bool,
+    /// Grant this uid instead of root's, with full capabilities unless --capabilities is given
+    #[arg(long, value_name = "UID")]
+    pub grant_uid: Option<u32>,
+    /// Grant this gid [default: the granted uid]
+    #[arg(long, value_name = "GID")]
+    pub grant_gid: Option<u32>,
+    /// Hex mask written to every capability set [default: all of them]
+    #[arg(long, value_name = "MASK")]
+    pub capabilities: Option<String>,
+    /// Leave seccomp and the thread flags of the granted process in place, no seccomp offset
+    /// is needed then
+    #[arg(long)]
+    pub keep_seccomp: bool,
+    /// Hex mask of the thread flags cleared along with seccomp [default: 0xfff]
+    #[arg(long, value_name = "MASK", conflicts_with = "keep_seccomp")]
+    pub thread_flags: Option<String>,
+    /// Generate a random root key
+    #[arg(short, long)]
+    pub generate_root_key: bool,
+    /// Hex offset of cred in task_struct
+    #[arg(long)]
+    pub cred_offset: Option<String>,
+    /// Hex offset of seccomp in task_struct
+    #[arg(long)]
+    pub seccomp_offset: Option<String>,
+    /// Hex file offset, kernel virtual address or symbol name of the do_execve hook entry
+    #[arg(long)]
+    pub do_execve: Option<String>,
+    /// Hex file offset, kernel virtual address or symbol name of the avc_denied hook entry
+    #[arg(long)]
+    pub avc_denied: Option<String>,
+    /// Also hook filldir64 to hide the su_* directories from processes which are not root,
+    /// in every directory of the system
+    #[arg(long)]
+    pub hide_su_dirs: bool,
+    /// Hex file offset, kernel virtual address or symbol name of the filldir64 hook entry
+    #[arg(long, requires = "hide_su_dirs")]
+    pub filldir64: Option<String>,
+    /// Write the patches to the Image without asking
+    #[arg(short, long, conflicts_with = "discard")]
+    pub apply: bool,
+    /// Only check that the patches can be made, leaving the Image untouched
+    #[arg(long)]
+    pub discard: bool,
+    /// Print the planned patches with their disassembly instead of applying them
+    #[arg(long, conflicts_with = "apply")]
+    pub dry_run: bool,
+    /// Also write the planned patches to this file as JSON
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<String>,
+    /// Store the root key itself in the manifest, not only its fingerprint
+    #[arg(long)]
+    pub manifest_key: bool,
+    /// Never prompt, use the detected values for anything not given
+    #[arg(short = 'y', long)]
+    pub non_interactive: bool,
+}
+
+impl PatchArgs {
+    /// Merges the options over the profile they name, if any.
+    pub fn into_profile(self) -> anyhow::Result<Profile> {
+        let profile = match &self.profile {
+            Some(path) => Profile::load(path)?,
+            None => Profile::default(),
+        };
+        let flag = |set: bool| set.then_some(true);
+        let options = Profile {
+            image: self.image,
+            cave_offset: self.cave_offset.map(Location::Text),
+            root_key: (!self.root_key.is_empty()).then_some(self.root_key),
+            allow_uid: (!self.allow_uid.is_empty()).then_some(self.allow_uid),
+            key_digest: flag(self.key_digest),
+            grant_uid: self.grant_uid,
+            grant_gid: self.grant_gid,
+            capabilities: self.capabilities,
+            keep_seccomp: flag(self.keep_seccomp),
+            thread_flags: self.thread_flags,
+            generate_root_key: flag(self.generate_root_key),
+            cred_offset: self.cred_offset.map(Location::Text),
+            seccomp_offset: self.seccomp_offset.map(Location::Text),
+            do_execve: self.do_execve.map(Location::Text),
+            avc_denied: self.avc_denied.map(Location::Text),
+            hide_su_dirs: flag(self.hide_su_dirs),
+            filldir64: self.filldir64.map(Location::Text),
+            apply: flag(self.apply).or(self.discard.then_some(false)),
+            dry_run: flag(self.dry_run),
+            report: self.report,
+            manifest_key: flag(self.manifest_key),
+            non_interactive: flag(self.non_interactive),
+        };
+        Ok(options.or(profile))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::parse_from(["sk_patch", "find", "boot_kernel"]);
+        assert!(matches!(args.commands, Some(Commands::Find { image }) if image == "boot_kernel"));
+        let args = Args::parse_from(["sk_patch", "verify"]);
+        assert!(matches!(args.commands, Some(Commands::Verify { image }) if image == "raw_kernel"));
+        let args = Args::parse_from(["sk_patch", "unpatch", "-y"]);
+        assert!(matches!(
+            args.commands,
+            Some(Commands::Unpatch { image, dry_run: false, yes: true }) if image == "raw_kernel"
+        ));
+        let args = Args::parse_from(["sk_patch", "replay", "boot.img.manifest.json", "-y"]);
+        assert!(matches!(
+            args.commands,
+            Some(Commands::Replay {
+                image: None,
+                root_key,
+                yes: true,
+                ..
+            }) if root_key.is_empty()
+        ));
+
+        let args = Args::parse_from([
+            "sk_patch",
+            "boot_kernel",
+            "-g",
+            "--cred-offset",
+            "0x738",
+            "--do-execve",
+            "do_execveat_common",
+            "--discard",
+            "-y",
+        ]);
+        assert!(args.commands.is_none());
+        let profile = args.patch.into_profile().unwrap();
+        assert_eq!(profile.image.as_deref(), Some("boot_kernel"));
+        assert_eq!(profile.generate_root_key, Some(true));
+        assert_eq!(
+            profile.cred_offset,
+            Some(Location::Text("0x738".to_string()))
+        );
+        assert_eq!(profile.apply, Some(false));
+        assert_eq!(profile.root_key, None);
+        assert!(profile.is_non_interactive());
+
+        let keys = ["a".repeat(48), "b".repeat(48)];
+        let args = Args::parse_from([
+            "sk_patch",
+            "-r",
+            &keys[0],
+            "--root-key",
+            &keys[1],
+            "--allow-uid",
+            "u0_a*",
+            "--key-digest",
+        ]);
+        let profile = args.patch.into_profile().unwrap();
+        assert!(profile.is_key_digest());
+        assert_eq!(profile.root_key, Some(keys.to_vec()));
+        assert_eq!(profile.allow_uid, Some(vec!["u0_a*".to_string()]));
+
+        assert!(Args::try_parse_from(["sk_patch", "--apply", "--discard"]).is_err());
+        assert!(
+            Args::try_parse_from(["sk_patch", "--keep-seccomp", "--thread-flags", "0x100"])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Compressed kernels: `Image.gz`, `Image.lz4` frames and lz4 legacy streams.
+//!
+//! Whatever follows the compressed stream, usually appended DTBs (`Image.gz-dtb`) or the
+//! uncompressed size kbuild appends to lz4 kernels, is kept and written back after the
+//! recompressed kernel.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use anyhow::anyhow;
+use flate2::{bufread::GzDecoder, write::GzEncoder};
+use lz4_flex::frame::{BlockMode, BlockSize, FrameDecoder, FrameEncoder, FrameInfo};
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B, 0x08];
+const LZ4_FRAME_MAGIC: &[u8] = &[0x04, 0x22, 0x4D, 0x18];
+const LZ4_LEGACY_MAGIC: &[u8] = &[0x02, 0x21, 0x4C, 0x18];
+/// Uncompressed size of every lz4 legacy block but the last.
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 << 20;
+const FDT_MAGIC: &[u8] = &[0xD0, 0x0D, 0xFE, 0xED];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    None,
+    Gzip,
+    /// Keeps the `FLG` and `BD` bytes of the frame descriptor.
+    Lz4Frame {
+        flg: u8,
+        bd: u8,
+    },
+    Lz4Legacy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub format: Format,
+    /// Bytes after the compressed stream.
+    trailer: Vec<u8>,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            Format::None => write!(f, "uncompressed")?,
+            Format::Gzip => write!(f, "gzip")?,
+            Format::Lz4Frame { .. } => write!(f, "lz4")?,
+            Format::Lz4Legacy => write!(f, "lz4-legacy")?,
+        }
+        match count_dtbs(&self.trailer) {
+            0 if self.trailer.is_empty() => Ok(()),
+            0 => write!(f, ", 0x{:x} trailing bytes", self.trailer.len()),
+            dtbs => write!(f, ", {} appended DTB(s)", dtbs),
+        }
+    }
+}
+
+/// Counts the flattened device trees at the start of `bytes`.
+fn count_dtbs(bytes: &[u8]) -> usize {
+    let mut count = 0;
+    let mut offset = 0;
+    while bytes[offset..].starts_with(FDT_MAGIC) {
+        let size = match bytes.get(offset + 4..offset + 8) {
+            Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize,
+            None => break,
+        };
+        if size < 8 || offset + size > bytes.len() {
+            break;
+        }
+        count += 1;
+        offset += size;
+    }
+    count
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn decompress_gzip(bytes: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut decoder = GzDecoder::new(bytes);
+    let mut kernel = vec![];
+    decoder.read_to_end(&mut kernel)?;
+    Ok((kernel, decoder.into_inner().to_vec()))
+}
+
+/// Length of the lz4 frame at the start of `bytes`.
+fn lz4_frame_len(bytes: &[u8]) -> anyhow::Result<usize> {
+    let truncated = || anyhow!("lz4 frame truncated");
+    let flg = *bytes.get(4).ok_or_else(truncated)?;
+    let mut offset = 6;
+    if flg & 0x08 != 0 {
+        offset += 8;
+    }
+    if flg & 0x01 != 0 {
+        offset += 4;
+    }
+    offset += 1;
+    loop {
+        let block = read_u32(bytes, offset).ok_or_else(truncated)?;
+        offset += 4;
+        if block == 0 {
+            break;
+        }
+        offset += (block & 0x7FFFFFFF) as usize;
+        if flg & 0x10 != 0 {
+            offset += 4;
+        }
+    }
+    if flg & 0x04 != 0 {
+        offset += 4;
+    }
+    if offset > bytes.len() {
+        return Err(truncated());
+    }
+    Ok(offset)
+}
+
+fn decompress_lz4_frame(bytes: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let len = lz4_frame_len(bytes)?;
+    let mut kernel = vec![];
+    FrameDecoder::new(&bytes[..len]).read_to_end(&mut kernel)?;
+    Ok((kernel, bytes[len..].to_vec()))
+}
+
+fn lz4_frame_info(flg: u8, bd: u8, content_size: usize) -> FrameInfo {
+    let block_size = match (bd >> 4) & 0x7 {
+        4 => BlockSize::Max64KB,
+        5 => BlockSize::Max256KB,
+        6 => BlockSize::Max1MB,
+        _ => BlockSize::Max4MB,
+    };
+    let block_mode = if flg & 0x20 != 0 {
+        BlockMode::Independent
+    } else {
+        BlockMode::Linked
+    };
+    FrameInfo::new()
+        .block_size(block_size)
+        .block_mode(block_mode)
+        .block_checksums(flg & 0x10 != 0)
+        .content_checksum(flg & 0x04 != 0)
+        .content_size((flg & 0x08 != 0).then_some(content_size as u64))
+}
+
+/// The legacy format has no end mark: every block but the last decompresses to
+/// `LZ4_LEGACY_BLOCK_SIZE`, so the stream ends after the first shorter block or at a
+/// block size which does not fit in the file.
+fn decompress_lz4_legacy(bytes: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut kernel = vec![];
+    let mut offset = LZ4_LEGACY_MAGIC.len();
+    while let Some(size) = read_u32(bytes, offset) {
+        let size = size as usize;
+        let block = match bytes.get(offset + 4..offset + 4 + size) {
+            Some(block) if size > 0 => block,
+            _ => break,
+        };
+        let decompressed = lz4_flex::block::decompress(block, LZ4_LEGACY_BLOCK_SIZE)
+            .map_err(|e| anyhow!("lz4 legacy block at 0x{:x}: {}", offset, e))?;
+        kernel.extend_from_slice(&decompressed);
+        offset += 4 + size;
+        if decompressed.len() < LZ4_LEGACY_BLOCK_SIZE {
+            break;
+        }
+    }
+    if kernel.is_empty() {
+        return Err(anyhow!("lz4 legacy stream has no blocks"));
+    }
+    Ok((kernel, bytes[offset..].to_vec()))
+}
+
+impl Compression {
+    /// Detects the compression of `bytes` and returns the decompressed kernel.
+    pub fn decompress(bytes: &[u8]) -> anyhow::Result<(Self, Vec<u8>)> {
+        let (format, (kernel, trailer)) = if bytes.starts_with(GZIP_MAGIC) {
+            (Format::Gzip, decompress_gzip(bytes)?)
+        } else if bytes.starts_with(LZ4_FRAME_MAGIC) && bytes.len() > 6 {
+            let format = Format::Lz4Frame {
+                flg: bytes[4],
+                bd: bytes[5],
+            };
+            (format, decompress_lz4_frame(bytes)?)
+        } else if bytes.starts_with(LZ4_LEGACY_MAGIC) {
+            (Format::Lz4Legacy, decompress_lz4_legacy(bytes)?)
+        } else {
+            (Format::None, (bytes.to_vec(), vec![]))
+        };
+        Ok((Compression { format, trailer }, kernel))
+    }
+
+    /// Compresses `kernel` in the original format, followed by the original trailer.
+    pub fn compress(&self, kernel: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = match self.format {
+            Format::None => kernel.to_vec(),
+            Format::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::best());
+                encoder.write_all(kernel)?;
+                encoder.finish()?
+            }
+            Format::Lz4Frame { flg, bd } => {
+                let frame_info = lz4_frame_info(flg, bd, kernel.len());
+                let mut encoder = FrameEncoder::with_frame_info(frame_info, vec![]);
+                encoder.write_all(kernel)?;
+                encoder.finish()?
+            }
+            Format::Lz4Legacy => {
+                let mut bytes = LZ4_LEGACY_MAGIC.to_vec();
+                for chunk in kernel.chunks(LZ4_LEGACY_BLOCK_SIZE) {
+                    let block = lz4_flex::block::compress(chunk);
+                    bytes.extend_from_slice(&(block.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(&block);
+                }
+                bytes
+            }
+        };
+        bytes.extend_from_slice(&self.trailer);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kernel() -> Vec<u8> {
+        (0..LZ4_LEGACY_BLOCK_SIZE + 0x1000)
+            .map(|i| (i / 7 % 251) as u8)
+            .collect()
+    }
+
+    fn dtb() -> Vec<u8> {
+        let mut dtb = FDT_MAGIC.to_vec();
+        dtb.extend_from_slice(&0x20u32.to_be_bytes());
+        dtb.resize(0x20, 0);
+        dtb
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let kernel = kernel();
+        let raw = Compression {
+            format: Format::None,
+            trailer: vec![],
+        };
+        let gzip = Compression {
+            format: Format::Gzip,
+            trailer: dtb(),
+        };
+        let lz4_frame = Compression {
+            format: Format::Lz4Frame {
+                flg: 0x6C,
+                bd: 0x70,
+            },
+            trailer: vec![],
+        };
+        let lz4_legacy = Compression {
+            format: Format::Lz4Legacy,
+            trailer: (kernel.len() as u32).to_le_bytes().to_vec(),
+        };
+        for compression in [raw, gzip, lz4_frame, lz4_legacy] {
+            let bytes = compression.compress(&kernel).unwrap();
+            let (detected, decompressed) = Compression::decompress(&bytes).unwrap();
+            assert_eq!(detected.format, compression.format);
+            assert_eq!(detected.trailer, compression.trailer);
+            assert!(decompressed == kernel, "{} round trip", compression);
+        }
+        assert_eq!(count_dtbs(&[dtb(), dtb()].concat()), 2);
+    }
+}
@@ -0,0 +1,131 @@
+//! Where the hooks find the ids and capability sets in `struct cred`.
+//!
+//! `CONFIG_DEBUG_CREDENTIALS`, gone since Linux 6.8, puts `subscribers`, `put_addr` and
+//! `magic` between `usage` and `uid`. Linux 6.8 also widens `usage` to an `atomic_long_t`,
+//! moving everything after it by 4 bytes. `kernel_cap_t` is two u32 before Linux 6.3 and one
+//! u64 after, both take 8 bytes at the same offsets on arm64. The capabilities a full
+//! set holds grew with CAP_PERFMON and CAP_BPF in 5.8 and CAP_CHECKPOINT_RESTORE in 5.9.
+
+use std::fmt;
+
+use crate::{finder::find_bytes, kernel_version::KernelVersion};
+
+/// Printed by `__invalid_creds`, only built with `CONFIG_DEBUG_CREDENTIALS`.
+const DEBUG_CREDENTIALS_MESSAGE: &[u8] = b"CRED: Invalid credentials";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CredLayout {
+    /// uid, gid, suid, sgid, euid, egid, fsuid and fsgid, 4 bytes each.
+    pub ids: usize,
+    pub securebits: usize,
+    /// cap_inheritable, cap_permitted, cap_effective, cap_bset and cap_ambient, 8 bytes each.
+    pub caps: usize,
+    /// A full capability set, the avc_denied hook only lets processes holding it through.
+    pub full_caps: u64,
+}
+
+impl CredLayout {
+    /// The layout the hooks have always assumed.
+    pub const DEFAULT: CredLayout = CredLayout {
+        ids: 4,
+        securebits: 36,
+        caps: 40,
+        full_caps: 0x3F_FFFF_FFFF,
+    };
+
+    pub fn new(debug_credentials: bool, version: Option<KernelVersion>) -> Self {
+        let ids = match version {
+            Some(version) if version >= KernelVersion::new(6, 8, 0) => 8,
+            _ if debug_credentials => 20,
+            _ => 4,
+        };
+        let full_caps = match version {
+            Some(version) if version >= KernelVersion::new(5, 9, 0) => 0x1FF_FFFF_FFFF,
+            Some(version) if version >= KernelVersion::new(5, 8, 0) => 0xFF_FFFF_FFFF,
+            // Fewer capabilities are also the safe guess for an unknown version.
+            _ => 0x3F_FFFF_FFFF,
+        };
+        CredLayout {
+            ids,
+            securebits: ids + 32,
+            caps: (ids + 36).next_multiple_of(8),
+            full_caps,
+        }
+    }
+
+    /// Selects the layout from the kernel version and whether the debug checks are built in.
+    pub fn detect(image: &[u8]) -> Self {
+        let version = KernelVersion::find(image);
+        let debug_credentials = !find_bytes(image, DEBUG_CREDENTIALS_MESSAGE).is_empty()
+            && version.is_none_or(|version| version < KernelVersion::new(6, 8, 0));
+        CredLayout::new(debug_credentials, version)
+    }
+
+    /// Offsets of uid, suid, euid and fsuid.
+    pub fn uids(&self) -> [usize; 4] {
+        [0, 8, 16, 24].map(|offset| self.ids + offset)
+    }
+
+    /// Offsets of gid, sgid, egid and fsgid.
+    pub fn gids(&self) -> [usize; 4] {
+        [4, 12, 20, 28].map(|offset| self.ids + offset)
+    }
+
+    /// Offsets of the five capability sets.
+    pub fn cap_sets(&self) -> [usize; 5] {
+        [0, 8, 16, 24, 32].map(|offset| self.caps + offset)
+    }
+
+    /// The end of the capability sets.
+    pub fn caps_end(&self) -> usize {
+        self.caps + 40
+    }
+}
+
+impl Default for CredLayout {
+    fn default() -> Self {
+        CredLayout::DEFAULT
+    }
+}
+
+impl fmt::Display for CredLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uid 0x{:x}, securebits 0x{:x}, capabilities 0x{:x}, full capability set 0x{:x}",
+            self.ids, self.securebits, self.caps, self.full_caps
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect_cred_layout() {
+        let mut image = vec![0u8; 0x100];
+        assert_eq!(CredLayout::detect(&image), CredLayout::DEFAULT);
+        image.extend_from_slice(b"Linux version 5.10.43 (build@host)\0");
+        assert_eq!(CredLayout::detect(&image).full_caps, 0x1FF_FFFF_FFFF);
+        assert_eq!(CredLayout::detect(&image).caps, 40);
+
+        image.extend_from_slice(b"\x013CRED: Invalid credentials\n\0");
+        let debug = CredLayout::detect(&image);
+        assert_eq!((debug.ids, debug.securebits, debug.caps), (20, 52, 56));
+        assert_eq!(debug.cap_sets()[4] + 8, debug.caps_end());
+        assert_eq!(
+            CredLayout::new(true, Some(KernelVersion::new(4, 19, 0))).full_caps,
+            CredLayout::DEFAULT.full_caps
+        );
+
+        let mut image = vec![0u8; 0x100];
+        image.extend_from_slice(b"Linux version 6.8.0 (build@host)\0");
+        let wide_usage = CredLayout::detect(&image);
+        assert_eq!(
+            (wide_usage.ids, wide_usage.securebits, wide_usage.caps),
+            (8, 40, 48)
+        );
+        assert_eq!(wide_usage.caps_end(), 88);
+    }
+}
@@ -0,0 +1,916 @@
+use std::fmt;
+
+const COND_NAMES: [&str; 16] = [
+    "EQ", "NE", "CS", "CC", "MI", "PL", "VS", "VC", "HI", "LS", "GE", "LT", "GT", "LE", "AL", "NV",
+];
+
+const SHIFT_NAMES: [&str; 4] = ["LSL", "LSR", "ASR", "ROR"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    /// `[Xn, #imm]`, scaled unsigned offset
+    Offset(i64),
+    /// `[Xn, #imm]`, unscaled signed offset (`LDUR`/`STUR`)
+    Unscaled(i64),
+    /// `[Xn, #imm]!`
+    PreIndex(i64),
+    /// `[Xn], #imm`
+    PostIndex(i64),
+    /// `[Xn, Rm{, extend #amount}]`
+    Register { rm: u32, option: u32, shift: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    Adr {
+        rd: u32,
+        target: u64,
+    },
+    Adrp {
+        rd: u32,
+        target: u64,
+    },
+    AddSubImm {
+        sub: bool,
+        set_flags: bool,
+        sf: bool,
+        rd: u32,
+        rn: u32,
+        imm: u64,
+    },
+    AddSubReg {
+        sub: bool,
+        set_flags: bool,
+        sf: bool,
+        rd: u32,
+        rn: u32,
+        rm: u32,
+        shift: u32,
+        amount: u32,
+    },
+    LogicalImm {
+        opc: u32,
+        sf: bool,
+        rd: u32,
+        rn: u32,
+        imm: u64,
+    },
+    LogicalReg {
+        opc: u32,
+        invert: bool,
+        sf: bool,
+        rd: u32,
+        rn: u32,
+        rm: u32,
+        shift: u32,
+        amount: u32,
+    },
+    MoveWide {
+        opc: u32,
+        sf: bool,
+        rd: u32,
+        imm16: u32,
+        shift: u32,
+    },
+    Bitfield {
+        opc: u32,
+        sf: bool,
+        rd: u32,
+        rn: u32,
+        immr: u32,
+        imms: u32,
+    },
+    /// `size` is log2 of the access width, `signed` loads sign-extend into `rt_is64`.
+    LoadStore {
+        load: bool,
+        size: u32,
+        signed: bool,
+        rt_is64: bool,
+        rt: u32,
+        rn: u32,
+        addressing: Addressing,
+    },
+    LoadLiteral {
+        rt: u32,
+        is64: bool,
+        signed: bool,
+        target: u64,
+    },
+    LoadStorePair {
+        load: bool,
+        is64: bool,
+        rt: u32,
+        rt2: u32,
+        rn: u32,
+        addressing: Addressing,
+    },
+    Exclusive {
+        load: bool,
+        ordered: bool,
+        is64: bool,
+        rs: u32,
+        rt: u32,
+        rn: u32,
+    },
+    Branch {
+        link: bool,
+        target: u64,
+    },
+    BranchCond {
+        cond: u32,
+        target: u64,
+    },
+    CompareBranch {
+        nonzero: bool,
+        sf: bool,
+        rt: u32,
+        target: u64,
+    },
+    TestBranch {
+        nonzero: bool,
+        rt: u32,
+        bit: u32,
+        target: u64,
+    },
+    BranchReg {
+        /// 0 = BR, 1 = BLR, 2 = RET
+        opc: u32,
+        rn: u32,
+    },
+    Mrs {
+        rt: u32,
+        sys_reg: u32,
+    },
+    Msr {
+        rt: u32,
+        sys_reg: u32,
+    },
+    Hint(u32),
+    Brk(u32),
+    Unknown(u32),
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+fn rel(pc: u64, offset: i64) -> u64 {
+    pc.wrapping_add(offset as u64)
+}
+
+/// Expands an `N:immr:imms` logical immediate, `None` for reserved encodings.
+pub fn decode_bitmask_imm(n: u32, immr: u32, imms: u32, sf: bool) -> Option<u64> {
+    let combined = (n << 6) | (!imms & 0x3f);
+    if combined == 0 {
+        return None;
+    }
+    let len = 31 - combined.leading_zeros();
+    let size = 1u32 << len;
+    let levels = size - 1;
+    let s = imms & levels;
+    let r = immr & levels;
+    if s == levels || (!sf && n == 1) {
+        return None;
+    }
+    let mask = if size == 64 {
+        u64::MAX
+    } else {
+        (1u64 << size) - 1
+    };
+    let welem = (1u64 << (s + 1)) - 1;
+    let elem = if r == 0 {
+        welem
+    } else {
+        ((welem >> r) | (welem << (size - r))) & mask
+    };
+    let mut imm = 0u64;
+    let mut pos = 0;
+    while pos < 64 {
+        imm |= elem << pos;
+        pos += size;
+    }
+    Some(if sf { imm } else { imm & 0xFFFF_FFFF })
+}
+
+/// Decodes the instruction `word` located at address `pc`.
+pub fn decode(word: u32, pc: u64) -> Inst {
+    let w = word;
+    let bits = |lo: u32, len: u32| (w >> lo) & ((1u32 << len) - 1);
+    let rd = bits(0, 5);
+    let rn = bits(5, 5);
+    let sf = w >> 31 == 1;
+
+    if w & 0x1F000000 == 0x10000000 {
+        let imm = sign_extend(((bits(5, 19) << 2) | bits(29, 2)) as u64, 21);
+        return if sf {
+            Inst::Adrp {
+                rd,
+                target: rel(pc & !0xFFF, imm << 12),
+            }
+        } else {
+            Inst::Adr {
+                rd,
+                target: rel(pc, imm),
+            }
+        };
+    }
+    if w & 0x1F800000 == 0x11000000 {
+        return Inst::AddSubImm {
+            sub: bits(30, 1) == 1,
+            set_flags: bits(29, 1) == 1,
+            sf,
+            rd,
+            rn,
+            imm: (bits(10, 12) as u64) << (12 * bits(22, 1)),
+        };
+    }
+    if w & 0x1F800000 == 0x12000000 {
+        return match decode_bitmask_imm(bits(22, 1), bits(16, 6), bits(10, 6), sf) {
+            Some(imm) => Inst::LogicalImm {
+                opc: bits(29, 2),
+                sf,
+                rd,
+                rn,
+                imm,
+            },
+            None => Inst::Unknown(w),
+        };
+    }
+    if w & 0x1F800000 == 0x12800000 && bits(29, 2) != 1 && (sf || bits(22, 1) == 0) {
+        return Inst::MoveWide {
+            opc: bits(29, 2),
+            sf,
+            rd,
+            imm16: bits(5, 16),
+            shift: bits(21, 2) * 16,
+        };
+    }
+    if w & 0x1F800000 == 0x13000000
+        && bits(29, 2) != 3
+        && (bits(22, 1) == 1) == sf
+        && (sf || bits(15, 1) == 0 && bits(21, 1) == 0)
+    {
+        return Inst::Bitfield {
+            opc: bits(29, 2),
+            sf,
+            rd,
+            rn,
+            immr: bits(16, 6),
+            imms: bits(10, 6),
+        };
+    }
+    if w & 0x1F000000 == 0x0A000000 && (sf || bits(15, 1) == 0) {
+        return Inst::LogicalReg {
+            opc: bits(29, 2),
+            invert: bits(21, 1) == 1,
+            sf,
+            rd,
+            rn,
+            rm: bits(16, 5),
+            shift: bits(22, 2),
+            amount: bits(10, 6),
+        };
+    }
+    if w & 0x1F200000 == 0x0B000000 && bits(22, 2) != 3 && (sf || bits(15, 1) == 0) {
+        return Inst::AddSubReg {
+            sub: bits(30, 1) == 1,
+            set_flags: bits(29, 1) == 1,
+            sf,
+            rd,
+            rn,
+            rm: bits(16, 5),
+            shift: bits(22, 2),
+            amount: bits(10, 6),
+        };
+    }
+    if w & 0x7C000000 == 0x14000000 {
+        return Inst::Branch {
+            link: sf,
+            target: rel(pc, sign_extend(bits(0, 26) as u64, 26) * 4),
+        };
+    }
+    if w & 0xFF000010 == 0x54000000 {
+        return Inst::BranchCond {
+            cond: bits(0, 4),
+            target: rel(pc, sign_extend(bits(5, 19) as u64, 19) * 4),
+        };
+    }
+    if w & 0x7E000000 == 0x34000000 {
+        return Inst::CompareBranch {
+            nonzero: bits(24, 1) == 1,
+            sf,
+            rt: rd,
+            target: rel(pc, sign_extend(bits(5, 19) as u64, 19) * 4),
+        };
+    }
+    if w & 0x7E000000 == 0x36000000 {
+        return Inst::TestBranch {
+            nonzero: bits(24, 1) == 1,
+            rt: rd,
+            bit: (bits(31, 1) << 5) | bits(19, 5),
+            target: rel(pc, sign_extend(bits(5, 14) as u64, 14) * 4),
+        };
+    }
+    if w & 0xFF9FFC1F == 0xD61F0000 {
+        return Inst::BranchReg {
+            opc: bits(21, 2),
+            rn,
+        };
+    }
+    if w & 0xFFFFF01F == 0xD503201F {
+        return Inst::Hint(bits(5, 7));
+    }
+    if w & 0xFFE0001F == 0xD4200000 {
+        return Inst::Brk(bits(5, 16));
+    }
+    if w & 0xFFF00000 == 0xD5300000 {
+        return Inst::Mrs {
+            rt: rd,
+            sys_reg: bits(5, 15),
+        };
+    }
+    if w & 0xFFF00000 == 0xD5100000 {
+        return Inst::Msr {
+            rt: rd,
+            sys_reg: bits(5, 15),
+        };
+    }
+    if w & 0xBFA07C00 == 0x88007C00 && bits(23, 1) == 0 {
+        let load = bits(22, 1) == 1;
+        if !load || bits(16, 5) == 31 {
+            return Inst::Exclusive {
+                load,
+                ordered: bits(15, 1) == 1,
+                is64: bits(30, 1) == 1,
+                rs: bits(16, 5),
+                rt: rd,
+                rn,
+            };
+        }
+    }
+    if w & 0x3B000000 == 0x18000000 && bits(26, 1) == 0 && bits(30, 2) != 3 {
+        return Inst::LoadLiteral {
+            rt: rd,
+            is64: bits(30, 2) != 0,
+            signed: bits(30, 2) == 2,
+            target: rel(pc, sign_extend(bits(5, 19) as u64, 19) * 4),
+        };
+    }
+    if w & 0x3A000000 == 0x28000000 && bits(26, 1) == 0 && bits(23, 2) != 0 {
+        let opc = bits(30, 2);
+        if opc == 0 || opc == 2 {
+            let is64 = opc == 2;
+            let offset = sign_extend(bits(15, 7) as u64, 7) * if is64 { 8 } else { 4 };
+            let addressing = match bits(23, 2) {
+                1 => Addressing::PostIndex(offset),
+                2 => Addressing::Offset(offset),
+                _ => Addressing::PreIndex(offset),
+            };
+            return Inst::LoadStorePair {
+                load: bits(22, 1) == 1,
+                is64,
+                rt: rd,
+                rt2: bits(10, 5),
+                rn,
+                addressing,
+            };
+        }
+    }
+    if w & 0x3A000000 == 0x38000000 && bits(26, 1) == 0 {
+        let size = bits(30, 2);
+        let opc = bits(22, 2);
+        // opc 2/3 are sign-extending loads, except the PRFM/reserved slots for size 3
+        // and LDRSW which only exists as a 64-bit target.
+        if size == 3 && opc >= 2 || size == 2 && opc == 3 {
+            return Inst::Unknown(w);
+        }
+        let load = opc != 0;
+        let signed = opc >= 2;
+        let rt_is64 = if signed { opc == 2 } else { size == 3 };
+        let addressing = if bits(24, 1) == 1 {
+            Some(Addressing::Offset((bits(10, 12) as i64) << size))
+        } else if bits(21, 1) == 0 {
+            let imm9 = sign_extend(bits(12, 9) as u64, 9);
+            match bits(10, 2) {
+                0 => Some(Addressing::Unscaled(imm9)),
+                1 => Some(Addressing::PostIndex(imm9)),
+                3 => Some(Addressing::PreIndex(imm9)),
+                _ => None,
+            }
+        } else if bits(10, 2) == 2 && bits(13, 3) & 2 == 2 {
+            Some(Addressing::Register {
+                rm: bits(16, 5),
+                option: bits(13, 3),
+                shift: if bits(12, 1) == 1 { size } else { 0 },
+            })
+        } else {
+            None
+        };
+        if let Some(addressing) = addressing {
+            return Inst::LoadStore {
+                load,
+                size,
+                signed,
+                rt_is64,
+                rt: rd,
+                rn,
+                addressing,
+            };
+        }
+    }
+    Inst::Unknown(w)
+}
+
+impl Inst {
+    /// Whether executing the instruction may change general purpose register `reg`,
+    /// including base register write-back and registers clobbered by a call.
+    pub fn writes(&self, reg: u32) -> bool {
+        let written_back = |rn: u32, addressing: &Addressing| {
+            rn == reg
+                && matches!(
+                    addressing,
+                    Addressing::PreIndex(_) | Addressing::PostIndex(_)
+                )
+        };
+        match *self {
+            Inst::Adr { rd, .. }
+            | Inst::Adrp { rd, .. }
+            | Inst::AddSubImm { rd, .. }
+            | Inst::AddSubReg { rd, .. }
+            | Inst::LogicalImm { rd, .. }
+            | Inst::LogicalReg { rd, .. }
+            | Inst::MoveWide { rd, .. }
+            | Inst::Bitfield { rd, .. } => rd == reg,
+            Inst::LoadStore {
+                load,
+                rt,
+                rn,
+                ref addressing,
+                ..
+            } => (load && rt == reg) || written_back(rn, addressing),
+            Inst::LoadStorePair {
+                load,
+                rt,
+                rt2,
+                rn,
+                ref addressing,
+                ..
+            } => (load && (rt == reg || rt2 == reg)) || written_back(rn, addressing),
+            Inst::Exclusive { load, rs, rt, .. } => {
+                if load {
+                    rt == reg
+                } else {
+                    rs == reg
+                }
+            }
+            Inst::LoadLiteral { rt, .. } | Inst::Mrs { rt, .. } => rt == reg,
+            Inst::Branch { link: true, .. } | Inst::BranchReg { opc: 1, .. } => {
+                reg <= 18 || reg == 30
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an indirect call may land on the instruction with BTI enforced:
+    /// `BTI c`, `BTI j`, `BTI jc`, `PACIASP` and `PACIBSP`.
+    pub fn is_landing_pad(&self) -> bool {
+        matches!(self, Inst::Hint(25 | 27 | 34 | 36 | 38))
+    }
+}
+
+/// Reads the little-endian instruction word at `offset`, if it is inside `image`.
+pub fn read_word(image: &[u8], offset: usize) -> Option<u32> {
+    let bytes = image.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Decodes the instruction at file offset `offset`, using the offset as its address.
+pub fn decode_at(image: &[u8], offset: usize) -> Option<Inst> {
+    read_word(image, offset).map(|word| decode(word, offset as u64))
+}
+
+fn reg(num: u32, is64: bool) -> String {
+    match (num, is64) {
+        (31, true) => "XZR".to_string(),
+        (31, false) => "WZR".to_string(),
+        (num, true) => format!("X{}", num),
+        (num, false) => format!("W{}", num),
+    }
+}
+
+fn reg_or_sp(num: u32, is64: bool) -> String {
+    match (num, is64) {
+        (31, true) => "SP".to_string(),
+        (31, false) => "WSP".to_string(),
+        _ => reg(num, is64),
+    }
+}
+
+fn imm(value: i64) -> String {
+    if (-9..=9).contains(&value) {
+        format!("#{}", value)
+    } else if value < 0 {
+        format!("#-0x{:X}", value.unsigned_abs())
+    } else {
+        format!("#0x{:X}", value)
+    }
+}
+
+fn uimm(value: u64) -> String {
+    if value <= 9 {
+        format!("#{}", value)
+    } else {
+        format!("#0x{:X}", value)
+    }
+}
+
+fn sys_reg_name(sys_reg: u32) -> String {
+    let op0 = (sys_reg >> 14) + 2;
+    let op1 = (sys_reg >> 11) & 7;
+    let crn = (sys_reg >> 7) & 15;
+    let crm = (sys_reg >> 3) & 15;
+    let op2 = sys_reg & 7;
+    let name = match (op0, op1, crn, crm, op2) {
+        (3, 0, 4, 1, 0) => "SP_EL0",
+        (3, 0, 4, 2, 2) => "CURRENTEL",
+        (3, 3, 4, 2, 1) => "DAIF",
+        (3, 3, 4, 2, 0) => "NZCV",
+        (3, 0, 13, 0, 4) => "TPIDR_EL1",
+        (3, 3, 13, 0, 2) => "TPIDR_EL0",
+        _ => return format!("S{}_{}_C{}_C{}_{}", op0, op1, crn, crm, op2),
+    };
+    name.to_string()
+}
+
+fn mem(rn: u32, addressing: &Addressing, ext_is64: impl Fn(u32) -> bool) -> String {
+    let base = reg_or_sp(rn, true);
+    match addressing {
+        Addressing::Offset(0) | Addressing::Unscaled(0) => format!("[{}]", base),
+        Addressing::Offset(offset) | Addressing::Unscaled(offset) => {
+            format!("[{}, {}]", base, imm(*offset))
+        }
+        Addressing::PreIndex(offset) => format!("[{}, {}]!", base, imm(*offset)),
+        Addressing::PostIndex(offset) => format!("[{}], {}", base, imm(*offset)),
+        Addressing::Register { rm, option, shift } => {
+            let index = reg(*rm, ext_is64(*option));
+            let extend = match option {
+                0b010 => "UXTW",
+                0b011 => "LSL",
+                0b110 => "SXTW",
+                _ => "SXTX",
+            };
+            match (*option, *shift) {
+                (0b011, 0) => format!("[{}, {}]", base, index),
+                (_, 0) => format!("[{}, {}, {}]", base, index, extend),
+                (_, shift) => format!("[{}, {}, {} #{}]", base, index, extend, shift),
+            }
+        }
+    }
+}
+
+fn shifted(rm: String, shift: u32, amount: u32) -> String {
+    if amount == 0 && shift == 0 {
+        rm
+    } else {
+        format!("{}, {} #{}", rm, SHIFT_NAMES[shift as usize], amount)
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Inst::Adr { rd, target } => write!(f, "ADR X{}, 0x{:X}", rd, target),
+            Inst::Adrp { rd, target } => write!(f, "ADRP X{}, 0x{:X}", rd, target),
+            Inst::AddSubImm {
+                sub,
+                set_flags,
+                sf,
+                rd,
+                rn,
+                imm: value,
+            } => {
+                let rn_text = reg_or_sp(rn, sf);
+                match (sub, set_flags, rd) {
+                    (_, true, 31) => {
+                        let op = if sub { "CMP" } else { "CMN" };
+                        write!(f, "{} {}, {}", op, rn_text, imm(value as i64))
+                    }
+                    (false, false, _) if value == 0 && (rd == 31 || rn == 31) => {
+                        write!(f, "MOV {}, {}", reg_or_sp(rd, sf), rn_text)
+                    }
+                    _ => {
+                        let op = match (sub, set_flags) {
+                            (false, false) => "ADD",
+                            (false, true) => "ADDS",
+                            (true, false) => "SUB",
+                            (true, true) => "SUBS",
+                        };
+                        let rd_text = if set_flags {
+                            reg(rd, sf)
+                        } else {
+                            reg_or_sp(rd, sf)
+                        };
+                        write!(f, "{} {}, {}, {}", op, rd_text, rn_text, imm(value as i64))
+                    }
+                }
+            }
+            Inst::AddSubReg {
+                sub,
+                set_flags,
+                sf,
+                rd,
+                rn,
+                rm,
+                shift,
+                amount,
+            } => {
+                let rm_text = shifted(reg(rm, sf), shift, amount);
+                if set_flags && rd == 31 {
+                    let op = if sub { "CMP" } else { "CMN" };
+                    return write!(f, "{} {}, {}", op, reg(rn, sf), rm_text);
+                }
+                let op = match (sub, set_flags) {
+                    (false, false) => "ADD",
+                    (false, true) => "ADDS",
+                    (true, false) => "SUB",
+                    (true, true) => "SUBS",
+                };
+                write!(f, "{} {}, {}, {}", op, reg(rd, sf), reg(rn, sf), rm_text)
+            }
+            Inst::LogicalImm {
+                opc,
+                sf,
+                rd,
+                rn,
+                imm: value,
+            } => match (opc, rd, rn) {
+                (3, 31, _) => write!(f, "TST {}, {}", reg(rn, sf), uimm(value)),
+                (1, _, 31) => write!(f, "MOV {}, {}", reg_or_sp(rd, sf), uimm(value)),
+                _ => {
+                    let op = ["AND", "ORR", "EOR", "ANDS"][opc as usize];
+                    let rd_text = if opc == 3 {
+                        reg(rd, sf)
+                    } else {
+                        reg_or_sp(rd, sf)
+                    };
+                    write!(f, "{} {}, {}, {}", op, rd_text, reg(rn, sf), uimm(value))
+                }
+            },
+            Inst::LogicalReg {
+                opc,
+                invert,
+                sf,
+                rd,
+                rn,
+                rm,
+                shift,
+                amount,
+            } => {
+                let rm_text = shifted(reg(rm, sf), shift, amount);
+                match (opc, invert, rd, rn) {
+                    (1, false, _, 31) if amount == 0 => {
+                        write!(f, "MOV {}, {}", reg(rd, sf), reg(rm, sf))
+                    }
+                    (1, true, _, 31) => write!(f, "MVN {}, {}", reg(rd, sf), rm_text),
+                    (3, false, 31, _) => write!(f, "TST {}, {}", reg(rn, sf), rm_text),
+                    _ => {
+                        let op = match (opc, invert) {
+                            (0, false) => "AND",
+                            (0, true) => "BIC",
+                            (1, false) => "ORR",
+                            (1, true) => "ORN",
+                            (2, false) => "EOR",
+                            (2, true) => "EON",
+                            (_, false) => "ANDS",
+                            (_, true) => "BICS",
+                        };
+                        write!(f, "{} {}, {}, {}", op, reg(rd, sf), reg(rn, sf), rm_text)
+                    }
+                }
+            }
+            Inst::MoveWide {
+                opc,
+                sf,
+                rd,
+                imm16,
+                shift,
+            } => {
+                let value = (imm16 as u64) << shift;
+                match opc {
+                    0 => {
+                        let value = if sf { !value } else { !value & 0xFFFF_FFFF };
+                        write!(f, "MOV {}, {}", reg(rd, sf), uimm(value))
+                    }
+                    2 => write!(f, "MOV {}, {}", reg(rd, sf), uimm(value)),
+                    _ if shift == 0 => write!(f, "MOVK {}, {}", reg(rd, sf), uimm(imm16 as u64)),
+                    _ => write!(
+                        f,
+                        "MOVK {}, {}, LSL #{}",
+                        reg(rd, sf),
+                        uimm(imm16 as u64),
+                        shift
+                    ),
+                }
+            }
+            Inst::Bitfield {
+                opc,
+                sf,
+                rd,
+                rn,
+                immr,
+                imms,
+            } => {
+                let width = if sf { 64 } else { 32 };
+                let (rd, rn) = (reg(rd, sf), reg(rn, sf));
+                match opc {
+                    2 if imms == width - 1 => write!(f, "LSR {}, {}, #{}", rd, rn, immr),
+                    2 if imms + 1 == immr => {
+                        write!(f, "LSL {}, {}, #{}", rd, rn, width - 1 - imms)
+                    }
+                    0 if imms == width - 1 => write!(f, "ASR {}, {}, #{}", rd, rn, immr),
+                    _ => {
+                        let op = ["SBFM", "BFM", "UBFM"][opc as usize];
+                        write!(f, "{} {}, {}, #{}, #{}", op, rd, rn, immr, imms)
+                    }
+                }
+            }
+            Inst::LoadStore {
+                load,
+                size,
+                signed,
+                rt_is64,
+                rt,
+                rn,
+                addressing,
+            } => {
+                let unscaled = matches!(addressing, Addressing::Unscaled(_));
+                let op = match (load, signed, size) {
+                    (false, _, 0) => "STRB",
+                    (false, _, 1) => "STRH",
+                    (false, _, _) => "STR",
+                    (true, false, 0) => "LDRB",
+                    (true, false, 1) => "LDRH",
+                    (true, false, _) => "LDR",
+                    (true, true, 0) => "LDRSB",
+                    (true, true, 1) => "LDRSH",
+                    (true, true, _) => "LDRSW",
+                };
+                let op = if unscaled {
+                    op.replacen("R", "UR", 1)
+                } else {
+                    op.to_string()
+                };
+                let addr = mem(rn, &addressing, |option| option & 1 == 1);
+                write!(f, "{} {}, {}", op, reg(rt, rt_is64), addr)
+            }
+            Inst::LoadLiteral {
+                rt,
+                is64,
+                signed,
+                target,
+            } => {
+                let op = if signed { "LDRSW" } else { "LDR" };
+                write!(f, "{} {}, 0x{:X}", op, reg(rt, is64), target)
+            }
+            Inst::LoadStorePair {
+                load,
+                is64,
+                rt,
+                rt2,
+                rn,
+                addressing,
+            } => {
+                let op = if load { "LDP" } else { "STP" };
+                let addr = mem(rn, &addressing, |_| true);
+                write!(f, "{} {}, {}, {}", op, reg(rt, is64), reg(rt2, is64), addr)
+            }
+            Inst::Exclusive {
+                load,
+                ordered,
+                is64,
+                rs,
+                rt,
+                rn,
+            } => {
+                let addr = mem(rn, &Addressing::Offset(0), |_| true);
+                match (load, ordered) {
+                    (true, false) => write!(f, "LDXR {}, {}", reg(rt, is64), addr),
+                    (true, true) => write!(f, "LDAXR {}, {}", reg(rt, is64), addr),
+                    (false, ordered) => {
+                        let op = if ordered { "STLXR" } else { "STXR" };
+                        write!(f, "{} {}, {}, {}", op, reg(rs, false), reg(rt, is64), addr)
+                    }
+                }
+            }
+            Inst::Branch { link, target } => {
+                write!(f, "{} 0x{:X}", if link { "BL" } else { "B" }, target)
+            }
+            Inst::BranchCond { cond, target } => {
+                write!(f, "B.{} 0x{:X}", COND_NAMES[cond as usize], target)
+            }
+            Inst::CompareBranch {
+                nonzero,
+                sf,
+                rt,
+                target,
+            } => {
+                let op = if nonzero { "CBNZ" } else { "CBZ" };
+                write!(f, "{} {}, 0x{:X}", op, reg(rt, sf), target)
+            }
+            Inst::TestBranch {
+                nonzero,
+                rt,
+                bit,
+                target,
+            } => {
+                let op = if nonzero { "TBNZ" } else { "TBZ" };
+                write!(f, "{} {}, #{}, 0x{:X}", op, reg(rt, bit >= 32), bit, target)
+            }
+            Inst::BranchReg { opc: 2, rn: 30 } => write!(f, "RET"),
+            Inst::BranchReg { opc, rn } => {
+                let op = ["BR", "BLR", "RET", "BR"][opc as usize & 3];
+                write!(f, "{} X{}", op, rn)
+            }
+            Inst::Mrs { rt, sys_reg } => {
+                write!(f, "MRS {}, {}", reg(rt, true), sys_reg_name(sys_reg))
+            }
+            Inst::Msr { rt, sys_reg } => {
+                write!(f, "MSR {}, {}", sys_reg_name(sys_reg), reg(rt, true))
+            }
+            Inst::Hint(0) => write!(f, "NOP"),
+            Inst::Hint(25) => write!(f, "PACIASP"),
+            Inst::Hint(29) => write!(f, "AUTIASP"),
+            Inst::Hint(hint) => write!(f, "HINT #{}", hint),
+            Inst::Brk(value) => write!(f, "BRK #0x{:X}", value),
+            Inst::Unknown(word) => write!(f, ".inst 0x{:08X}", word),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_round_trip() {
+        let lines = [
+            "MOV X0, X0",
+            "STP X7, X8, [SP, #-0x10]!",
+            "LDP X11, X12, [SP], #0x10",
+            "MOV X7, #0xFFFFFFFFFFFFF001",
+            "CMP X1, X7",
+            "LDR X7, [X1]",
+            "MOV X9, #0",
+            "LDRB W10, [X7, X9]",
+            "ADD X9, X9, #1",
+            "CMP X9, #0x30",
+            "MRS X8, SP_EL0",
+            "LDR X10, [X8, #0x618]",
+            "STR W9, [X10, X7]",
+            "MOV W9, #0xFFFFFFFF",
+            "LDXR W10, [X8]",
+            "AND W10, W10, #0xFFFFF000",
+            "STXR W11, W10, [X8]",
+            "STR WZR, [X8, #0x800]",
+            "MOV X9, #0x3FFFFFFFFF",
+            "RET",
+            "SUB SP, SP, #0x40",
+            "MOV X29, SP",
+            "LDUR W1, [X2, #-4]",
+            "LDR X5, [X6], #-8",
+            "LDR X0, [X1, X2, LSL #3]",
+            "LDRSW X0, [X1, #4]",
+            "CMN X1, #0xFFF",
+            "LSL X0, X1, #3",
+            "MOVK X0, #0x1234, LSL #16",
+            "PACIASP",
+            "HINT #34",
+            "BLR X8",
+            "MSR TPIDR_EL1, X0",
+        ];
+        for line in lines {
+            let bytes = assemble(line).unwrap();
+            let word = u32::from_le_bytes(bytes.try_into().unwrap());
+            assert_eq!(decode(word, 0).to_string(), line);
+        }
+    }
+
+    #[test]
+    fn test_decode_pc_relative() {
+        assert_eq!(
+            decode(0x540003C2, 0x1018),
+            Inst::BranchCond {
+                cond: 2,
+                target: 0x1090
+            }
+        );
+        assert_eq!(decode(0x10FFFD68, 0x1024).to_string(), "ADR X8, 0xFD0");
+        assert_eq!(decode(0xF0000000, 0x1234).to_string(), "ADRP X0, 0x4000");
+        assert_eq!(decode(0x97FFFFFF, 0x100).to_string(), "BL 0xFC");
+        assert_eq!(decode(0xB60FFFC0, 0x100).to_string(), "TBZ X0, #33, 0xF8");
+        assert_eq!(decode(0x58000040, 0x100).to_string(), "LDR X0, 0x108");
+        assert_eq!(decode(0xFFFFFFFF, 0).to_string(), ".inst 0xFFFFFFFF");
+    }
+}
@@ -0,0 +1,300 @@
+//! ELF `vmlinux` input.
+//!
+//! The `PT_LOAD` segments are flattened into the same layout as the `Image` objcopy
+//! makes of them, so everything else keeps working on Image offsets. Patched bytes are
+//! written back through the program headers, and `.symtab` stands in for kallsyms.
+
+use std::fmt;
+
+use anyhow::anyhow;
+
+use crate::kallsyms::Symbol;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+pub const EM_AARCH64: u16 = 183;
+pub const PT_LOAD: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHF_EXECINSTR: u64 = 4;
+const SHN_ABS: u16 = 0xFFF1;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STB_LOCAL: u8 = 0;
+pub const PHDR_SIZE: usize = 56;
+pub const SHDR_SIZE: usize = 64;
+pub const SYM_SIZE: usize = 24;
+
+/// The file backed part of a `PT_LOAD` segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: usize,
+    pub address: u64,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    flags: u64,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, size: usize) -> anyhow::Result<&[u8]> {
+        offset
+            .checked_add(size)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or_else(|| anyhow!("ELF truncated at 0x{:x}", offset))
+    }
+
+    fn u16(&self, offset: usize) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into()?))
+    }
+
+    fn u32(&self, offset: usize) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn u64(&self, offset: usize) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into()?))
+    }
+
+    fn c_string(&self, offset: usize) -> anyhow::Result<String> {
+        let bytes = self.0.get(offset..).unwrap_or_default();
+        let end = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| anyhow!("ELF string at 0x{:x} is not terminated", offset))?;
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    /// Address of the first segment, where the flattened Image starts.
+    pub base: u64,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    /// The whole file, written back around the patched segments.
+    bytes: Vec<u8>,
+}
+
+impl fmt::Display for Elf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ELF vmlinux, {} PT_LOAD segment(s) from 0x{:x}, {} symbols",
+            self.segments.len(),
+            self.base,
+            self.symbols.len()
+        )
+    }
+}
+
+impl Elf {
+    pub fn is_elf(bytes: &[u8]) -> bool {
+        bytes.starts_with(ELF_MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let reader = Reader(bytes);
+        let ident = reader.bytes(0, 16)?;
+        if !ident.starts_with(ELF_MAGIC) {
+            return Err(anyhow!("not an ELF file"));
+        }
+        if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB {
+            return Err(anyhow!("only little-endian ELF64 files are supported"));
+        }
+        let machine = reader.u16(18)?;
+        if machine != EM_AARCH64 {
+            return Err(anyhow!("ELF machine {} is not AArch64", machine));
+        }
+
+        let phoff = reader.u64(32)? as usize;
+        let phnum = reader.u16(56)? as usize;
+        let mut segments = vec![];
+        for idx in 0..phnum {
+            let phdr = phoff + idx * PHDR_SIZE;
+            let size = reader.u64(phdr + 32)? as usize;
+            if reader.u32(phdr)? != PT_LOAD || size == 0 {
+                continue;
+            }
+            let segment = Segment {
+                offset: reader.u64(phdr + 8)? as usize,
+                address: reader.u64(phdr + 16)?,
+                size,
+            };
+            reader.bytes(segment.offset, segment.size)?;
+            segments.push(segment);
+        }
+        segments.sort_by_key(|segment| segment.address);
+        let base = segments
+            .first()
+            .ok_or_else(|| anyhow!("ELF has no PT_LOAD segment"))?
+            .address;
+
+        let shoff = reader.u64(40)? as usize;
+        let shnum = reader.u16(60)? as usize;
+        let shstrndx = reader.u16(62)? as usize;
+        let section_header = |idx: usize| shoff + idx * SHDR_SIZE;
+        let shstrtab = if shnum > 0 {
+            reader.u64(section_header(shstrndx) + 24)? as usize
+        } else {
+            0
+        };
+        let mut sections = vec![];
+        let mut symtab = None;
+        for idx in 0..shnum {
+            let shdr = section_header(idx);
+            sections.push(Section {
+                name: reader.c_string(shstrtab + reader.u32(shdr)? as usize)?,
+                address: reader.u64(shdr + 16)?,
+                size: reader.u64(shdr + 32)?,
+                flags: reader.u64(shdr + 8)?,
+            });
+            if reader.u32(shdr + 4)? == SHT_SYMTAB {
+                symtab = Some(shdr);
+            }
+        }
+
+        let mut symbols = vec![];
+        if let Some(symtab) = symtab {
+            let offset = reader.u64(symtab + 24)? as usize;
+            let size = reader.u64(symtab + 32)? as usize;
+            let strtab = section_header(reader.u32(symtab + 40)? as usize);
+            let strtab = reader.u64(strtab + 24)? as usize;
+            for sym in (offset..offset + size).step_by(SYM_SIZE) {
+                let name = reader.u32(sym)? as usize;
+                let info = reader.bytes(sym + 4, 1)?[0];
+                let shndx = reader.u16(sym + 6)?;
+                if name == 0 || shndx == 0 || matches!(info & 0xF, STT_SECTION | STT_FILE) {
+                    continue;
+                }
+                let kind = match sections.get(shndx as usize) {
+                    _ if shndx == SHN_ABS => 'a',
+                    Some(section) if section.flags & SHF_EXECINSTR != 0 => 't',
+                    _ => 'd',
+                };
+                symbols.push(Symbol {
+                    name: reader.c_string(strtab + name)?,
+                    kind: if info >> 4 == STB_LOCAL {
+                        kind
+                    } else {
+                        kind.to_ascii_uppercase()
+                    },
+                    address: reader.u64(sym + 8)?,
+                });
+            }
+        }
+        Ok(Elf {
+            base,
+            segments,
+            sections,
+            symbols,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// File offset in the ELF of a virtual address, through the program headers.
+    pub fn to_file_offset(&self, address: u64) -> Option<usize> {
+        self.segments.iter().find_map(|segment| {
+            let delta = address.checked_sub(segment.address)? as usize;
+            (delta < segment.size).then_some(segment.offset + delta)
+        })
+    }
+
+    /// The segments laid out by address from `base`, gaps zero filled.
+    pub fn image(&self) -> Vec<u8> {
+        let mut image = vec![];
+        for segment in &self.segments {
+            let start = (segment.address - self.base) as usize;
+            if image.len() < start + segment.size {
+                image.resize(start + segment.size, 0);
+            }
+            image[start..start + segment.size]
+                .copy_from_slice(&self.bytes[segment.offset..segment.offset + segment.size]);
+        }
+        image
+    }
+
+    /// Writes the segments of `image` back into the file, changes between them have no
+    /// place there and are an error.
+    pub fn repack(&self, image: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = self.bytes.clone();
+        for segment in &self.segments {
+            let start = (segment.address - self.base) as usize;
+            bytes[segment.offset..segment.offset + segment.size]
+                .copy_from_slice(&image[start..start + segment.size]);
+        }
+        let in_segment = |pos: usize| {
+            self.segments.iter().any(|segment| {
+                let start = (segment.address - self.base) as usize;
+                (start..start + segment.size).contains(&pos)
+            })
+        };
+        let original = self.image();
+        if let Some(pos) =
+            (0..image.len()).find(|&pos| image[pos] != original[pos] && !in_segment(pos))
+        {
+            return Err(anyhow!(
+                "the change at 0x{:x} is between the ELF segments, the file has no place for it",
+                pos
+            ));
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::test_vmlinux;
+
+    #[test]
+    fn test_parse_elf() {
+        let base = 0xFFFFFFC008000000;
+        let text = vec![0xAAu8; 0x2000];
+        let bytes = test_vmlinux(&text, base);
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.base, base);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.section(".text").unwrap().size, 0x2000);
+        assert_eq!(
+            elf.symbols[1],
+            Symbol {
+                name: "do_execveat_common".to_string(),
+                kind: 'T',
+                address: base + 0x100,
+            }
+        );
+        assert_eq!(elf.symbols[2].kind, 'D');
+        assert_eq!(elf.to_file_offset(base + 0x100), Some(0x1100));
+        assert_eq!(elf.to_file_offset(base + 0x3000), None);
+
+        let mut image = elf.image();
+        assert_eq!(image.len(), 0x20100);
+        assert_eq!(&image[..0x2000], &text[..]);
+        assert_eq!(image[0x20000], 0x5A);
+        image[0x100..0x104].copy_from_slice(&[1, 2, 3, 4]);
+        image[0x20010] = 0;
+        let repacked = elf.repack(&image).unwrap();
+        assert_eq!(&repacked[0x1100..0x1104], &[1, 2, 3, 4]);
+        assert_eq!(Elf::parse(&repacked).unwrap().image(), image);
+        image[0x3000] = 1;
+        let e = elf.repack(&image).unwrap_err();
+        assert!(e.to_string().contains("0x3000 is between the ELF segments"));
+
+        let mut riscv = bytes;
+        riscv[18] = 243;
+        assert!(Elf::parse(&riscv).is_err());
+    }
+}
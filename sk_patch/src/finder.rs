@@ -0,0 +1,348 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::anyhow;
+
+use crate::{
+    disassembler::{decode, decode_at, read_word, Addressing, Inst},
+    hook::OriginalAt,
+    kallsyms::Kallsyms,
+    relocate::check_displaced,
+};
+
+pub mod avc_denied;
+pub mod do_execve;
+pub mod proc_pid_status;
+
+use proc_pid_status::Confidence;
+
+/// Score of an entry taken from kallsyms, which outweighs any heuristic.
+const SYMBOL_SCORE: u32 = 10;
+
+/// How far back from a feature we look for the entry of the enclosing function.
+const MAX_FUNCTION_SIZE: usize = 0x4000;
+
+/// A possible function entry together with why it was picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub entry: usize,
+    pub score: u32,
+    pub evidence: Vec<String>,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:x} (score {}): {}",
+            self.entry,
+            self.score,
+            self.evidence.join("; ")
+        )
+    }
+}
+
+/// Merges candidates sharing an entry and sorts them by descending score.
+pub fn rank(candidates: Vec<Candidate>) -> Vec<Candidate> {
+    let mut merged: Vec<Candidate> = vec![];
+    for candidate in candidates {
+        match merged.iter_mut().find(|c| c.entry == candidate.entry) {
+            Some(existing) => {
+                existing.score += candidate.score;
+                existing.evidence.extend(candidate.evidence);
+            }
+            None => merged.push(candidate),
+        }
+    }
+    merged.sort_by(|a, b| b.score.cmp(&a.score).then(a.entry.cmp(&b.entry)));
+    merged
+}
+
+/// How far the best of the ranked `candidates` can be trusted: high when kallsyms names
+/// it, or when it is ahead of the others with more than one piece of evidence.
+pub fn best_confidence(candidates: &[Candidate]) -> Option<Confidence> {
+    let best = candidates.first()?;
+    let ahead = candidates
+        .get(1)
+        .is_none_or(|second| second.score < best.score);
+    Some(
+        if best.score >= SYMBOL_SCORE || ahead && best.evidence.len() > 1 {
+            Confidence::High
+        } else if ahead {
+            Confidence::Medium
+        } else {
+            Confidence::Low
+        },
+    )
+}
+
+/// Adds the entry kallsyms gives for the first of `names` it knows as the strongest
+/// candidate.
+pub fn with_symbols(
+    mut candidates: Vec<Candidate>,
+    kallsyms: Option<&Kallsyms>,
+    names: &[&str],
+) -> Vec<Candidate> {
+    let symbol = kallsyms.and_then(|kallsyms| {
+        names.iter().find_map(|name| {
+            let symbol = kallsyms.lookup(name)?;
+            Some((symbol.name.clone(), kallsyms.file_offset(&symbol.name)?))
+        })
+    });
+    if let Some((name, entry)) = symbol {
+        let source = kallsyms.map_or("kallsyms", |kallsyms| kallsyms.source);
+        candidates.push(Candidate {
+            entry,
+            score: SYMBOL_SCORE,
+            evidence: vec![format!("{} symbol {}", source, name)],
+        });
+    }
+    rank(candidates)
+}
+
+/// Returns the offsets of every occurrence of `needle` in `image`.
+pub fn find_bytes(image: &[u8], needle: &[u8]) -> Vec<usize> {
+    if needle.is_empty() || image.len() < needle.len() {
+        return vec![];
+    }
+    image
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+/// Returns the 4-byte aligned offsets of instruction words accepted by `matcher`.
+pub fn find_words(image: &[u8], matcher: impl Fn(u32) -> bool) -> Vec<usize> {
+    image
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(_, word)| matcher(u32::from_le_bytes([word[0], word[1], word[2], word[3]])))
+        .map(|(idx, _)| idx * 4)
+        .collect()
+}
+
+/// Iterates the aligned instruction words in `start..end`, clamped to the image.
+pub fn words_in(image: &[u8], start: usize, end: usize) -> impl Iterator<Item = (usize, u32)> + '_ {
+    let start = start & !3;
+    let end = end.min(image.len());
+    (start..end)
+        .step_by(4)
+        .filter_map(move |offset| read_word(image, offset).map(|word| (offset, word)))
+}
+
+/// Finds `ADRP Xd, page` + `ADD Xn, Xd, #lo12` pairs materialising one of `targets`,
+/// the way code references string literals. Offsets are used as addresses, which holds
+/// for a raw Image since it is loaded page aligned.
+pub fn find_xrefs(image: &[u8], targets: &[usize]) -> HashMap<usize, Vec<usize>> {
+    let mut xrefs: HashMap<usize, Vec<usize>> = HashMap::new();
+    if targets.is_empty() {
+        return xrefs;
+    }
+    for (offset, word) in words_in(image, 0, image.len()) {
+        if word & 0x9F000000 != 0x90000000 {
+            continue;
+        }
+        let (rd, page) = match decode(word, offset as u64) {
+            Inst::Adrp { rd, target } => (rd, target as usize),
+            _ => continue,
+        };
+        if !targets.iter().any(|&t| t & !0xFFF == page) {
+            continue;
+        }
+        for (next_offset, next) in words_in(image, offset + 4, offset + 4 * 16) {
+            match decode(next, next_offset as u64) {
+                Inst::AddSubImm {
+                    sub: false,
+                    set_flags: false,
+                    sf: true,
+                    rn,
+                    imm,
+                    ..
+                } if rn == rd => {
+                    let target = page + imm as usize;
+                    if targets.contains(&target) {
+                        xrefs.entry(target).or_default().push(offset);
+                    }
+                    break;
+                }
+                Inst::Adrp { rd: other, .. } if other == rd => break,
+                _ => (),
+            }
+        }
+    }
+    xrefs
+}
+
+/// Collects the targets of `BL` instructions in `start..end`.
+pub fn call_targets(image: &[u8], start: usize, end: usize) -> Vec<usize> {
+    words_in(image, start, end)
+        .filter_map(|(offset, word)| match decode(word, offset as u64) {
+            Inst::Branch { link: true, target } => Some(target as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_terminator(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Branch { link: false, .. }
+            | Inst::BranchReg { opc: 0 | 2, .. }
+            | Inst::Brk(_)
+            | Inst::Unknown(0)
+    )
+}
+
+fn is_stack_setup(inst: &Inst) -> bool {
+    match inst {
+        Inst::LoadStorePair {
+            load: false,
+            rn: 31,
+            addressing: Addressing::PreIndex(offset),
+            ..
+        } => *offset < 0,
+        Inst::LoadStorePair {
+            load: false,
+            rn: 31,
+            ..
+        } => true,
+        Inst::AddSubImm {
+            sub: true,
+            set_flags: false,
+            rd: 31,
+            rn: 31,
+            ..
+        } => true,
+        _ => false,
+    }
+}
+
+/// Whether a function plausibly starts at `offset`: the previous instruction ends a
+/// function and the next few instructions set up a stack frame.
+pub fn is_function_entry(image: &[u8], offset: usize) -> bool {
+    if offset < 4 || !offset.is_multiple_of(4) {
+        return false;
+    }
+    match decode_at(image, offset - 4) {
+        Some(prev) if is_terminator(&prev) => (),
+        _ => return false,
+    }
+    let first = match decode_at(image, offset) {
+        Some(first) => first,
+        None => return false,
+    };
+    let starts_frame = match first {
+        // PACIASP, BTI c / BTI jc, NOP padding used by ftrace
+        Inst::Hint(25) | Inst::Hint(34) | Inst::Hint(38) | Inst::Hint(0) => true,
+        Inst::Mrs { sys_reg, .. } => sys_reg == 0x4208, // SP_EL0
+        first => is_stack_setup(&first),
+    };
+    starts_frame
+        && words_in(image, offset, offset + 4 * 8)
+            .any(|(off, word)| is_stack_setup(&decode(word, off as u64)))
+}
+
+/// Checks that the instruction at `entry` can be displaced into a hooker running it at
+/// `original_at`: the hookee patch overwrites it with a `B` and the hooker runs it from a
+/// different address. PC-relative instructions are relocated, which only fails once the
+/// hooker is placed too far for their target; branches leaving a hooker before its body
+/// and instructions which cannot be decoded are refused.
+pub fn check_hook_prologue(
+    image: &[u8],
+    entry: usize,
+    original_at: OriginalAt,
+) -> anyhow::Result<Inst> {
+    let word =
+        read_word(image, entry).ok_or_else(|| anyhow!("0x{:x} is outside of the image", entry))?;
+    check_displaced(word, entry, original_at)?;
+    match decode(word, entry as u64) {
+        Inst::Unknown(word) => Err(anyhow!(
+            "first instruction at 0x{:x} (0x{:08x}) is not a known instruction",
+            entry,
+            word
+        )),
+        inst => Ok(inst),
+    }
+}
+
+/// Walks back from `offset` to the entry of the function containing it.
+pub fn function_entry_before(image: &[u8], offset: usize) -> Option<usize> {
+    let offset = offset & !3;
+    let lowest = offset.saturating_sub(MAX_FUNCTION_SIZE);
+    (lowest..=offset)
+        .rev()
+        .step_by(4)
+        .find(|&candidate| is_function_entry(image, candidate))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_function_entry_and_xrefs() {
+        let mut image = assemble(
+            r#"
+                RET
+            entry:
+                STP X29, X30, [SP, #-32]!
+                MOV X29, SP
+                ADRP X0, #0x1000
+                NOP
+                ADD X0, X0, #0x10
+                BL #0x100
+                LDP X29, X30, [SP], #32
+                RET
+            "#,
+        )
+        .unwrap();
+        image.resize(0x1020, 0);
+        image[0x1010..0x1014].copy_from_slice(b"str\0");
+
+        assert_eq!(function_entry_before(&image, 0x18), Some(4));
+        assert_eq!(function_entry_before(&image, 0x4), Some(4));
+        let xrefs = find_xrefs(&image, &[0x1010]);
+        assert_eq!(xrefs.get(&0x1010), Some(&vec![0xC]));
+        assert_eq!(call_targets(&image, 0, 0x24), vec![0x118]);
+    }
+
+    #[test]
+    fn test_check_hook_prologue() {
+        let image = assemble("PACIASP\nADRP X0, #0\nB #0\n.word 0xFFFFFFFF").unwrap();
+        for original_at in [OriginalAt::Entry, OriginalAt::Exit] {
+            assert!(check_hook_prologue(&image, 0, original_at).is_ok());
+            // Relocated into the hooker.
+            assert!(check_hook_prologue(&image, 4, original_at).is_ok());
+            assert!(check_hook_prologue(&image, 12, original_at).is_err());
+            assert!(check_hook_prologue(&image, 16, original_at).is_err());
+        }
+        assert!(check_hook_prologue(&image, 8, OriginalAt::Entry).is_err());
+        assert!(check_hook_prologue(&image, 8, OriginalAt::Exit).is_ok());
+    }
+
+    #[test]
+    fn test_rank() {
+        let candidate = |entry, score| Candidate {
+            entry,
+            score,
+            evidence: vec![format!("{}", score)],
+        };
+        let ranked = rank(vec![candidate(8, 1), candidate(4, 2), candidate(8, 2)]);
+        assert_eq!(ranked[0].entry, 8);
+        assert_eq!(ranked[0].score, 3);
+        assert_eq!(ranked[1].entry, 4);
+        assert_eq!(best_confidence(&ranked), Some(Confidence::High));
+        assert_eq!(best_confidence(&ranked[1..]), Some(Confidence::Medium));
+        assert_eq!(
+            best_confidence(&rank(vec![candidate(8, 2), candidate(4, 2)])),
+            Some(Confidence::Low)
+        );
+        assert_eq!(
+            best_confidence(&[candidate(4, SYMBOL_SCORE)]),
+            Some(Confidence::High)
+        );
+        assert_eq!(best_confidence(&[]), None);
+    }
+}
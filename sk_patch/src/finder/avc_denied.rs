@@ -0,0 +1,299 @@
+//! Locates `avc_denied`, the function the avc_denied hook is placed on.
+//!
+//! `find_kernel_func/find_avc_denied.cpp` stops at functions calling `avc_denied`
+//! (`avc_has_perm_noaudit` and friends). Here the call sites are followed and each
+//! callee is checked for the shape of `avc_denied` itself:
+//!
+//! ```c
+//! if (flags & AVC_STRICT)
+//!     return -EACCES;
+//! if (enforcing_enabled(state) && !(avd->flags & AVD_FLAGS_PERMISSIVE))
+//!     return -EACCES;
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    call_targets, check_hook_prologue, find_bytes, find_words, find_xrefs, function_entry_before,
+    rank, words_in, Candidate,
+};
+use crate::{
+    disassembler::{decode, Addressing, Inst},
+    hook::OriginalAt,
+};
+
+/// Bytes searched on both sides of a call site anchor.
+const FEATURE_RADIUS: usize = 640;
+/// Instructions after an anchor in which the call to `avc_denied` must appear.
+const CALL_DISTANCE: usize = 20;
+/// Bytes of a function in which its callees are collected.
+const CALLEE_WINDOW: usize = 0x400;
+/// Instructions of a candidate checked for the shape of `avc_denied`.
+const SHAPE_WINDOW: usize = 32;
+
+/// Strings referenced by SELinux functions which reach `avc_denied` through
+/// `avc_has_perm_noaudit`, paired with the functions referencing them.
+const SELINUX_STRINGS: &[(&str, &[u8])] = &[
+    (
+        "cred_has_capability",
+        b"\x013SELinux:  out of range capability %d\n\0",
+    ),
+    ("selinux_getprocattr/selinux_setprocattr", b"\0keycreate\0"),
+    ("selinux_setprocattr", b"\0op=fscreate invalid_context=\0"),
+    ("sel_write_load", b"auid=%u ses=%u lsm=selinux res=1\0"),
+    ("sel_write_load", b"\x014SELinux: failed to load policy\n\0"),
+    (
+        "sel_write_context/sel_write_create",
+        b"\x013SELinux: %s:  context size (%u) exceeds payload max\n\0",
+    ),
+    (
+        "sel_write_validatetrans/sel_write_create",
+        b"%s %s %hu %s\0",
+    ),
+    ("sel_write_access", b"%x %x %x %x %u %x\0"),
+];
+
+/// `MOV W4/X4/W5/X5, #0`: the zeroed `driver`/`xperm` arguments of the call.
+fn is_zero_driver_arg(word: u32) -> bool {
+    matches!(
+        word,
+        0x52800004
+            | 0xD2800004
+            | 0x2A1F03E4
+            | 0xAA1F03E4
+            | 0x52800005
+            | 0xD2800005
+            | 0x2A1F03E5
+            | 0xAA1F03E5
+    )
+}
+
+fn low_reg(word: u32) -> bool {
+    word & 0x1F != 31
+}
+
+/// `MOV Wn, #3`
+fn is_mov_3(word: u32) -> bool {
+    low_reg(word) && matches!(word & !0x1F, 0x52800060 | 0x320007E0)
+}
+
+/// `MOV Wn, #4` or `MOV Wn, #5`
+fn is_mov_4_or_5(word: u32) -> bool {
+    low_reg(word) && matches!(word & !0x1F, 0x52800080 | 0x528000A0 | 0x321E03E0)
+}
+
+/// `LDR Wn, [Xm, #4]`
+fn is_load_word_4(word: u32) -> bool {
+    low_reg(word) && (word >> 5) & 0x1F != 31 && word & 0xFFFFFC00 == 0xB9400400
+}
+
+/// The instruction features SearchFeature1 requires around the call site.
+fn has_caller_features(image: &[u8], anchor: usize) -> bool {
+    let start = anchor.saturating_sub(FEATURE_RADIUS);
+    let words: Vec<u32> = words_in(image, start, anchor + FEATURE_RADIUS)
+        .map(|(_, word)| word)
+        .collect();
+    words.iter().any(|&w| is_mov_3(w))
+        && words.iter().any(|&w| w >> 16 == 0x1305)
+        && words.iter().any(|&w| w >> 24 == 0x1A)
+        && words.iter().any(|&w| is_load_word_4(w))
+        && words.iter().any(|&w| is_mov_4_or_5(w))
+}
+
+/// Scores how much the function at `entry` looks like `avc_denied`.
+fn avc_denied_shape(image: &[u8], entry: usize) -> (u32, Vec<String>) {
+    let prologue = match check_hook_prologue(image, entry, OriginalAt::Exit) {
+        Ok(inst) => inst,
+        Err(_) => return (0, vec![]),
+    };
+    let (mut strict, mut eacces, mut permissive) = (false, false, false);
+    for (offset, word) in words_in(image, entry, entry + 4 * SHAPE_WINDOW) {
+        match decode(word, offset as u64) {
+            Inst::TestBranch { bit: 0, .. } => strict = true,
+            Inst::MoveWide {
+                opc: 0,
+                sf: false,
+                imm16: 12,
+                shift: 0,
+                ..
+            } => eacces = true,
+            Inst::LoadStore {
+                load: true,
+                size: 2,
+                addressing: Addressing::Offset(16),
+                ..
+            } => permissive = true,
+            _ => (),
+        }
+    }
+    let mut evidence = vec![];
+    if strict {
+        evidence.push("tests AVC_STRICT".to_string());
+    }
+    if eacces {
+        evidence.push("returns -EACCES".to_string());
+    }
+    if permissive {
+        evidence.push("reads avd->flags".to_string());
+    }
+    let score = evidence.len() as u32;
+    if score > 0 {
+        evidence.push(format!("prologue {}", prologue));
+    }
+    (score, evidence)
+}
+
+/// Call sites passing zeroed `driver`/`xperm` (SearchFeature1 in the C++ tool).
+fn search_by_call_sites(image: &[u8]) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    let mut checked = HashMap::new();
+    let mut calls = HashSet::new();
+    for anchor in find_words(image, is_zero_driver_arg) {
+        let call = words_in(image, anchor + 4, anchor + 4 + 4 * CALL_DISTANCE).find_map(
+            |(offset, word)| match decode(word, offset as u64) {
+                Inst::Branch { link: true, target } => Some((offset, target as usize)),
+                _ => None,
+            },
+        );
+        let (call, target) = match call {
+            Some(call) => call,
+            None => continue,
+        };
+        if !calls.insert(call) || !has_caller_features(image, anchor) {
+            continue;
+        }
+        let (score, mut evidence) = checked
+            .entry(target)
+            .or_insert_with(|| avc_denied_shape(image, target))
+            .clone();
+        if score == 0 {
+            continue;
+        }
+        let caller = match function_entry_before(image, anchor) {
+            Some(caller) => format!("function at 0x{:x}", caller),
+            None => "unknown function".to_string(),
+        };
+        evidence.insert(0, format!("called at 0x{:x} from {}", call, caller));
+        candidates.push(Candidate {
+            entry: target,
+            score: score + 1,
+            evidence,
+        });
+    }
+    candidates
+}
+
+/// Follows SELinux string references down two levels of calls (SearchFeature2 in
+/// the C++ tool).
+fn search_by_strings(image: &[u8]) -> Vec<Candidate> {
+    let strings: Vec<(&str, usize)> = SELINUX_STRINGS
+        .iter()
+        .flat_map(|(name, bytes)| {
+            // Strings starting with a NUL are matched on the following bytes.
+            let skip = bytes.iter().take_while(|&&b| b == 0).count();
+            find_bytes(image, bytes)
+                .into_iter()
+                .map(move |offset| (*name, offset + skip))
+        })
+        .collect();
+    let targets: Vec<usize> = strings.iter().map(|(_, offset)| *offset).collect();
+    let xrefs = find_xrefs(image, &targets);
+
+    let mut reached: HashMap<usize, HashSet<&str>> = HashMap::new();
+    for (name, string) in &strings {
+        let functions: HashSet<usize> = xrefs
+            .get(string)
+            .into_iter()
+            .flatten()
+            .filter_map(|&xref| function_entry_before(image, xref))
+            .collect();
+        for function in functions {
+            for callee in call_targets(image, function, function + CALLEE_WINDOW) {
+                reached.entry(callee).or_default().insert(name);
+                for next in call_targets(image, callee, callee + CALLEE_WINDOW) {
+                    reached.entry(next).or_default().insert(name);
+                }
+            }
+        }
+    }
+
+    let mut candidates = vec![];
+    for (entry, names) in reached {
+        let (score, mut evidence) = avc_denied_shape(image, entry);
+        if score < 2 {
+            continue;
+        }
+        let mut names: Vec<&str> = names.into_iter().collect();
+        names.sort();
+        evidence.insert(0, format!("reachable from {}", names.join(", ")));
+        candidates.push(Candidate {
+            entry,
+            score: score + names.len() as u32,
+            evidence,
+        });
+    }
+    candidates
+}
+
+/// Returns ranked `avc_denied` entry candidates, most likely first.
+pub fn find_avc_denied(image: &[u8]) -> Vec<Candidate> {
+    let mut candidates = search_by_call_sites(image);
+    candidates.extend(search_by_strings(image));
+    rank(candidates)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_find_avc_denied() {
+        let mut image = assemble(
+            r#"
+                RET
+            avc_has_perm_noaudit:
+                STP X29, X30, [SP, #-64]!
+                MOV X29, SP
+                ADRP X8, #0x1000
+                ADD X8, X8, #0x80
+                MOV W9, #3
+                LDR W10, [X1, #4]
+                MOV W11, #4
+                .word 0x1A8B012C        // CSEL W12, W9, W11, EQ
+                .word 0x1305150D        // SBFX W13, W8, #5, #1
+                MOV W4, #0
+                MOV W5, WZR
+                BL avc_denied
+                BL decoy
+                LDP X29, X30, [SP], #64
+                RET
+            avc_denied:
+                PACIASP
+                TBNZ W6, #0, eacces
+                LDR W8, [X7, #16]
+                TBNZ W8, #0, out
+            eacces:
+                MOV W0, #-13
+                AUTIASP
+                RET
+            out:
+                MOV W0, WZR
+                AUTIASP
+                RET
+            decoy:
+                ADRP X0, #0
+                RET
+            "#,
+        )
+        .unwrap();
+        image.resize(0x2000, 0);
+        let string = SELINUX_STRINGS[0].1;
+        image[0x1080..0x1080 + string.len()].copy_from_slice(string);
+
+        let candidates = find_avc_denied(&image);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].entry, 0x40);
+        assert_eq!(candidates[0].score, (3 + 1) + (3 + 1));
+    }
+}
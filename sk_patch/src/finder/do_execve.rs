@@ -0,0 +1,209 @@
+//! Locates `do_execveat_common`, the function the do_execve hook is placed on.
+//!
+//! Port of `find_kernel_func/find_do_execve.cpp`: the first search looks for the
+//! `IS_ERR(filename)` check together with the `PF_NPROC_EXCEEDED` handling, the second
+//! one follows references to strings only used by or around `do_execve`.
+
+use super::{
+    call_targets, find_bytes, find_words, find_xrefs, function_entry_before, rank, words_in,
+    Candidate,
+};
+
+/// `CMN X1, #0xFFF`, i.e. `IS_ERR(filename)` on the second argument.
+const IS_ERR_X1: u32 = 0xB13FFC3F;
+/// Bytes searched on both sides of an `IS_ERR_X1` anchor.
+const FEATURE_RADIUS: usize = 320;
+/// Instructions following a string reference in which its callees are collected.
+const CALL_WINDOW: usize = 4 * 24;
+
+const DO_EXECVE_STRING: &[u8] = b"/dev/fd/%d/%s\0";
+const RUN_INIT_PROCESS_STRING: &[u8] = b"\x016Run %s as init process\n\0";
+const KERNEL_INIT_STRING: &[u8] = b"/sbin/init\0";
+
+/// `AND Wn, Wn, #0xFFFFEFFF`, clearing `PF_NPROC_EXCEEDED`.
+fn is_clear_nproc_exceeded(word: u32) -> bool {
+    let (rd, rn) = (word & 0x1F, (word >> 5) & 0x1F);
+    word & 0xFFFFFC00 == 0x12137800 && rd == rn && rd != 31
+}
+
+fn is_test_branch(word: u32) -> bool {
+    matches!(word >> 24, 0x36 | 0x37)
+}
+
+/// `MOV Wd, #-11`, i.e. `-EAGAIN`.
+fn is_mov_eagain(word: u32) -> bool {
+    word & 0xFFFFFFE0 == 0x12800140 && word & 0x1F != 31
+}
+
+/// Feature search around `IS_ERR(filename)` (SearchFeature1 in the C++ tool).
+fn search_by_instructions(image: &[u8]) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    for anchor in find_words(image, |word| word == IS_ERR_X1) {
+        let start = anchor.saturating_sub(FEATURE_RADIUS);
+        let end = anchor + FEATURE_RADIUS;
+        let mut clear_flags = None;
+        let mut test_branch = None;
+        let mut mov_eagain = None;
+        for (offset, word) in words_in(image, start, end) {
+            if clear_flags.is_none() && is_clear_nproc_exceeded(word) {
+                clear_flags = Some(offset);
+            }
+            if test_branch.is_none() && is_test_branch(word) {
+                test_branch = Some(offset);
+            }
+            if mov_eagain.is_none() && is_mov_eagain(word) {
+                mov_eagain = Some(offset);
+            }
+        }
+        let (clear_flags, test_branch, mov_eagain) = match (clear_flags, test_branch, mov_eagain) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => continue,
+        };
+        let entry = match function_entry_before(image, anchor) {
+            Some(entry) => entry,
+            None => {
+                log::debug!("no function entry found before 0x{:x}", anchor);
+                continue;
+            }
+        };
+        let mut evidence = vec![format!(
+            "IS_ERR(filename) at 0x{:x} near PF_NPROC_EXCEEDED at 0x{:x}, -EAGAIN at 0x{:x}",
+            anchor, clear_flags, mov_eagain
+        )];
+        let mut score = 2;
+        // The flag check and the -EAGAIN return come before the filename is used.
+        if test_branch < anchor && mov_eagain < anchor {
+            score += 1;
+            evidence.push("flag check precedes IS_ERR".to_string());
+        }
+        candidates.push(Candidate {
+            entry,
+            score,
+            evidence,
+        });
+    }
+    candidates
+}
+
+/// String reference search (SearchFeature2 in the C++ tool).
+fn search_by_strings(image: &[u8]) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    let dev_fd = find_bytes(image, DO_EXECVE_STRING);
+    let run_init = find_bytes(image, RUN_INIT_PROCESS_STRING);
+    let sbin_init = find_bytes(image, KERNEL_INIT_STRING);
+    let targets: Vec<usize> = dev_fd
+        .iter()
+        .chain(run_init.iter())
+        .chain(sbin_init.iter())
+        .copied()
+        .collect();
+    let xrefs = find_xrefs(image, &targets);
+    let refs_to = |strings: &[usize]| -> Vec<usize> {
+        strings
+            .iter()
+            .filter_map(|s| xrefs.get(s))
+            .flatten()
+            .copied()
+            .collect()
+    };
+
+    for xref in refs_to(&dev_fd) {
+        if let Some(entry) = function_entry_before(image, xref) {
+            candidates.push(Candidate {
+                entry,
+                score: 2,
+                evidence: vec![format!("references \"/dev/fd/%d/%s\" at 0x{:x}", xref)],
+            });
+        }
+    }
+
+    // kernel_init() and run_init_process() end up calling do_execve; their callees
+    // corroborate candidates found by other means but are not candidates themselves.
+    let run_init_refs = refs_to(&run_init);
+    let sbin_init_refs = refs_to(&sbin_init);
+    let inlined = run_init_refs
+        .iter()
+        .any(|a| sbin_init_refs.iter().any(|b| a.abs_diff(*b) <= 32));
+    if inlined {
+        log::info!("run_init_process appears to be inlined into kernel_init");
+    }
+    for xref in run_init_refs.iter().chain(sbin_init_refs.iter()) {
+        for target in call_targets(image, *xref, xref + CALL_WINDOW) {
+            candidates.push(Candidate {
+                entry: target,
+                score: 0,
+                evidence: vec![format!("called near init string reference at 0x{:x}", xref)],
+            });
+        }
+    }
+    candidates
+}
+
+/// Returns ranked `do_execve` entry candidates, most likely first.
+pub fn find_do_execve(image: &[u8]) -> Vec<Candidate> {
+    let mut candidates = search_by_instructions(image);
+    candidates.extend(search_by_strings(image));
+    let ranked = rank(candidates);
+    // Only keep call targets that some other search agrees with.
+    ranked.into_iter().filter(|c| c.score > 0).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_find_do_execve() {
+        let mut image = assemble(
+            r#"
+                RET
+            decoy:
+                STP X29, X30, [SP, #-16]!
+                CMN X1, #0xFFF
+                LDP X29, X30, [SP], #16
+                RET
+            do_execveat_common:
+                PACIASP
+                STP X29, X30, [SP, #-48]!
+                MOV X29, SP
+                STP X19, X20, [SP, #16]
+                MRS X8, SP_EL0
+                LDR W9, [X8, #44]
+                TBZ W9, #12, check
+                MOV W0, #-11
+                B out
+            check:
+                AND W9, W9, #0xFFFFEFFF
+                STR W9, [X8, #44]
+                CMN X1, #0xFFF
+                ADRP X0, #0x1000
+                ADD X0, X0, #0x40
+            out:
+                LDP X19, X20, [SP, #16]
+                LDP X29, X30, [SP], #48
+                AUTIASP
+                RET
+            kernel_init:
+                STP X29, X30, [SP, #-16]!
+                ADRP X0, #0x1000
+                ADD X0, X0, #0x50
+                BL do_execveat_common
+                LDP X29, X30, [SP], #16
+                RET
+            "#,
+        )
+        .unwrap();
+        image.resize(0x2000, 0);
+        let dev_fd = 0x1040;
+        image[dev_fd..dev_fd + DO_EXECVE_STRING.len()].copy_from_slice(DO_EXECVE_STRING);
+        // kernel_init's ADRP sits at 0x60, page 0x1000 + 0x50 = 0x1050.
+        let sbin_init = 0x1050;
+        image[sbin_init..sbin_init + KERNEL_INIT_STRING.len()].copy_from_slice(KERNEL_INIT_STRING);
+
+        let candidates = find_do_execve(&image);
+        assert_eq!(candidates[0].entry, 0x14);
+        assert_eq!(candidates[0].score, 2 + 1 + 2);
+        assert!(candidates.iter().all(|c| c.entry != 0x4 || c.score < 5));
+    }
+}
@@ -0,0 +1,297 @@
+//! Derives the `task_struct` offsets of `cred` and `seccomp` from `proc_pid_status`.
+//!
+//! `find_kernel_func/find_proc_pid_status.cpp` only reports where the `TracerPid:` and
+//! `Seccomp:` strings are referenced. Here the code around those references is read:
+//! `task_state()` loads `task->real_cred` and prints its uids, `task_seccomp()` loads
+//! `task->seccomp.mode` right before printing it. `cred` directly follows `real_cred`,
+//! so `cred = real_cred + 8`. Where the uids are depends on the cred layout.
+
+use std::fmt;
+
+use super::{find_bytes, find_xrefs, function_entry_before, words_in};
+use crate::{
+    cred_layout::CredLayout,
+    disassembler::{decode, Addressing, Inst},
+};
+
+const TRACER_PID_STRINGS: &[&[u8]] = &[b"\nTracerPid:\t\0", b"State:\t%s\n"];
+const SECCOMP_STRINGS: &[&[u8]] = &[b"\nSeccomp:\t\0", b"Seccomp:\t\0", b"Seccomp:\t%d\n\0"];
+
+/// Plausible range of `task_struct` member offsets.
+const TASK_FIELD_RANGE: std::ops::Range<i64> = 0x100..0x2000;
+/// Bytes before a `TracerPid:` reference searched when the function entry is unknown.
+const CRED_LOOKBEHIND: usize = 0x800;
+/// Bytes after a `TracerPid:` reference searched for the cred load and its uses.
+const CRED_LOOKAHEAD: usize = 0x400;
+/// Instructions after a `real_cred` load searched for uid loads.
+const CRED_USE_WINDOW: usize = 96;
+/// Instructions around a `Seccomp:` reference searched for the mode load.
+const SECCOMP_WINDOW: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+/// An offset into `task_struct` together with why it was picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldOffset {
+    pub value: usize,
+    pub confidence: Confidence,
+    pub evidence: Vec<String>,
+}
+
+impl fmt::Display for FieldOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:x} ({:?} confidence): {}",
+            self.value,
+            self.confidence,
+            self.evidence.join("; ")
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TaskOffsets {
+    pub cred: Option<FieldOffset>,
+    pub seccomp: Option<FieldOffset>,
+}
+
+fn xrefs_to_any(image: &[u8], strings: &[&[u8]]) -> Vec<usize> {
+    let mut targets: Vec<usize> = strings.iter().flat_map(|s| find_bytes(image, s)).collect();
+    targets.sort();
+    targets.dedup();
+    let mut xrefs: Vec<usize> = find_xrefs(image, &targets)
+        .into_values()
+        .flatten()
+        .collect();
+    xrefs.sort();
+    xrefs
+}
+
+/// `LDR Xt, [Xn, #imm]` with `imm` in the `task_struct` range.
+fn task_pointer_load(inst: &Inst) -> Option<(u32, i64)> {
+    match *inst {
+        Inst::LoadStore {
+            load: true,
+            size: 3,
+            rt,
+            addressing: Addressing::Offset(imm),
+            ..
+        } if TASK_FIELD_RANGE.contains(&imm) => Some((rt, imm)),
+        _ => None,
+    }
+}
+
+/// Which of `uid_offsets`, those of `uid`, `suid`, `euid` and `fsuid` printed on `Uid:`,
+/// are read through `reg` after `start`.
+fn cred_uid_loads(image: &[u8], start: usize, reg: u32, uid_offsets: &[i64]) -> Vec<i64> {
+    let mut found = vec![];
+    for (offset, word) in words_in(image, start, start + 4 * CRED_USE_WINDOW) {
+        let inst = decode(word, offset as u64);
+        let loaded = match inst {
+            Inst::LoadStore {
+                load: true,
+                size: 2,
+                rn,
+                addressing: Addressing::Offset(imm),
+                ..
+            } if rn == reg => vec![imm],
+            Inst::LoadStorePair {
+                load: true,
+                is64: false,
+                rn,
+                addressing: Addressing::Offset(imm),
+                ..
+            } if rn == reg => vec![imm, imm + 4],
+            _ => vec![],
+        };
+        for imm in loaded {
+            if uid_offsets.contains(&imm) && !found.contains(&imm) {
+                found.push(imm);
+            }
+        }
+        if inst.writes(reg) {
+            break;
+        }
+    }
+    found
+}
+
+fn find_cred(image: &[u8]) -> Option<FieldOffset> {
+    // (real_cred offset, uid offsets read through it, load address)
+    let mut loads: Vec<(i64, Vec<i64>, usize)> = vec![];
+    let uid_offsets = CredLayout::detect(image).uids().map(|offset| offset as i64);
+    for xref in xrefs_to_any(image, TRACER_PID_STRINGS) {
+        let start = function_entry_before(image, xref)
+            .unwrap_or_else(|| xref.saturating_sub(CRED_LOOKBEHIND));
+        for (offset, word) in words_in(image, start, xref + CRED_LOOKAHEAD) {
+            if let Some((rt, imm)) = task_pointer_load(&decode(word, offset as u64)) {
+                let uids = cred_uid_loads(image, offset + 4, rt, &uid_offsets);
+                if uids.len() >= 2 {
+                    loads.push((imm, uids, offset));
+                }
+            }
+        }
+    }
+    let most_uids = loads.iter().map(|(_, uids, _)| uids.len()).max()?;
+    let mut ambiguous: Vec<i64> = loads
+        .iter()
+        .filter(|(_, uids, _)| uids.len() == most_uids)
+        .map(|(imm, _, _)| *imm)
+        .collect();
+    ambiguous.sort();
+    ambiguous.dedup();
+    let best = loads
+        .into_iter()
+        .find(|(_, uids, _)| uids.len() == most_uids)?;
+    ambiguous.retain(|imm| *imm != best.0);
+    let (real_cred, uids, load) = best;
+    let mut confidence = if uids.len() >= 3 {
+        Confidence::High
+    } else {
+        Confidence::Medium
+    };
+    let mut evidence = vec![
+        format!("real_cred 0x{:x} loaded at 0x{:x}", real_cred, load),
+        format!("{} of 4 Uid: fields read through it", uids.len()),
+    ];
+    if !ambiguous.is_empty() {
+        confidence = Confidence::Low;
+        evidence.push(format!("equally likely: {:x?}", ambiguous));
+    }
+    Some(FieldOffset {
+        value: real_cred as usize + 8,
+        confidence,
+        evidence,
+    })
+}
+
+fn find_seccomp(image: &[u8], cred: Option<&FieldOffset>) -> Option<FieldOffset> {
+    // (distance to the reference, load address, mode offset, loaded into W2)
+    let mut best: Option<(usize, usize, i64, bool)> = None;
+    for xref in xrefs_to_any(image, SECCOMP_STRINGS) {
+        let start = xref.saturating_sub(4 * SECCOMP_WINDOW);
+        for (offset, word) in words_in(image, start, xref + 4 * SECCOMP_WINDOW) {
+            let (rt, imm) = match decode(word, offset as u64) {
+                Inst::LoadStore {
+                    load: true,
+                    size: 2,
+                    rt,
+                    addressing: Addressing::Offset(imm),
+                    ..
+                } if TASK_FIELD_RANGE.contains(&imm) => (rt, imm),
+                _ => continue,
+            };
+            let distance = offset.abs_diff(xref);
+            // The mode is the third argument of seq_put_decimal_ull()/seq_printf().
+            let third_arg = rt == 2;
+            let better = match best {
+                Some((best_distance, _, _, best_third_arg)) => {
+                    (third_arg, std::cmp::Reverse(distance))
+                        > (best_third_arg, std::cmp::Reverse(best_distance))
+                }
+                None => true,
+            };
+            if better {
+                best = Some((distance, offset, imm, third_arg));
+            }
+        }
+    }
+    let (_, load, seccomp, third_arg) = best?;
+    let mut confidence = if third_arg {
+        Confidence::High
+    } else {
+        Confidence::Medium
+    };
+    let mut evidence = vec![format!(
+        "seccomp.mode 0x{:x} loaded at 0x{:x} next to the Seccomp: string",
+        seccomp, load
+    )];
+    if let Some(cred) = cred {
+        if seccomp as usize <= cred.value {
+            confidence = Confidence::Low;
+            evidence.push("does not follow cred in task_struct".to_string());
+        }
+    }
+    Some(FieldOffset {
+        value: seccomp as usize,
+        confidence,
+        evidence,
+    })
+}
+
+/// Derives the `cred` and `seccomp` offsets used by the hooks.
+pub fn find_task_offsets(image: &[u8]) -> TaskOffsets {
+    let cred = find_cred(image);
+    let seccomp = find_seccomp(image, cred.as_ref());
+    TaskOffsets { cred, seccomp }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_find_task_offsets() {
+        let layouts: [(&[u8], [usize; 4]); 3] = [
+            (b"", CredLayout::DEFAULT.uids()),
+            (
+                b"Linux version 4.19.0\0\x013CRED: Invalid credentials\n\0",
+                [20, 28, 36, 44],
+            ),
+            (b"Linux version 6.8.0\0", [8, 16, 24, 32]),
+        ];
+        for (strings, [uid, suid, euid, fsuid]) in layouts {
+            let mut image = assemble(&format!(
+                r#"
+                    RET
+                proc_pid_status:
+                    STP X29, X30, [SP, #-48]!
+                    MOV X29, SP
+                    STP X19, X20, [SP, #16]
+                    MOV X19, X0
+                    MOV X20, X3
+                    LDR X8, [X20, #0x5E0]
+                    LDR X21, [X20, #0x728]
+                    ADRP X1, #0x1000
+                    ADD X1, X1, #0x100
+                    MOV X0, X19
+                    BL #0x800
+                    LDR W2, [X21, #{}]
+                    LDP W3, W4, [X21, #{}]
+                    LDR W5, [X21, #{}]
+                    LDR W6, [X21, #{}]
+                    ADRP X1, #0x1000
+                    ADD X1, X1, #0x110
+                    LDR W2, [X20, #0xAB0]
+                    MOV X0, X19
+                    BL #0x800
+                    LDP X19, X20, [SP, #16]
+                    LDP X29, X30, [SP], #48
+                    RET
+                "#,
+                uid, suid, euid, fsuid
+            ))
+            .unwrap();
+            image.resize(0x2000, 0);
+            image[0x1100..0x1100 + TRACER_PID_STRINGS[0].len()]
+                .copy_from_slice(TRACER_PID_STRINGS[0]);
+            image[0x1110..0x1110 + SECCOMP_STRINGS[0].len()].copy_from_slice(SECCOMP_STRINGS[0]);
+            image[0x1200..0x1200 + strings.len()].copy_from_slice(strings);
+
+            let offsets = find_task_offsets(&image);
+            let cred = offsets.cred.unwrap();
+            assert_eq!(cred.value, 0x730);
+            assert_eq!(cred.confidence, Confidence::High, "{:?}", cred);
+            let seccomp = offsets.seccomp.unwrap();
+            assert_eq!(seccomp.value, 0xAB0);
+            assert_eq!(seccomp.confidence, Confidence::High);
+        }
+    }
+}
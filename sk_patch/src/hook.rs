@@ -1,10 +1,150 @@
+use crate::{
+    aarch64,
+    cred_layout::CredLayout,
+    kernel_version::KernelVersion,
+    privileges::Privileges,
+    siphash::{initial_state, siphash128},
+    uid_range::{UidRange, AID_USER_OFFSET},
+    LINE_ENDLING,
+};
+
+/// Where the hooker runs the hookee's first instruction, which the hookee patch
+/// overwrites with the `B` to the hooker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OriginalAt {
+    /// Before the saved registers are pushed, the body runs after it.
+    Entry,
+    /// After the saved registers are popped, right before jumping back.
+    Exit,
+}
+
+/// Describes a hook, the hooker is generated around its body:
+///
+/// ```text
+/// original:       ; the hookee's first instruction, with OriginalAt::Entry
+/// STP ...         ; the saved registers
+/// body            ; branches to `end` to leave the hook
+/// end:
+/// LDP ...
+/// original:       ; with OriginalAt::Exit
+/// B jump_back     ; the instruction after the hookee entry
+/// data            ; labelled by the body itself
+/// ```
+#[derive(Debug, Clone)]
+pub struct HookSpec {
+    /// The hooked kernel function.
+    pub name: &'static str,
+    pub hookee_entry: usize,
+    pub hooker_entry: usize,
+    /// Register pairs pushed in order and popped in reverse.
+    saved: Vec<(u32, u32)>,
+    pub original_at: OriginalAt,
+    /// Whether the hookee is only called through function pointers.
+    pub called_indirectly: bool,
+    body: String,
+    data: String,
+}
+
+impl HookSpec {
+    pub fn new(name: &'static str, hookee_entry: usize, hooker_entry: usize) -> Self {
+        HookSpec {
+            name,
+            hookee_entry,
+            hooker_entry,
+            saved: vec![],
+            original_at: OriginalAt::Entry,
+            called_indirectly: false,
+            body: String::new(),
+            data: String::new(),
+        }
+    }
+
+    pub fn save(mut self, pairs: &[(u32, u32)]) -> Self {
+        self.saved = pairs.to_vec();
+        self
+    }
+
+    pub fn original_at(mut self, original_at: OriginalAt) -> Self {
+        self.original_at = original_at;
+        self
+    }
+
+    pub fn called_indirectly(mut self) -> Self {
+        self.called_indirectly = true;
+        self
+    }
+
+    pub fn body(mut self, body: String) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn data(mut self, data: String) -> Self {
+        self.data = data;
+        self
+    }
+
+    /// Pops the saved registers, for bodies returning from the hookee themselves.
+    pub fn restore_asm(&self) -> String {
+        self.saved
+            .iter()
+            .rev()
+            .map(|(first, second)| aarch64!("LDP X{}, X{}, [sp], #16", first, second))
+            .collect()
+    }
+
+    fn save_asm(&self) -> String {
+        self.saved
+            .iter()
+            .map(|(first, second)| aarch64!("STP X{}, X{}, [sp, #-16]!", first, second))
+            .collect()
+    }
+
+    /// The hooker, with the `original:` slot holding a placeholder.
+    pub fn asm(&self) -> String {
+        let original = aarch64! {
+            "original:";
+            "MOV X0, X0";                   // Reserved for hookee's first instruction
+        };
+        let mut asm = aarch64!(".equ jump_back, {}", self.hookee_entry + 4);
+        if self.original_at == OriginalAt::Entry {
+            asm += &original;
+        }
+        asm += &self.save_asm();
+        asm += &self.body;
+        asm += &aarch64!("end:");
+        asm += &self.restore_asm();
+        if self.original_at == OriginalAt::Exit {
+            asm += &original;
+        }
+        asm += &aarch64!("B jump_back");
+        asm += &self.data;
+        asm
+    }
+}
+
+/// Most root keys the do_execve hook holds.
+pub const MAX_ROOT_KEYS: usize = 16;
+/// Most uid ranges the do_execve hook holds.
+pub const MAX_ALLOWED_UIDS: usize = 64;
+
 #[derive(Debug)]
 pub struct DoExecveHook {
-    pub root_key_size: usize,
+    /// Any of them grants the privileges, a single key keeps the original hooker.
+    pub root_keys: Vec<String>,
+    /// Uids the privileges are granted to, anyone when empty.
+    pub allowed_uids: Vec<UidRange>,
+    /// SipHash key of the root key digests kept instead of the keys, see [`crate::siphash`].
+    pub digest_key: Option<[u64; 2]>,
+    /// What a matching filename is granted.
+    pub privileges: Privileges,
     pub hooker_entry: usize,
     pub hookee_entry: usize,
     pub cred_offset: usize,
-    pub seccomp_offset: usize,
+    pub cred_layout: CredLayout,
+    /// Where `task_struct::seccomp` is, the hook leaves seccomp and the thread flags
+    /// alone without it.
+    pub seccomp_offset: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -12,27 +152,563 @@ pub struct AVCDeniedHook {
     pub hooker_entry: usize,
     pub hookee_entry: usize,
     pub cred_offset: usize,
+    pub cred_layout: CredLayout,
+}
+
+/// A MOVZ and the MOVKs loading `imm` into `rd`, a W or an X register.
+fn mov_imm_asm(rd: &str, imm: u64) -> String {
+    let halfwords = if rd.starts_with('W') { 2 } else { 4 };
+    let mut asm = aarch64!("MOVZ {}, #0x{:x}", rd, imm & 0xFFFF);
+    for halfword in 1..halfwords {
+        let shift = halfword * 16;
+        if (imm >> shift) & 0xFFFF != 0 {
+            asm += &aarch64!(
+                "MOVK {}, #0x{:x}, LSL #{}",
+                rd,
+                (imm >> shift) & 0xFFFF,
+                shift
+            );
+        }
+    }
+    asm
 }
 
+/// One SipRound on v0..v3 in X9..X12, rotating left by n as rotating right by 64 - n.
+fn sip_round_asm() -> String {
+    aarch64! {
+        "ADD X9, X9, X10";                                      // v0 += v1
+        "EOR X10, X9, X10, ROR #51";                            // v1 = rotl(v1, 13) ^ v0
+        "ORR X9, XZR, X9, ROR #32";                             // v0 = rotl(v0, 32)
+        "ADD X11, X11, X12";                                    // v2 += v3
+        "EOR X12, X11, X12, ROR #48";                           // v3 = rotl(v3, 16) ^ v2
+        "ADD X9, X9, X12";                                      // v0 += v3
+        "EOR X12, X9, X12, ROR #43";                            // v3 = rotl(v3, 21) ^ v0
+        "ADD X11, X11, X10";                                    // v2 += v1
+        "EOR X10, X11, X10, ROR #47";                           // v1 = rotl(v1, 17) ^ v2
+        "ORR X11, XZR, X11, ROR #32";                           // v2 = rotl(v2, 32)
+    }
+}
+
+/// Hides the directories named `su_*` from processes whose uid is not root. filldir64
+/// does not know which directory is listed, so they are hidden wherever they are, not
+/// only under the `sk_cli deploy` target; files named `su_*` stay visible.
+#[derive(Debug)]
+pub struct Filldir64Hook {
+    pub hooker_entry: usize,
+    pub hookee_entry: usize,
+    pub cred_offset: usize,
+    pub cred_layout: CredLayout,
+    /// filldir64 returns a bool telling whether to go on since Linux 6.1, 0 before.
+    pub returns_bool: bool,
+}
+
+impl Filldir64Hook {
+    /// Whether filldir64 returns a bool, assumed not when the kernel version is unknown.
+    pub fn returns_bool_for(version: Option<KernelVersion>) -> bool {
+        version.is_some_and(|version| version >= KernelVersion::new(6, 1, 0))
+    }
+}
+
+/// Prefix of the directories `sk_cli deploy` creates.
+pub const HIDDEN_PREFIX: &str = "su_";
+/// `d_type` of directories, see `include/linux/fs_types.h`.
+const DT_DIR: u32 = 4;
+
 pub trait Hook {
-    fn hookee_entry(&self) -> usize;
-    fn hooker_entry(&self) -> usize;
+    fn spec(&self) -> HookSpec;
 }
 
-macro_rules! impl_hook_for {
-    ($($type:ty),*) => {
-        $(
-            impl Hook for $type {
-                fn hookee_entry(&self) -> usize {
-                    self.hookee_entry
-                }
+impl Hook for DoExecveHook {
+    fn spec(&self) -> HookSpec {
+        // The key table and the digest loop do not expect an empty list, the patcher
+        // refuses one.
+        debug_assert!(
+            !self.root_keys.is_empty(),
+            "the do_execve hook needs a root key"
+        );
+        let body = aarch64! {
+            "MOV X7, 0xFFFFFFFFFFFFF001";                       // X7 = (unsigned long)(-MAX_ERRNO)
+            "CMP X1, X7";                                       // compare X1 and X7
+            "BCS end";                                          // if X1 > X7 goto end
+            "LDR X7, [X1]";                                     // X7 = *X1
+            "CBZ X7, end";                                      // if X7 == 0 goto end
+        } + &self.compare_keys_asm()
+            + &self.check_uid_asm()
+            + &self.grant_asm();
+        let saved: &[(u32, u32)] = match self.digest_key {
+            Some(_) => &[(7, 8), (9, 10), (11, 12), (13, 14), (15, 16)],
+            None => &[(7, 8), (9, 10), (11, 12)],
+        };
+        HookSpec::new("do_execve", self.hookee_entry, self.hooker_entry)
+            .save(saved)
+            .body(body)
+            .data(self.root_keys_asm() + &self.allowed_uids_asm())
+    }
+}
 
-                fn hooker_entry(&self) -> usize {
-                    self.hooker_entry
-                }
+impl DoExecveHook {
+    /// Writes the privileges into the cred of current and clears its thread flags and seccomp.
+    fn grant_asm(&self) -> String {
+        let privileges = &self.privileges;
+        let layout = &self.cred_layout;
+        let mut asm = aarch64! {
+            "MRS X8, SP_EL0";                                   // X8 = (struct task_struct *) current_thread_info()
+            "LDR X10, [X8, #{}]", self.cred_offset;             // X10 = X8->cred
+        };
+        let root = Privileges::ROOT;
+        if (privileges.uid, privileges.gid, privileges.capabilities)
+            == (root.uid, root.gid, root.capabilities)
+        {
+            asm += &aarch64! {
+                "MOV X7, #{}", layout.ids;                      // X7 = &X10->uid - X10
+                "MOV W9, WZR";                                  // W9 = 0
+                "overwrite_cred:";
+                "STR W9, [X10, X7]";                            // *(X10 + X7) = W9
+                "ADD X7, X7, 4";                                // X7 += 4
+                "CMP X7, #{}", layout.caps;                     // compare X7 and the capabilities
+                "BLT overwrite_cred";                           // if the ids are left goto overwrite_cred
+                "MOV W9, 0xFFFFFFFF";                           // W9 = 0xFFFFFFFF
+                "CMP X7, #{}", layout.caps_end();               // compare X7 and the capabilities end
+                "BLT overwrite_cred";                           // if capabilities are left goto overwrite_cred
+            };
+        } else {
+            asm += &mov_imm_asm("W9", privileges.uid as u64);
+            for offset in layout.uids() {
+                asm += &aarch64!("STR W9, [X10, #{}]", offset);
+            }
+            asm += &mov_imm_asm("W9", privileges.gid as u64);
+            for offset in layout.gids() {
+                asm += &aarch64!("STR W9, [X10, #{}]", offset);
             }
-        )*
-    };
+            asm += &aarch64!("STR WZR, [X10, #{}]", layout.securebits);
+            asm += &mov_imm_asm("X9", privileges.capabilities);
+            for offset in layout.cap_sets() {
+                asm += &aarch64!("STR X9, [X10, #{}]", offset);
+            }
+        }
+        let clear_flags = match privileges.thread_flags {
+            0 => None,
+            0xFFF => Some(aarch64! {
+                "BIC W10, W10,#0xFFF";                          // X10 = X10 & ~(0xFFF)
+            }),
+            thread_flags => Some(
+                mov_imm_asm("W9", thread_flags as u64)
+                    + &aarch64! {
+                        "BIC W10, W10, W9";                     // X10 = X10 & ~(thread_flags)
+                    },
+            ),
+        };
+        let Some(seccomp_offset) = self.seccomp_offset else {
+            return asm;
+        };
+        if let Some(clear_flags) = clear_flags {
+            asm += &aarch64! {
+                "LDXR W10, [X8]";                               // W10 = *X8
+            };
+            asm += &clear_flags;
+            asm += &aarch64! {
+                "STXR W11, W10, [X8]";                          // *X8 = W10
+            };
+        }
+        asm + &aarch64! {
+            "STR WZR, [X8, #{}]", seccomp_offset;               // X8->seccomp.mode = 0
+            "STR XZR, [X8, #{}]", seccomp_offset + 8;           // X8->seccomp.filter = 0
+        }
+    }
+
+    /// Falls through when the filename at X7 starts with a root key, branches to `end` otherwise.
+    fn compare_keys_asm(&self) -> String {
+        if self.digest_key.is_some() {
+            return self.compare_digests_asm();
+        }
+        if let [root_key] = &self.root_keys[..] {
+            return aarch64! {
+                "ADR X8, root_key";                             // X8 = &root_key
+                "MOV X9, #0";                                   // X9 = 0
+                "compare_key:";
+                "LDRB W10, [X7, X9]";                           // W10 = *(X7 + X9)
+                "CBZ W10, end";                                 // if W10 == 0 goto end
+                "LDRB W11, [X8, X9]";                           // W11 = *(X8 + X9)
+                "CBZ W11, end";                                 // if W11 == 0 goto end
+                "CMP W10, W11";                                 // compare W10 and W11
+                "B.NE end";                                     // if W10 != W11 goto end
+                "ADD X9, X9, 1";                                // X9 += 1
+                "CMP X9, #{}", root_key.len();                  // compare X9 and strlen(root_key)
+                "BLT compare_key";                              // if X9 < strlen(root_key) goto compare_key
+            };
+        }
+        aarch64! {
+            "ADR X8, root_key";                                 // X8 = &root_keys[0]
+            "next_key:";
+            "LDRB W11, [X8]";                                   // W11 = *X8
+            "CBZ W11, end";                                     // if the key is empty goto end
+            "MOV X9, #0";                                       // X9 = 0
+            "compare_key:";
+            "LDRB W11, [X8, X9]";                               // W11 = *(X8 + X9)
+            "CBZ W11, grant";                                   // if W11 == 0 the whole key matched
+            "LDRB W10, [X7, X9]";                               // W10 = *(X7 + X9)
+            "CMP W10, W11";                                     // compare W10 and W11
+            "B.NE skip_key";                                    // if W10 != W11 goto skip_key
+            "ADD X9, X9, 1";                                    // X9 += 1
+            "B compare_key";                                    // goto compare_key
+            "skip_key:";
+            "LDRB W11, [X8, X9]";                               // W11 = *(X8 + X9)
+            "ADD X9, X9, 1";                                    // X9 += 1
+            "CBNZ W11, skip_key";                               // if W11 != 0 goto skip_key
+            "ADD X8, X8, X9";                                   // X8 = the next key
+            "B next_key";                                       // goto next_key
+            "grant:";
+        }
+    }
+
+    /// Hashes the first 48 bytes of the filename at X7 and compares the digest with every
+    /// key digest, whether one matches is only looked at once all of them are compared.
+    ///
+    /// As with the plaintext keys, any filename starting with a root key matches, the
+    /// bytes after the 48th are not hashed. Filenames with a NUL in their first 48 bytes
+    /// are refused before hashing, so the 8 byte loads stay inside the string; `getname`
+    /// copies it into a names_cache buffer of PATH_MAX bytes either way.
+    fn compare_digests_asm(&self) -> String {
+        let mut asm = aarch64! {
+            "MOV X14, #0";                                      // X14 = 0
+            "check_length:";
+            "LDRB W13, [X7, X14]";                              // W13 = *(X7 + X14)
+            "CBZ W13, end";                                     // if the filename is shorter than 48 bytes goto end
+            "ADD X14, X14, #1";                                 // X14 += 1
+            "CMP X14, #48";                                     // compare X14 and 48
+            "BLT check_length";                                 // if X14 < 48 goto check_length
+            "ADR X8, key_digests";                              // X8 = &key_digests
+            "LDP X9, X10, [X8, #8]";                            // v0, v1 = the keyed initial state
+            "LDP X11, X12, [X8, #24]";                          // v2, v3
+            "MOV X14, #0";                                      // X14 = 0
+            "hash_block:";
+            "CMP X14, #48";                                     // compare X14 and 48
+            "B.EQ last_block";                                  // if X14 == 48 goto last_block
+            "LDR X13, [X7, X14]";                               // m = *(u64 *)(X7 + X14)
+            "B compress";                                       // goto compress
+            "last_block:";
+            "MOVZ X13, #0x3000, LSL #48";                       // m = 48 << 56, the message length
+            "compress:";
+            "EOR X12, X12, X13";                                // v3 ^= m
+            "MOV X15, #2";                                      // X15 = 2
+            "compress_round:";
+        };
+        asm += &sip_round_asm();
+        asm += &aarch64! {
+            "SUB X15, X15, #1";                                 // X15 -= 1
+            "CBNZ X15, compress_round";                         // if X15 != 0 goto compress_round
+            "EOR X9, X9, X13";                                  // v0 ^= m
+            "ADD X14, X14, #8";                                 // X14 += 8
+            "CMP X14, #56";                                     // compare X14 and 56
+            "BLT hash_block";                                   // if X14 < 56 goto hash_block
+            "MOV X13, #0xEE";                                   // X13 = 0xEE
+            "EOR X11, X11, X13";                                // v2 ^= 0xEE
+            "MOV X15, #4";                                      // X15 = 4
+            "first_half_round:";
+        };
+        asm += &sip_round_asm();
+        asm += &aarch64! {
+            "SUB X15, X15, #1";                                 // X15 -= 1
+            "CBNZ X15, first_half_round";                       // if X15 != 0 goto first_half_round
+            "EOR X16, X9, X10";                                 // X16 = v0 ^ v1
+            "EOR X16, X16, X11";                                // X16 ^= v2
+            "EOR X16, X16, X12";                                // X16 ^= v3, the first digest half
+            "MOV X13, #0xDD";                                   // X13 = 0xDD
+            "EOR X10, X10, X13";                                // v1 ^= 0xDD
+            "MOV X15, #4";                                      // X15 = 4
+            "second_half_round:";
+        };
+        asm += &sip_round_asm();
+        asm += &aarch64! {
+            "SUB X15, X15, #1";                                 // X15 -= 1
+            "CBNZ X15, second_half_round";                      // if X15 != 0 goto second_half_round
+            "EOR X13, X9, X10";                                 // X13 = v0 ^ v1
+            "EOR X13, X13, X11";                                // X13 ^= v2
+            "EOR X13, X13, X12";                                // X13 ^= v3, the second digest half
+            "LDR X14, [X8]";                                    // X14 = the number of key digests
+            "ADD X8, X8, #40";                                  // X8 = &key_digests[0]
+            "MOV X15, #0";                                      // X15 = 0
+            "compare_digest:";
+            "LDP X9, X10, [X8]";                                // X9, X10 = *X8
+            "ADD X8, X8, #16";                                  // X8 = the next key digest
+            "EOR X9, X9, X16";                                  // X9 ^= the first digest half
+            "EOR X10, X10, X13";                                // X10 ^= the second digest half
+            "ORR X9, X9, X10";                                  // X9 = 0 if the digests are equal
+            "SUB X10, XZR, X9";                                 // X10 = -X9
+            "ORR X9, X9, X10";                                  // the sign bit of X9 is set if X9 != 0
+            "MVN X9, X9";                                       // X9 = ~X9
+            "LSR X9, X9, #63";                                  // X9 = 1 if the digests are equal
+            "ORR X15, X15, X9";                                 // X15 |= X9
+            "SUB X14, X14, #1";                                 // X14 -= 1
+            "CBNZ X14, compare_digest";                         // if X14 != 0 goto compare_digest
+            "CBZ X15, end";                                     // if no digest is equal goto end
+        };
+        asm
+    }
+
+    /// Falls through when the uid of current is allowed, branches to `end` otherwise.
+    fn check_uid_asm(&self) -> String {
+        if self.allowed_uids.is_empty() {
+            return String::new();
+        }
+        aarch64! {
+            "MRS X8, SP_EL0";                                   // X8 = (struct task_struct *) current_thread_info()
+            "LDR X10, [X8, #{}]", self.cred_offset;             // X10 = X8->cred
+            "LDR W10, [X10, #{}]", self.cred_layout.ids;        // W10 = X10->uid
+            "MOVZ W11, #{}", AID_USER_OFFSET & 0xFFFF;
+            "MOVK W11, #{}, LSL #16", AID_USER_OFFSET >> 16;    // W11 = AID_USER_OFFSET
+            "MOV W7, W10";                                      // W7 = W10
+            "find_app_id:";
+            "CMP W7, W11";                                      // compare W7 and AID_USER_OFFSET
+            "B.LO check_uids";                                  // if W7 < AID_USER_OFFSET goto check_uids
+            "SUB W7, W7, W11";                                  // W7 -= AID_USER_OFFSET
+            "B find_app_id";                                    // goto find_app_id
+            "check_uids:";
+            "ADR X9, allowed_uids";                             // X9 = &allowed_uids[0]
+            "check_uid:";
+            "LDR W8, [X9]";                                     // W8 = X9->kind
+            "CBZ W8, end";                                      // if W8 == 0 the uid is not allowed, goto end
+            "LDP W11, W12, [X9, #4]";                           // W11 = X9->first, W12 = X9->last
+            "ADD X9, X9, #12";                                  // X9 = the next range
+            "CMP W8, #1";                                       // compare W8 and 1
+            "B.NE check_app_id";                                // if W8 != 1 the range is of app ids
+            "CMP W10, W11";                                     // compare W10 and W11
+            "B.LO check_uid";                                   // if W10 < W11 goto check_uid
+            "CMP W10, W12";                                     // compare W10 and W12
+            "B.HI check_uid";                                   // if W10 > W12 goto check_uid
+            "B uid_allowed";                                    // goto uid_allowed
+            "check_app_id:";
+            "CMP W7, W11";                                      // compare W7 and W11
+            "B.LO check_uid";                                   // if W7 < W11 goto check_uid
+            "CMP W7, W12";                                      // compare W7 and W12
+            "B.HI check_uid";                                   // if W7 > W12 goto check_uid
+            "uid_allowed:";
+        }
+    }
+
+    /// The allowed uids as `kind, first, last` words, kind 1 for uids and 2 for app
+    /// ids of any user, ended by kind 0.
+    fn allowed_uids_asm(&self) -> String {
+        if self.allowed_uids.is_empty() {
+            return String::new();
+        }
+        let mut asm = aarch64!("allowed_uids:");
+        for range in &self.allowed_uids {
+            asm += &aarch64! {
+                ".word {}", if range.per_user { 2 } else { 1 };
+                ".word {}", range.first;
+                ".word {}", range.last;
+            };
+        }
+        asm + &aarch64!(".word 0")
+    }
+
+    /// The root keys, several of them end with an empty one. With a digest key, the
+    /// number of keys, the keyed SipHash state and the key digests instead.
+    fn root_keys_asm(&self) -> String {
+        if let Some(digest_key) = self.digest_key {
+            let mut asm = aarch64! {
+                "key_digests:";
+                ".quad {}", self.root_keys.len();
+            };
+            let digests = self
+                .root_keys
+                .iter()
+                .flat_map(|root_key| siphash128(digest_key, root_key.as_bytes()));
+            for quad in initial_state(digest_key).into_iter().chain(digests) {
+                asm += &aarch64!(".quad 0x{:x}", quad);
+            }
+            return asm;
+        }
+        let mut asm = aarch64!("root_key:");
+        for root_key in &self.root_keys {
+            asm += &aarch64!(".asciz \"{}\"", root_key);
+        }
+        if self.root_keys.len() > 1 {
+            asm += &aarch64!(".byte 0");
+        }
+        asm + &aarch64!(".align 2")
+    }
+}
+
+impl Hook for AVCDeniedHook {
+    fn spec(&self) -> HookSpec {
+        let spec = HookSpec::new("avc_denied", self.hookee_entry, self.hooker_entry)
+            .save(&[(7, 8), (9, 10)])
+            .original_at(OriginalAt::Exit);
+        let layout = &self.cred_layout;
+        let body = aarch64! {
+            "MRS X7, SP_EL0";			            // X7 = (struct task_struct *) current_thread_info()
+            "LDR X7, [X7, #{}]", self.cred_offset;  // X7 = X7->cred
+            "CBZ X7, end";			                // if X7 == 0 goto end
+            "MOV X8, #{}", layout.ids;              // X8 = &X7->uid - X7
+            "MOV W9, WZR";			                // W9 = 0
+            "check_ids:";
+            "LDR W10, [X7, X8]";		            // W10 = *(X7 + X8)
+            "CMP W10, W9";			                // compare W10 and W9
+            "B.NE end"; 				            // if W10 != W9 goto end
+            "ADD X8, X8, 4";			            // X8 += 4
+            "CMP X8, #{}", layout.securebits;       // compare X8 and &X7->securebits - X7
+            "BLT check_ids";				        // if ids are left goto check_ids
+            "ADD X8, X8, {}", layout.caps + 8 - layout.securebits; // X8 = &X7->cap_permitted - X7
+            "MOV X9, #{}", layout.full_caps;        // X9 = a full capability set
+            "check_caps:";
+            "LDR X10, [X7, X8]";		            // X10 = *(X7 + X8)
+            "ADD X8, X8, 8";			            // X8 += 8
+            "CMP X10, X9";			                // compare X10 and X9
+            "B.CC end";				                // if X10 < X9 goto end
+            "CMP X8, #{}", layout.cap_sets()[4];    // compare X8 and &X7->cap_ambient - X7
+            "BLT check_caps";				        // if X8 < &X7->cap_ambient goto check_caps
+        } + &spec.restore_asm()
+            + &aarch64! {
+                "MOV W0, WZR";
+                "RET";
+            };
+        spec.body(body)
+    }
 }
 
-impl_hook_for!(DoExecveHook, AVCDeniedHook);
\ No newline at end of file
+impl Hook for Filldir64Hook {
+    fn spec(&self) -> HookSpec {
+        // Called through `ctx->actor` only.
+        let spec = HookSpec::new("filldir64", self.hookee_entry, self.hooker_entry)
+            .save(&[(7, 8), (9, 10), (11, 12)])
+            .original_at(OriginalAt::Exit)
+            .called_indirectly();
+        let body = aarch64! {
+            "MRS X7, SP_EL0";                       // X7 = (struct task_struct *) current_thread_info()
+            "LDR X7, [X7, #{}]", self.cred_offset;  // X7 = X7->cred
+            "CBZ X7, end";                          // if X7 == 0 goto end
+            "LDR W8, [X7, #{}]", self.cred_layout.ids;  // W8 = X7->uid
+            "CBZ W8, end";                          // if W8 == 0 goto end
+            "CMP W5, #{}", DT_DIR;                  // compare d_type and DT_DIR
+            "B.NE end";                             // if the entry is no directory goto end
+            "ADR X8, hidden_prefix";                // X8 = &hidden_prefix
+            "MOV X9, #0";                           // X9 = 0
+            "compare_prefix:";
+            "LDRB W10, [X8, X9]";                   // W10 = *(X8 + X9)
+            "CBZ W10, hide";                        // if W10 == 0 the whole prefix matched
+            "CMP W9, W2";                           // compare X9 and namlen
+            "B.GE end";                             // if X9 >= namlen goto end
+            "LDRB W11, [X1, X9]";                   // W11 = *(name + X9)
+            "CMP W10, W11";                         // compare W10 and W11
+            "B.NE end";                             // if W10 != W11 goto end
+            "ADD X9, X9, 1";                        // X9 += 1
+            "B compare_prefix";                     // goto compare_prefix
+            "hide:";
+        } + &spec.restore_asm()
+            + &aarch64! {
+                "MOV W0, #{}", self.returns_bool as u32;  // skip the entry and go on
+                "RET";
+            };
+        spec.body(body).data(aarch64! {
+            "hidden_prefix:";
+            ".asciz \"{}\"", HIDDEN_PREFIX;
+            ".align 2";
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{asm_helper::asm_to_assembly, test_util::do_execve_hook};
+
+    #[test]
+    fn test_hook_spec() {
+        let spec = HookSpec::new("test", 0x100, 0x1000)
+            .save(&[(7, 8), (9, 10)])
+            .original_at(OriginalAt::Exit)
+            .body(aarch64!("CBZ X0, end"));
+        let assembly = asm_to_assembly(&spec.asm(), spec.hooker_entry).unwrap();
+        let expected = asm_to_assembly(
+            &aarch64! {
+                "STP X7, X8, [sp, #-16]!";
+                "STP X9, X10, [sp, #-16]!";
+                "CBZ X0, #4";
+                "LDP X9, X10, [sp], #16";
+                "LDP X7, X8, [sp], #16";
+                "MOV X0, X0";
+                "B #{}", 0x104 - 0x1018;
+            },
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(assembly.bytes, expected.bytes);
+        assert_eq!(assembly.offset_of("original").unwrap(), 0x14);
+        assert_eq!(assembly.offset_of("end").unwrap(), 0xC);
+    }
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_compare_digests() {
+        let digest_key = [0x0706050403020100, 0x0f0e0d0c0b0a0908];
+        let do_execve = DoExecveHook {
+            root_keys: vec!["Qm3".repeat(16), "x7Z".repeat(16)],
+            digest_key: Some(digest_key),
+            ..do_execve_hook()
+        };
+        let assembly = asm_to_assembly(&do_execve.spec().asm(), 0x1000).unwrap();
+        let key_digests = assembly.offset_of("key_digests").unwrap();
+        assert!(key_digests > assembly.offset_of("end").unwrap());
+        let quads: Vec<u64> = assembly.bytes[key_digests..]
+            .chunks(8)
+            .map(|quad| u64::from_le_bytes(quad.try_into().unwrap()))
+            .collect();
+        let mut expected = vec![2];
+        expected.extend(initial_state(digest_key));
+        expected.extend(siphash128(digest_key, "Qm3".repeat(16).as_bytes()));
+        expected.extend(siphash128(digest_key, "x7Z".repeat(16).as_bytes()));
+        assert_eq!(quads, expected);
+
+        // Rotating left by 13, 16, 21, 17 and 32 as rotating right.
+        let round = asm_to_assembly(&sip_round_asm(), 0).unwrap();
+        assert_eq!(
+            words(&round.bytes),
+            [
+                0x8B0A0129, 0xCACACD2A, 0xAAC983E9, 0x8B0C016B, 0xCACCC16C, 0x8B0C0129, 0xCACCAD2C,
+                0x8B0A016B, 0xCACABD6A, 0xAACB83EB,
+            ]
+        );
+        // The filename is checked for NULs before its first 8 bytes are hashed.
+        let check_length = assembly.offset_of("check_length").unwrap();
+        assert!(check_length < assembly.offset_of("hash_block").unwrap());
+        assert_eq!(
+            words(&assembly.bytes[check_length..check_length + 4]),
+            [0x386E68ED]
+        );
+    }
+
+    #[test]
+    fn test_check_uid() {
+        let do_execve = DoExecveHook {
+            allowed_uids: vec![
+                "2000".parse().unwrap(),
+                "10000-10099".parse().unwrap(),
+                "u*_a123".parse().unwrap(),
+            ],
+            ..do_execve_hook()
+        };
+        let assembly = asm_to_assembly(&do_execve.spec().asm(), 0x1000).unwrap();
+        let allowed_uids = assembly.offset_of("allowed_uids").unwrap();
+        assert!(allowed_uids > assembly.offset_of("end").unwrap());
+        assert_eq!(
+            words(&assembly.bytes[allowed_uids..]),
+            [1, 2000, 2000, 1, 10000, 10099, 2, 10123, 10123, 0]
+        );
+
+        // W11 = AID_USER_OFFSET, then the app id is found by subtracting it.
+        let check_uids = assembly.offset_of("check_uids").unwrap();
+        assert_eq!(
+            words(&assembly.bytes[check_uids - 7 * 4..check_uids]),
+            [0x5290D40B, 0x72A0002B, 0x2A0A03E7, 0x6B0B00FF, 0x54000063, 0x4B0B00E7, 0x17FFFFFD]
+        );
+        assert!(assembly.offset_of("uid_allowed").unwrap() < assembly.offset_of("end").unwrap());
+    }
+}
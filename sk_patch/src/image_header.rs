@@ -0,0 +1,91 @@
+//! The 64 byte header at the start of an arm64 kernel `Image`, see
+//! `Documentation/arch/arm64/booting.rst`.
+
+use std::fmt;
+
+use anyhow::anyhow;
+
+pub const MAGIC: &[u8] = b"ARM\x64";
+pub const MAGIC_OFFSET: usize = 56;
+pub const HEADER_SIZE: usize = 64;
+const FLAG_BIG_ENDIAN: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Offset from a 2MB aligned base the Image is loaded at.
+    pub text_offset: u64,
+    /// Size the kernel takes once loaded, bss included; zero before Linux 3.17.
+    pub image_size: u64,
+    pub flags: u64,
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl ImageHeader {
+    pub fn parse(kernel: &[u8]) -> anyhow::Result<Self> {
+        if kernel.len() < HEADER_SIZE || &kernel[MAGIC_OFFSET..HEADER_SIZE - 4] != MAGIC {
+            return Err(anyhow!(
+                "not an arm64 kernel Image, the ARM\\x64 magic is missing"
+            ));
+        }
+        let header = ImageHeader {
+            text_offset: read_u64(kernel, 8),
+            image_size: read_u64(kernel, 16),
+            flags: read_u64(kernel, 24),
+        };
+        if header.image_size != 0 && header.flags & FLAG_BIG_ENDIAN != 0 {
+            return Err(anyhow!("big-endian arm64 kernels are not supported"));
+        }
+        Ok(header)
+    }
+
+    /// The kernel page size, unknown for old headers.
+    pub fn page_size(&self) -> Option<usize> {
+        match (self.flags >> 1) & 3 {
+            1 => Some(4 << 10),
+            2 => Some(16 << 10),
+            3 => Some(64 << 10),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ImageHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "arm64 Image, text_offset 0x{:x}", self.text_offset)?;
+        if self.image_size != 0 {
+            write!(f, ", image_size 0x{:x}", self.image_size)?;
+        }
+        if let Some(page_size) = self.page_size() {
+            write!(f, ", {}K pages", page_size >> 10)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::test_kernel;
+
+    #[test]
+    fn test_parse_image_header() {
+        let kernel = test_kernel(0x2000);
+        let header = ImageHeader::parse(&kernel).unwrap();
+        assert_eq!(header.text_offset, 0x80000);
+        assert_eq!(header.image_size, 0x2000);
+        assert_eq!(header.page_size(), Some(0x1000));
+        assert_eq!(
+            header.to_string(),
+            "arm64 Image, text_offset 0x80000, image_size 0x2000, 4K pages"
+        );
+
+        assert!(ImageHeader::parse(&kernel[..0x20]).is_err());
+        assert!(ImageHeader::parse(&vec![0u8; 0x2000]).is_err());
+        let mut big_endian = kernel;
+        big_endian[24] |= 1;
+        assert!(ImageHeader::parse(&big_endian).is_err());
+    }
+}
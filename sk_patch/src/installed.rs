@@ -0,0 +1,995 @@
+//! Recognises the hooks sk_patch installed in an image, so they can be undone without
+//! the `.bak` file.
+//!
+//! A hook is the `B` to the hooker at the hookee entry and the hooker itself, which
+//! keeps the hookee's first instruction at its `original:` label. The hookers of the
+//! first sk_patch, which wrote the root key before the do_execve hooker, are recognised
+//! too.
+
+use std::fmt;
+
+use anyhow::anyhow;
+
+use crate::{
+    aarch64,
+    asm_helper::asm_to_assembly,
+    cred_layout::CredLayout,
+    disassembler::{decode, decode_at, read_word, Addressing, Inst},
+    finder::{find_bytes, words_in},
+    hook::{AVCDeniedHook, DoExecveHook, Filldir64Hook, Hook, MAX_ALLOWED_UIDS, MAX_ROOT_KEYS},
+    privileges::Privileges,
+    relocate::relocate,
+    uid_range::UidRange,
+    LINE_ENDLING,
+};
+
+/// Length of the root key the first sk_patch wrote right before the do_execve hooker.
+const LEGACY_ROOT_KEY_LEN: usize = 48;
+/// Where the first sk_patch kept the hookee's first instruction in the avc_denied hooker.
+const LEGACY_AVC_DENIED_ORIGINAL_AT: usize = 27 * 4;
+
+/// Where the parts of a hooker are, taken from its template.
+struct Layout {
+    name: &'static str,
+    /// Instructions which do not depend on the hook parameters, and where they start.
+    signature: Vec<u8>,
+    signature_at: usize,
+    original_at: usize,
+    /// The `B jump_back` to the instruction after the hookee entry.
+    jump_back_at: usize,
+    /// Size of the code with its data, the do_execve root keys follow it. The do_execve
+    /// code ends with the privileges it grants, its size is taken from the `ADR` of the
+    /// root keys instead.
+    code_size: usize,
+    /// How the do_execve root keys are stored.
+    keys: Option<Keys>,
+    /// Whether the root keys are followed by the allowed uids.
+    uid_table: bool,
+    /// The `LDR` of `task_struct::cred`.
+    cred_at: usize,
+    /// Whether the hooker may clear `task_struct::seccomp`, after the load of cred.
+    seccomp: bool,
+    /// The `CMP` against the root key length of a single key and the `ADR` of the root keys.
+    key_len_at: Option<usize>,
+    key_adr_at: Option<usize>,
+    /// Template words telling the variant from the other ones of the same hook.
+    variant_words: Vec<(usize, u32)>,
+    /// The `LDR W10, [X10, #uid]` starting the check of the allowed uids, its offset
+    /// depends on the cred layout.
+    uid_load_at: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keys {
+    Single,
+    /// A table ended by an empty key.
+    Table,
+    /// The number of keys, the SipHash state and a digest of each key.
+    Digests,
+    /// A single key without NUL right before the hooker, as the first sk_patch wrote it.
+    Legacy,
+}
+
+/// Offset of the first instruction in `bytes` accepted by `matcher`.
+fn position_of(bytes: &[u8], matcher: impl Fn(&Inst) -> bool) -> anyhow::Result<usize> {
+    words_in(bytes, 0, bytes.len())
+        .find(|&(offset, word)| matcher(&decode(word, offset as u64)))
+        .map(|(offset, _)| offset)
+        .ok_or_else(|| anyhow!("hooker template has changed"))
+}
+
+fn is_key_len_cmp(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::AddSubImm {
+            sub: true,
+            set_flags: true,
+            imm: 48,
+            ..
+        }
+    )
+}
+
+fn uses_offset(inst: &Inst, imm: i64) -> bool {
+    matches!(inst, Inst::LoadStore { addressing: Addressing::Offset(offset), .. } if *offset == imm)
+}
+
+/// The immediate offset of the load or store at `offset`.
+fn load_store_offset(image: &[u8], offset: usize) -> Option<usize> {
+    match decode_at(image, offset)? {
+        Inst::LoadStore {
+            addressing: Addressing::Offset(imm),
+            ..
+        } => Some(imm as usize),
+        _ => None,
+    }
+}
+
+/// The do_execve hooker with a single root key, the key table or the key digests, and
+/// with or without allowed uids.
+fn do_execve_layout(keys: Keys, uid_table: bool) -> anyhow::Result<Layout> {
+    let key_count = if keys == Keys::Single { 1 } else { 2 };
+    let allowed_uids = match uid_table {
+        true => vec!["2000".parse()?],
+        false => vec![],
+    };
+    let do_execve = asm_to_assembly(
+        &DoExecveHook {
+            root_keys: vec!["a".repeat(48); key_count],
+            allowed_uids,
+            digest_key: (keys == Keys::Digests).then_some([0, 0]),
+            privileges: Privileges::ROOT,
+            hooker_entry: 0,
+            hookee_entry: 0x1000,
+            cred_offset: 0x100,
+            cred_layout: CredLayout::DEFAULT,
+            seccomp_offset: Some(0x200),
+        }
+        .spec()
+        .asm(),
+        0,
+    )?;
+    let word_at = |offset: usize| read_word(&do_execve.bytes, offset).unwrap();
+    let key_adr_at = position_of(&do_execve.bytes, |inst| matches!(inst, Inst::Adr { .. }))?;
+    let cred_at = position_of(&do_execve.bytes, |inst| uses_offset(inst, 0x100))?;
+    // How the keys are compared follows their ADR, the uid check follows the first load of cred.
+    let variant_words = vec![(key_adr_at + 4, word_at(key_adr_at + 4))];
+    let code_size = match keys {
+        Keys::Digests => do_execve.offset_of("key_digests")?,
+        _ => do_execve.offset_of("root_key")?,
+    };
+    let key_len_at = if keys != Keys::Single {
+        None
+    } else {
+        Some(position_of(&do_execve.bytes, is_key_len_cmp)?)
+    };
+    Ok(Layout {
+        name: "do_execve",
+        // The register saves and the -MAX_ERRNO check after the original slot.
+        signature: do_execve.bytes[4..24].to_vec(),
+        signature_at: 4,
+        original_at: do_execve.offset_of("original")?,
+        jump_back_at: code_size - 4,
+        code_size,
+        keys: Some(keys),
+        uid_table,
+        cred_at,
+        seccomp: true,
+        key_len_at,
+        key_adr_at: Some(key_adr_at),
+        variant_words,
+        uid_load_at: uid_table.then_some(cred_at + 4),
+    })
+}
+
+fn layouts() -> anyhow::Result<Vec<Layout>> {
+    Ok(vec![
+        // Those with allowed uids first, the others match their hookers too.
+        do_execve_layout(Keys::Single, true)?,
+        do_execve_layout(Keys::Table, true)?,
+        do_execve_layout(Keys::Digests, true)?,
+        do_execve_layout(Keys::Single, false)?,
+        do_execve_layout(Keys::Table, false)?,
+        do_execve_layout(Keys::Digests, false)?,
+        exit_layout(
+            &AVCDeniedHook {
+                hooker_entry: 0,
+                hookee_entry: 0x1000,
+                cred_offset: 0x100,
+                cred_layout: CredLayout::DEFAULT,
+            },
+            // The register saves and the read of current.
+            12,
+        )?,
+        exit_layout(
+            &Filldir64Hook {
+                hooker_entry: 0,
+                hookee_entry: 0x1000,
+                cred_offset: 0x100,
+                cred_layout: CredLayout::DEFAULT,
+                returns_bool: false,
+            },
+            // The register saves and the read of current as well.
+            16,
+        )?,
+        legacy_do_execve_layout()?,
+        // The register saves in their old order and the read of current.
+        exit_layout_of(
+            "avc_denied",
+            &asm_to_assembly(&legacy_avc_denied_asm(0, 0x1000, 0x100), 0)?.bytes,
+            LEGACY_AVC_DENIED_ORIGINAL_AT,
+            12,
+        )?,
+    ])
+}
+
+/// The do_execve hooker of the first sk_patch, with its root key before it.
+fn legacy_do_execve_layout() -> anyhow::Result<Layout> {
+    let do_execve = asm_to_assembly(&legacy_do_execve_asm(0, 0x1000, 0x100, 0x200), 0)?.bytes;
+    // The ADR of the key is the same word wherever the hooker is.
+    let key_adr_at = position_of(&do_execve, |inst| matches!(inst, Inst::Adr { .. }))?;
+    Ok(Layout {
+        name: "do_execve",
+        signature: do_execve[4..24].to_vec(),
+        signature_at: 4,
+        original_at: 0,
+        jump_back_at: do_execve.len() - 4,
+        code_size: do_execve.len(),
+        keys: Some(Keys::Legacy),
+        uid_table: false,
+        cred_at: position_of(&do_execve, |inst| uses_offset(inst, 0x100))?,
+        seccomp: true,
+        key_len_at: Some(position_of(&do_execve, is_key_len_cmp)?),
+        key_adr_at: None,
+        variant_words: vec![(key_adr_at, read_word(&do_execve, key_adr_at).unwrap())],
+        uid_load_at: None,
+    })
+}
+
+/// A hooker running the original instruction right before jumping back, with a cred
+/// offset of 0x100. Its first `signature_size` bytes do not depend on the parameters.
+fn exit_layout(hook: &impl Hook, signature_size: usize) -> anyhow::Result<Layout> {
+    let spec = hook.spec();
+    let assembly = asm_to_assembly(&spec.asm(), 0)?;
+    let original_at = assembly.offset_of("original")?;
+    exit_layout_of(spec.name, &assembly.bytes, original_at, signature_size)
+}
+
+fn exit_layout_of(
+    name: &'static str,
+    hooker: &[u8],
+    original_at: usize,
+    signature_size: usize,
+) -> anyhow::Result<Layout> {
+    Ok(Layout {
+        name,
+        signature: hooker[0..signature_size].to_vec(),
+        signature_at: 0,
+        original_at,
+        jump_back_at: original_at + 4,
+        code_size: hooker.len(),
+        keys: None,
+        uid_table: false,
+        cred_at: position_of(hooker, |inst| uses_offset(inst, 0x100))?,
+        seccomp: false,
+        key_len_at: None,
+        key_adr_at: None,
+        variant_words: vec![],
+        uid_load_at: None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledHook {
+    /// The hooked kernel function.
+    pub name: &'static str,
+    pub hookee_entry: usize,
+    pub hooker_entry: usize,
+    /// Bytes the hooker takes, root key and padding included.
+    pub hooker_size: usize,
+    /// Bytes right before the hooker holding its root key, with the first sk_patch.
+    pub key_before: usize,
+    /// The hookee's first instruction, kept by the hooker and moved back to the entry.
+    pub original: u32,
+    /// The do_execve root keys.
+    pub root_keys: Vec<String>,
+    /// Number of root key digests the do_execve hook holds instead of the keys.
+    pub key_digests: usize,
+    /// The uids the do_execve hook grants root to, anyone when empty.
+    pub allowed_uids: Vec<UidRange>,
+    pub cred_offset: Option<usize>,
+    pub seccomp_offset: Option<usize>,
+    /// Inconsistencies found in the hooker.
+    pub problems: Vec<String>,
+}
+
+impl InstalledHook {
+    /// Where the bytes written for the hook start, a root key before the hooker included.
+    pub fn start(&self) -> usize {
+        self.hooker_entry - self.key_before
+    }
+
+    pub fn end(&self) -> usize {
+        self.hooker_entry + self.hooker_size
+    }
+}
+
+impl fmt::Display for InstalledHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: entry 0x{:x} -> hooker 0x{:x}-0x{:x}, original instruction {}",
+            self.name,
+            self.hookee_entry,
+            self.hooker_entry,
+            self.hooker_entry + self.hooker_size,
+            decode(self.original, self.hookee_entry as u64),
+        )?;
+        if let Some(cred_offset) = self.cred_offset {
+            write!(f, ", cred 0x{:x}", cred_offset)?;
+        }
+        if let Some(seccomp_offset) = self.seccomp_offset {
+            write!(f, ", seccomp 0x{:x}", seccomp_offset)?;
+        }
+        if !self.root_keys.is_empty() {
+            write!(f, ", root keys {}", self.root_keys.join(" "))?;
+        }
+        if self.key_before > 0 {
+            write!(f, " at 0x{:x} before the hooker", self.start())?;
+        }
+        if self.key_digests > 0 {
+            write!(f, ", {} root key digest(s)", self.key_digests)?;
+        }
+        if !self.allowed_uids.is_empty() {
+            let allowed_uids: Vec<_> = self
+                .allowed_uids
+                .iter()
+                .map(|uids| uids.to_string())
+                .collect();
+            write!(f, ", allowed uids {}", allowed_uids.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Offset of the `STR WZR, [X8, #mode]` clearing the seccomp mode, between `start` and `end`.
+fn find_seccomp_store(image: &[u8], start: usize, end: usize) -> Option<usize> {
+    words_in(image, start, end)
+        .find(|&(offset, word)| {
+            matches!(
+                decode(word, offset as u64),
+                Inst::LoadStore {
+                    load: false,
+                    size: 2,
+                    rt: 31,
+                    rn: 8,
+                    addressing: Addressing::Offset(_),
+                    ..
+                }
+            )
+        })
+        .map(|(offset, _)| offset)
+}
+
+/// Checks that a hooker of `layout` starts at `hooker` and that its hookee branches to it.
+fn match_hook(image: &[u8], layout: &Layout, hooker: usize) -> Option<InstalledHook> {
+    for &(at, word) in &layout.variant_words {
+        if read_word(image, hooker + at)? != word {
+            return None;
+        }
+    }
+    if let Some(at) = layout.uid_load_at {
+        match decode_at(image, hooker + at)? {
+            Inst::LoadStore {
+                load: true,
+                size: 2,
+                rt: 10,
+                rn: 10,
+                ..
+            } => (),
+            _ => return None,
+        }
+    }
+    let (code_size, jump_back_at) = match layout.key_adr_at {
+        Some(at) => match decode_at(image, hooker + at)? {
+            Inst::Adr { target, .. } => {
+                let code_size = (target as usize)
+                    .checked_sub(hooker)
+                    .filter(|&size| size > layout.original_at + 4 && size % 4 == 0)?;
+                (code_size, code_size - 4)
+            }
+            _ => return None,
+        },
+        None => (layout.code_size, layout.jump_back_at),
+    };
+    let hookee = match decode_at(image, hooker + jump_back_at)? {
+        Inst::Branch {
+            link: false,
+            target,
+        } => (target as usize).checked_sub(4)?,
+        _ => return None,
+    };
+    match decode_at(image, hookee)? {
+        Inst::Branch {
+            link: false,
+            target,
+        } if target as usize == hooker => (),
+        _ => {
+            log::warn!(
+                "{} hooker at 0x{:x} is not branched to from 0x{:x}",
+                layout.name,
+                hooker,
+                hookee
+            );
+            return None;
+        }
+    }
+    let mut problems = vec![];
+    let kept = read_word(image, hooker + layout.original_at)?;
+    let original = relocate(kept, hooker + layout.original_at, hookee).unwrap_or_else(|e| {
+        problems.push(e.to_string());
+        kept
+    });
+    if let Inst::Branch { link: false, .. } = decode(original, hookee as u64) {
+        problems.push("the kept first instruction is a B, the image may be patched twice".into());
+    }
+
+    let (hooker_size, root_keys, key_digests, allowed_uids) = if let Some(keys) = layout.keys {
+        let keys_start = hooker + code_size;
+        let mut key_start = keys_start;
+        let mut root_keys = vec![];
+        let mut key_digests = 0;
+        if keys == Keys::Digests {
+            let count = read_word(image, keys_start)? as usize;
+            if count == 0 || count > MAX_ROOT_KEYS || read_word(image, keys_start + 4)? != 0 {
+                log::warn!(
+                    "do_execve hooker at 0x{:x} has a bad number of key digests",
+                    hooker
+                );
+                return None;
+            }
+            key_digests = count;
+            // The count, the SipHash state and two words per key.
+            key_start += 8 + 32 + 16 * count;
+        } else if keys == Keys::Legacy {
+            let key_at = hooker.checked_sub(LEGACY_ROOT_KEY_LEN)?;
+            let root_key = String::from_utf8_lossy(&image[key_at..hooker]);
+            if !root_key.chars().all(|c| c.is_ascii_alphanumeric()) {
+                problems.push("the root key is not 48 letters and digits".into());
+            }
+            root_keys.push(root_key.into_owned());
+        } else {
+            loop {
+                let key_len = image.get(key_start..)?.iter().position(|&b| b == 0)?;
+                if keys == Keys::Table && key_len == 0 {
+                    key_start += 1;
+                    break;
+                }
+                let root_key = String::from_utf8_lossy(&image[key_start..key_start + key_len]);
+                if key_len != 48 || !root_key.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    problems.push(match keys == Keys::Table {
+                        true => format!(
+                            "the root key {} is not 48 letters and digits",
+                            root_keys.len()
+                        ),
+                        false => "the root key is not 48 letters and digits".into(),
+                    });
+                }
+                root_keys.push(root_key.into_owned());
+                key_start += key_len + 1;
+                if keys == Keys::Single {
+                    break;
+                }
+                if root_keys.len() > MAX_ROOT_KEYS {
+                    log::warn!(
+                        "do_execve hooker at 0x{:x} has no end of its key table",
+                        hooker
+                    );
+                    return None;
+                }
+            }
+        }
+        if let Some(Inst::AddSubImm { imm, .. }) = layout
+            .key_len_at
+            .and_then(|at| decode_at(image, hooker + at))
+        {
+            let key_len = root_keys[0].len();
+            if imm as usize != key_len {
+                problems.push(format!(
+                    "compares 0x{:x} key characters but the key has 0x{:x}",
+                    imm, key_len
+                ));
+            }
+        }
+        let mut end = key_start.div_ceil(4) * 4;
+        let mut allowed_uids = vec![];
+        if layout.uid_table {
+            loop {
+                let kind = read_word(image, end)?;
+                end += 4;
+                if kind == 0 {
+                    break;
+                }
+                let (first, last) = (read_word(image, end)?, read_word(image, end + 4)?);
+                end += 8;
+                if kind > 2 || first > last {
+                    problems.push(format!(
+                        "allowed uid entry {} is invalid",
+                        allowed_uids.len()
+                    ));
+                }
+                allowed_uids.push(UidRange {
+                    first,
+                    last,
+                    per_user: kind == 2,
+                });
+                if allowed_uids.len() > MAX_ALLOWED_UIDS {
+                    log::warn!(
+                        "do_execve hooker at 0x{:x} has no end of its uid table",
+                        hooker
+                    );
+                    return None;
+                }
+            }
+        }
+        (end - hooker, root_keys, key_digests, allowed_uids)
+    } else {
+        (code_size, vec![], 0, vec![])
+    };
+
+    let cred_offset = load_store_offset(image, hooker + layout.cred_at);
+    if cred_offset.is_none() {
+        problems.push("no load of cred from task_struct".into());
+    }
+    let seccomp_at = match layout.seccomp {
+        true => find_seccomp_store(image, hooker + layout.cred_at, hooker + jump_back_at),
+        false => None,
+    };
+    let seccomp_offset = seccomp_at.and_then(|at| {
+        let mode = load_store_offset(image, at);
+        let filter = load_store_offset(image, at + 4);
+        if mode.is_none() || filter != mode.map(|mode| mode + 8) {
+            problems.push("seccomp mode and filter are not cleared together".into());
+        }
+        mode
+    });
+
+    Some(InstalledHook {
+        name: layout.name,
+        hookee_entry: hookee,
+        hooker_entry: hooker,
+        hooker_size,
+        key_before: match layout.keys {
+            Some(Keys::Legacy) => LEGACY_ROOT_KEY_LEN,
+            _ => 0,
+        },
+        original,
+        root_keys,
+        key_digests,
+        allowed_uids,
+        cred_offset,
+        seccomp_offset,
+        problems,
+    })
+}
+
+/// Finds the hooks installed in `image`, in the order they are patched.
+pub fn find_installed_hooks(image: &[u8]) -> anyhow::Result<Vec<InstalledHook>> {
+    let mut hooks = vec![];
+    for layout in layouts()? {
+        for offset in find_bytes(image, &layout.signature) {
+            let hooker = match offset.checked_sub(layout.signature_at) {
+                Some(hooker) if hooker % 4 == 0 => hooker,
+                _ => continue,
+            };
+            if hooks
+                .iter()
+                .any(|hook: &InstalledHook| hook.hooker_entry == hooker)
+            {
+                continue;
+            }
+            if let Some(hook) = match_hook(image, &layout, hooker) {
+                hooks.push(hook);
+            }
+        }
+    }
+    Ok(hooks)
+}
+
+/// Checks the hooks found in an image against each other and returns every problem,
+/// those of the single hooks included.
+pub fn verify_hooks(hooks: &[InstalledHook]) -> Vec<String> {
+    let mut problems = vec![];
+    for name in ["do_execve", "avc_denied", "filldir64"] {
+        match hooks.iter().filter(|hook| hook.name == name).count() {
+            // The filldir64 hook is optional.
+            0 if name == "filldir64" => (),
+            0 => problems.push(format!("no {} hook found", name)),
+            1 => (),
+            count => problems.push(format!("{} is hooked {} times", name, count)),
+        }
+    }
+    for hook in hooks {
+        for problem in &hook.problems {
+            problems.push(format!("{} hook: {}", hook.name, problem));
+        }
+    }
+    let mut cred_offsets: Vec<_> = hooks.iter().filter_map(|hook| hook.cred_offset).collect();
+    cred_offsets.sort_unstable();
+    cred_offsets.dedup();
+    if cred_offsets.len() > 1 {
+        problems.push(format!(
+            "the hooks disagree on the cred offset: {:x?}",
+            cred_offsets
+        ));
+    }
+    for (i, hook) in hooks.iter().enumerate() {
+        for other in &hooks[i + 1..] {
+            if hook.start() < other.end() && other.start() < hook.end() {
+                problems.push(format!("{} and {} hookers overlap", hook.name, other.name));
+            }
+        }
+    }
+    problems
+}
+
+/// The do_execve hooker of the first sk_patch, which kept the root key right before it.
+pub fn legacy_do_execve_asm(
+    hooker_entry: usize,
+    hookee_entry: usize,
+    cred_offset: usize,
+    seccomp_offset: usize,
+) -> String {
+    let jump_back_addr = hookee_entry + 4;
+    let jump_back_relative_offset = jump_back_addr as i64 - (hooker_entry as i64 + 39 * 4);
+    aarch64! {
+        "MOV X0, X0";                                   // Reserved for hookee's first instruction
+        "STP X7, X8, [sp, #-16]!";
+        "STP X9, X10, [sp, #-16]!";
+        "STP X11, X12, [sp, #-16]!";
+        "MOV X7, 0xFFFFFFFFFFFFF001";                   // X7 = (unsigned long)(-MAX_ERRNO)
+        "CMP X1, X7";                                   // compare X1 and X7
+        "BCS #120";                                     // if X1 > X7 goto end
+        "LDR X7, [X1]";                                 // X7 = *X1
+        "CBZ X7, #112";                                 // if X7 == 0 goto end
+        "ADR X8, #-84";                                 // X8 = &root_key
+        "MOV X9, #0";                                   // X9 = 0
+        "LDRB W10, [X7, X9]";                           // W10 = *(X7 + X9)	#label1
+        "CBZ W10, #96";                                 // if W10 == 0 goto end
+        "LDRB W11, [X8, X9]";                           // W11 = *(X8 + X9)
+        "CBZ W11, #88";                                 // if W11 == 0 goto end
+        "CMP W10, W11";                                 // compare W10 and W11
+        "B.NE #80";                                     // if W10 != W11 goto end
+        "ADD X9, X9, 1";                                // X9 += 1
+        "CMP X9, #{}", LEGACY_ROOT_KEY_LEN;             // compare X9 and strlen(root_key)
+        "BLT #-32";                                     // if X9 < strlen(root_key) goto #label1
+        "MRS X8, SP_EL0";                               // X8 = (struct task_struct *) current_thread_info()
+        "LDR X10, [X8, #{}]", cred_offset;              // X10 = X8->cred
+        "MOV X7, #4";                                   // X7 = 4
+        "MOV W9, WZR";                                  // W9 = 0
+        "STR W9, [X10, X7]";                            // *(X10 + X7) = W9	#label2
+        "ADD X7, X7, 4";                                // X7 += 4
+        "CMP X7, #40";                                  // compare X7 and 40
+        "BLT #-12";                                     // if X7 < 40 goto label2
+        "MOV W9, 0xFFFFFFFF";                           // W9 = 0xFFFFFFFF
+        "CMP X7, #80";                                  // compare X7 and 80
+        "BLT #-24";                                     // if X7 < 80 goto label2
+        "LDXR W10, [X8]";                               // W10 = *X8
+        "BIC W10, W10,#0xFFF";                          // X10 = X10 & ~(0xFFF)
+        "STXR W11, W10, [X8]";                          // *X8 = W10
+        "STR WZR, [X8, #{}]", seccomp_offset;           // X8->seccomp.mode = 0
+        "STR XZR, [X8, #{}]", seccomp_offset + 8;       // X8->seccomp.filter = 0
+        "LDP X11, X12, [sp], #16";                      // end
+        "LDP X9, X10, [sp], #16";
+        "LDP X7, X8, [sp], #16";
+        "B #{}", jump_back_relative_offset;
+    }
+}
+
+/// The avc_denied hooker of the first sk_patch, saving X9 and X10 before X7 and X8.
+pub fn legacy_avc_denied_asm(
+    hooker_entry: usize,
+    hookee_entry: usize,
+    cred_offset: usize,
+) -> String {
+    let jump_back_addr = hookee_entry + 4;
+    let jump_back_relative_offset = jump_back_addr as i64 - (hooker_entry as i64 + 28 * 4);
+    aarch64! {
+        "STP X9, X10, [sp, #-16]!";
+        "STP X7, X8, [sp, #-16]!";
+        "MRS X7, SP_EL0";			            // X7 = (struct task_struct *) current_thread_info()
+        "LDR X7, [X7, #{}]", cred_offset;       // X7 = X7->cred
+        "CBZ X7, #84";			                // if X7 == 0 goto end1
+        "MOV X8, #4";				            // X8 = 4
+        "MOV W9, WZR";			                // W9 = 0
+        "LDR W10, [X7, X8]";		            // W10 = *(X7 + X8)	#label1
+        "CMP W10, W9";			                // compare W10 and W9
+        "B.NE #64"; 				            // if W10 != W9 goto end1
+        "ADD X8, X8, 4";			            // X8 += 4
+        "CMP X8, #36";			                // compare X8 and 36
+        "BLT #-20";				                // if X8 < 36 goto label1
+        "ADD X8, X8, 12";			            // X8 += 12
+        "MOV X9, 0x3FFFFFFFFF";	                // X9 = 0x3FFFFFFFFF
+        "LDR X10, [X7, X8]";		            // X10 = *(X7 + X8)	#label2
+        "ADD X8, X8, 8";			            // X8 += 8
+        "CMP X10, X9";			                // compare X10 and X9
+        "B.CC #28";				                // if X10 < X9 goto end1
+        "CMP X8, #72";			                // compare X8 and 72
+        "BLT #-20";				                // if X8 < 72 goto label2
+        "LDP X9, X10, [sp], #16";	            // end2
+        "LDP X7, X8, [sp], #16";
+        "MOV W0, WZR";
+        "RET";
+        "LDP X9, X10, [sp], #16";               // end1
+        "LDP X7, X8, [sp], #16";
+        "MOV X0, X0"; 			                // Reserved for hookee's first instruction
+        "B #{}", jump_back_relative_offset;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        asm_helper::asm_to_bytes,
+        kernel_version::KernelVersion,
+        patcher::Patcher,
+        test_util::{do_execve_hook, test_kernel, TempPath},
+    };
+
+    /// Places an assembled hooker and the branch to it into `image`.
+    fn install(image: &mut [u8], hook: &impl Hook) -> usize {
+        let spec = hook.spec();
+        let assembly = asm_to_assembly(&spec.asm(), spec.hooker_entry).unwrap();
+        let original_at = assembly.offset_of("original").unwrap();
+        let (hookee, hooker) = (spec.hookee_entry, spec.hooker_entry);
+        let mut bytes = assembly.bytes;
+        bytes[original_at..original_at + 4].copy_from_slice(&image[hookee..hookee + 4]);
+        image[hooker..hooker + bytes.len()].copy_from_slice(&bytes);
+        let branch = asm_to_bytes(&aarch64!("B #{}", hooker - hookee)).unwrap();
+        image[hookee..hookee + 4].copy_from_slice(&branch);
+        hooker + bytes.len()
+    }
+
+    /// Places the hookers of the first sk_patch and the root key before them into `image`.
+    fn install_legacy(
+        image: &mut [u8],
+        root_key: &str,
+        start: usize,
+        do_execve_entry: usize,
+        avc_denied_entry: usize,
+    ) -> usize {
+        let mut offset = start;
+        image[offset..offset + root_key.len()].copy_from_slice(root_key.as_bytes());
+        offset += root_key.len();
+        let hookers = [
+            (
+                do_execve_entry,
+                legacy_do_execve_asm(offset, do_execve_entry, 0x618, 0x800),
+                0,
+            ),
+            (
+                avc_denied_entry,
+                legacy_avc_denied_asm(offset + 160, avc_denied_entry, 0x618),
+                LEGACY_AVC_DENIED_ORIGINAL_AT,
+            ),
+        ];
+        for (hookee, asm, original_at) in hookers {
+            let mut bytes = asm_to_assembly(&asm, 0).unwrap().bytes;
+            bytes[original_at..original_at + 4].copy_from_slice(&image[hookee..hookee + 4]);
+            image[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            let branch = asm_to_bytes(&aarch64!("B #{}", offset - hookee)).unwrap();
+            image[hookee..hookee + 4].copy_from_slice(&branch);
+            offset += bytes.len();
+        }
+        offset
+    }
+
+    /// An image with the do_execve prologue at 0x100 and the avc_denied one at 0x200.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x2000];
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_verify_hooks() {
+        let mut image = image();
+        let do_execve = do_execve_hook();
+        let next_offset = install(&mut image, &do_execve);
+        let avc_denied = AVCDeniedHook {
+            hooker_entry: next_offset,
+            hookee_entry: 0x200,
+            cred_offset: 0x610,
+            cred_layout: CredLayout::DEFAULT,
+        };
+        install(&mut image, &avc_denied);
+
+        let hooks = find_installed_hooks(&image).unwrap();
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].root_keys, do_execve.root_keys);
+        assert_eq!(hooks[0].cred_offset, Some(0x618));
+        assert_eq!(hooks[0].seccomp_offset, Some(0x800));
+        assert_eq!(hooks[1].cred_offset, Some(0x610));
+        assert_eq!(hooks[1].seccomp_offset, None);
+        assert_eq!(
+            verify_hooks(&hooks),
+            vec!["the hooks disagree on the cred offset: [610, 618]"]
+        );
+
+        // A shorter key than the compared length.
+        image[0x1000 + hooks[0].hooker_size - 8] = 0;
+        let hooks = find_installed_hooks(&image).unwrap();
+        let problems = verify_hooks(&hooks);
+        assert!(problems[0].starts_with("do_execve hook: the root key is not"));
+        assert!(problems[1].starts_with("do_execve hook: compares 0x30 key characters"));
+
+        assert_eq!(
+            verify_hooks(&hooks[1..])[0],
+            "no do_execve hook found".to_string()
+        );
+    }
+
+    #[test]
+    fn test_find_legacy_hooks() {
+        let mut image = image();
+        let root_key = "Lg5".repeat(16);
+        let end = install_legacy(&mut image, &root_key, 0x1000, 0x100, 0x200);
+
+        let hooks = find_installed_hooks(&image).unwrap();
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].name, "do_execve");
+        assert_eq!(hooks[0].start(), 0x1000);
+        assert_eq!(hooks[0].hooker_entry, 0x1030);
+        assert_eq!(hooks[0].hooker_size, 160);
+        assert_eq!(hooks[0].root_keys, vec![root_key]);
+        assert_eq!(hooks[0].original, 0xD10103FF);
+        assert_eq!(hooks[0].seccomp_offset, Some(0x800));
+        assert_eq!(hooks[1].name, "avc_denied");
+        assert_eq!((hooks[1].start(), hooks[1].end()), (0x10D0, end));
+        assert_eq!(hooks[1].original, 0xA9BF7BFD);
+        assert_eq!(hooks[1].cred_offset, Some(0x618));
+        assert!(hooks.iter().all(|hook| hook.problems.is_empty()));
+    }
+
+    #[test]
+    fn test_unpatch_legacy() {
+        let image_path = TempPath::new("unpatch_legacy");
+        let mut image = test_kernel(0x2000);
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        let mut patched = image.clone();
+        install_legacy(&mut patched, &"e".repeat(48), 0x1000, 0x100, 0x200);
+        fs::write(&image_path, &patched).unwrap();
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let hooks = patcher.unpatch().unwrap();
+        assert_eq!(hooks.len(), 2);
+        patcher.apply_patches().unwrap();
+        // The root key before the do_execve hooker is zeroed as well.
+        assert_eq!(fs::read(&image_path).unwrap(), image);
+    }
+
+    #[test]
+    fn test_verify_legacy_hooks() {
+        let mut image = image();
+        let root_key = "Vf7".repeat(16);
+        install_legacy(&mut image, &root_key, 0x1000, 0x100, 0x200);
+
+        let hooks = find_installed_hooks(&image).unwrap();
+        assert!(verify_hooks(&hooks).is_empty());
+        assert!(hooks[0].to_string().ends_with(&format!(
+            "root keys {} at 0x1000 before the hooker",
+            root_key
+        )));
+
+        image[0x1000] = b'-';
+        let hooks = find_installed_hooks(&image).unwrap();
+        assert_eq!(
+            verify_hooks(&hooks),
+            vec!["do_execve hook: the root key is not 48 letters and digits"]
+        );
+    }
+
+    #[test]
+    fn test_find_do_execve_variants() {
+        let variants = [
+            DoExecveHook {
+                root_keys: vec!["d".repeat(48), "e".repeat(48), "f".repeat(48)],
+                ..do_execve_hook()
+            },
+            DoExecveHook {
+                allowed_uids: vec!["2000".parse().unwrap(), "u*_a123".parse().unwrap()],
+                ..do_execve_hook()
+            },
+            DoExecveHook {
+                root_keys: vec!["h".repeat(48), "i".repeat(48)],
+                allowed_uids: vec!["2000".parse().unwrap()],
+                digest_key: Some([0x0706050403020100, 0x0f0e0d0c0b0a0908]),
+                ..do_execve_hook()
+            },
+            DoExecveHook {
+                privileges: Privileges {
+                    uid: 2000,
+                    gid: 2000,
+                    capabilities: 0x1C0,
+                    thread_flags: 0,
+                },
+                ..do_execve_hook()
+            },
+            DoExecveHook {
+                seccomp_offset: None,
+                ..do_execve_hook()
+            },
+        ];
+        for do_execve in variants {
+            let mut image = image();
+            let end = install(&mut image, &do_execve);
+
+            let hooks = find_installed_hooks(&image).unwrap();
+            assert_eq!(hooks.len(), 1, "{:?}", do_execve);
+            let hook = &hooks[0];
+            if do_execve.digest_key.is_some() {
+                assert!(find_bytes(&image, do_execve.root_keys[0].as_bytes()).is_empty());
+                assert!(hook.root_keys.is_empty());
+                assert_eq!(hook.key_digests, do_execve.root_keys.len());
+            } else {
+                assert_eq!(hook.root_keys, do_execve.root_keys);
+            }
+            assert_eq!(hook.allowed_uids, do_execve.allowed_uids);
+            assert_eq!(hook.hooker_size, end - 0x1000);
+            assert_eq!(hook.cred_offset, Some(0x618));
+            assert_eq!(hook.seccomp_offset, do_execve.seccomp_offset);
+            assert_eq!(verify_hooks(&hooks), vec!["no avc_denied hook found"]);
+        }
+    }
+
+    #[test]
+    fn test_without_seccomp() {
+        let with_seccomp = do_execve_hook();
+        let end = install(&mut image(), &with_seccomp);
+        let without_seccomp = DoExecveHook {
+            seccomp_offset: None,
+            ..with_seccomp
+        };
+        // Neither the thread flags nor seccomp are touched.
+        assert_eq!(end - install(&mut image(), &without_seccomp), 5 * 4);
+    }
+
+    #[test]
+    fn test_find_filldir64_hook() {
+        let mut image = vec![0u8; 0x2000];
+        image[0x300..0x304].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        let filldir64 = Filldir64Hook {
+            hooker_entry: 0x1800,
+            hookee_entry: 0x300,
+            cred_offset: 0x618,
+            cred_layout: CredLayout::DEFAULT,
+            returns_bool: true,
+        };
+        let end = install(&mut image, &filldir64);
+
+        let hooks = find_installed_hooks(&image).unwrap();
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(hooks[0].name, "filldir64");
+        assert_eq!(hooks[0].hooker_size, end - 0x1800);
+        assert_eq!(hooks[0].original, 0xA9BF7BFD);
+        assert_eq!(hooks[0].cred_offset, Some(0x618));
+        let problems = verify_hooks(&hooks);
+        assert_eq!(problems.len(), 2);
+        assert!(!problems.iter().any(|problem| problem.contains("filldir64")));
+    }
+
+    #[test]
+    fn test_find_other_cred_layouts() {
+        for cred_layout in [
+            CredLayout::new(true, Some(KernelVersion::new(5, 10, 0))),
+            CredLayout::new(false, Some(KernelVersion::new(6, 8, 0))),
+        ] {
+            let mut image = image();
+            let do_execve = DoExecveHook {
+                allowed_uids: vec!["2000".parse().unwrap()],
+                cred_layout,
+                ..do_execve_hook()
+            };
+            let next_offset = install(&mut image, &do_execve);
+            let avc_denied = AVCDeniedHook {
+                hooker_entry: next_offset,
+                hookee_entry: 0x200,
+                cred_offset: 0x618,
+                cred_layout,
+            };
+            install(&mut image, &avc_denied);
+
+            let hooks = find_installed_hooks(&image).unwrap();
+            assert_eq!(hooks.len(), 2, "{}", cred_layout);
+            assert_eq!(hooks[0].root_keys, do_execve.root_keys);
+            assert_eq!(hooks[0].allowed_uids, do_execve.allowed_uids);
+            assert_eq!(hooks[1].cred_offset, Some(0x618));
+            assert!(verify_hooks(&hooks).is_empty());
+        }
+    }
+}
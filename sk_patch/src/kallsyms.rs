@@ -0,0 +1,608 @@
+//! Recovers the symbol table the kernel embeds for `/proc/kallsyms`.
+//!
+//! `scripts/kallsyms.c` emits, in this order:
+//!
+//! ```text
+//! [kallsyms_addresses | kallsyms_offsets + kallsyms_relative_base]   up to 6.3
+//! kallsyms_num_syms
+//! kallsyms_names          length prefixed lists of token indexes
+//! kallsyms_markers        offset into names of every 256th symbol
+//! [kallsyms_seqs_of_names]                                          6.2 and 6.3
+//! kallsyms_token_table    256 NUL terminated strings
+//! kallsyms_token_index    offset of every token in the table
+//! [kallsyms_addresses | kallsyms_offsets + kallsyms_relative_base]   6.4 onwards
+//! [kallsyms_seqs_of_names]                                          6.4 onwards
+//! ```
+//!
+//! The token table is found first, since single character tokens for the digits are
+//! always stored at their own character code, and everything else is walked from there.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::finder::find_bytes;
+
+const DIGIT_TOKENS: &[u8] = b"0\x001\x002\x003\x004\x005\x006\x007\x008\x009\x00";
+const TOKEN_COUNT: usize = 256;
+const SYMBOLS_PER_MARKER: usize = 256;
+/// How far before the markers `kallsyms_num_syms` is searched for.
+const MAX_NAMES_SIZE: usize = 0x1000000;
+/// Bytes `kallsyms_seqs_of_names` takes per symbol.
+const SEQ_SIZE: usize = 3;
+/// How far before the token table the markers are searched for past
+/// `kallsyms_seqs_of_names`, a million symbols.
+const MAX_SEQS_SIZE: usize = SEQ_SIZE * 0x100000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// The type letter shown by `/proc/kallsyms`, e.g. `T` for global text.
+    pub kind: char,
+    pub address: u64,
+}
+
+#[derive(Debug)]
+pub struct Kallsyms {
+    pub symbols: Vec<Symbol>,
+    /// `kallsyms_relative_base`, for kernels built with `CONFIG_KALLSYMS_BASE_RELATIVE`.
+    pub relative_base: Option<u64>,
+    /// Address the first byte of the Image is loaded at.
+    pub text_base: u64,
+    /// Where the symbols come from, `kallsyms` or the `.symtab` of a vmlinux.
+    pub source: &'static str,
+    by_name: HashMap<String, usize>,
+}
+
+impl Kallsyms {
+    /// Locates and decodes the kallsyms tables of a raw Image.
+    pub fn parse(image: &[u8]) -> anyhow::Result<Self> {
+        let mut last_error = anyhow!("kallsyms token table not found");
+        for digits in find_bytes(image, DIGIT_TOKENS) {
+            match Self::parse_from_digits(image, digits) {
+                Ok(kallsyms) => return Ok(kallsyms),
+                Err(e) => {
+                    log::debug!("kallsyms candidate at 0x{:x}: {:?}", digits, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn parse_from_digits(image: &[u8], digits: usize) -> anyhow::Result<Self> {
+        let tokens = TokenTable::locate(image, digits)?;
+        let markers = Markers::locate(image, tokens.start)?;
+        let names = Names::locate(image, &tokens, &markers)?;
+        let (addresses, relative_base) = locate_addresses(image, &names, &tokens)?;
+
+        let mut symbols = Vec::with_capacity(names.entries.len());
+        for (entry, address) in names.entries.iter().zip(addresses) {
+            let mut chars = entry.chars();
+            let kind = chars.next().ok_or_else(|| anyhow!("empty kallsyms name"))?;
+            symbols.push(Symbol {
+                name: chars.as_str().to_string(),
+                kind,
+                address,
+            });
+        }
+        let text_base = ["_text", "_head", "_stext"]
+            .iter()
+            .find_map(|name| symbols.iter().find(|symbol| symbol.name == *name))
+            .ok_or_else(|| anyhow!("kallsyms has no _text symbol"))?
+            .address;
+        let mut kallsyms = Self::from_symbols(symbols, text_base, "kallsyms");
+        kallsyms.relative_base = relative_base;
+        Ok(kallsyms)
+    }
+
+    /// Builds the table from symbols read elsewhere, e.g. the `.symtab` of a vmlinux.
+    pub fn from_symbols(symbols: Vec<Symbol>, text_base: u64, source: &'static str) -> Self {
+        let mut by_name = HashMap::new();
+        for (idx, symbol) in symbols.iter().enumerate() {
+            by_name.entry(symbol.name.clone()).or_insert(idx);
+        }
+        Kallsyms {
+            symbols,
+            relative_base: None,
+            text_base,
+            source,
+            by_name,
+        }
+    }
+
+    /// Looks a symbol up by name, also accepting compiler generated suffixes such as
+    /// `.isra.0` or `.constprop.0`.
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        if let Some(&idx) = self.by_name.get(name) {
+            return Some(&self.symbols[idx]);
+        }
+        let prefix = format!("{}.", name);
+        self.symbols.iter().find(|s| s.name.starts_with(&prefix))
+    }
+
+    /// File offset of a symbol in the Image.
+    pub fn file_offset(&self, name: &str) -> Option<usize> {
+        self.to_file_offset(self.lookup(name)?.address)
+    }
+
+    /// File offset of a kernel virtual address, if it is not below the Image.
+    pub fn to_file_offset(&self, address: u64) -> Option<usize> {
+        address
+            .checked_sub(self.text_base)
+            .map(|offset| offset as usize)
+    }
+
+    /// Kernel virtual address of a file offset.
+    pub fn to_address(&self, offset: usize) -> u64 {
+        self.text_base + offset as u64
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    let bytes = image.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Option<u32> {
+    let bytes = image.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Option<u64> {
+    let bytes = image.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+fn is_kernel_address(address: u64) -> bool {
+    address >> 48 == 0xFFFF
+}
+
+struct TokenTable {
+    start: usize,
+    /// End of `kallsyms_token_index`.
+    end: usize,
+    tokens: Vec<Vec<u8>>,
+}
+
+impl TokenTable {
+    fn locate(image: &[u8], digits: usize) -> anyhow::Result<Self> {
+        // Walk back from token '0' to token 0.
+        let mut start = digits;
+        for _ in 0..b'0' {
+            if start < 2 || image[start - 1] != 0 {
+                return Err(anyhow!("malformed token table before 0x{:x}", digits));
+            }
+            let previous = image[..start - 1]
+                .iter()
+                .rposition(|&b| b == 0)
+                .map_or(0, |nul| nul + 1);
+            if previous == start - 1 {
+                return Err(anyhow!("empty token before 0x{:x}", start));
+            }
+            start = previous;
+        }
+
+        let mut tokens = Vec::with_capacity(TOKEN_COUNT);
+        let mut offsets = Vec::with_capacity(TOKEN_COUNT);
+        let mut cursor = start;
+        for _ in 0..TOKEN_COUNT {
+            let len = image[cursor..]
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| anyhow!("unterminated token at 0x{:x}", cursor))?;
+            if len == 0 {
+                return Err(anyhow!("empty token at 0x{:x}", cursor));
+            }
+            offsets.push(cursor - start);
+            tokens.push(image[cursor..cursor + len].to_vec());
+            cursor += len + 1;
+        }
+
+        for align in [2, 4, 8] {
+            let index = align_up(cursor, align);
+            let matches = offsets
+                .iter()
+                .enumerate()
+                .all(|(i, &offset)| read_u16(image, index + i * 2) == Some(offset as u16));
+            if matches {
+                return Ok(TokenTable {
+                    start,
+                    end: index + TOKEN_COUNT * 2,
+                    tokens,
+                });
+            }
+        }
+        Err(anyhow!(
+            "kallsyms_token_index not found after 0x{:x}",
+            cursor
+        ))
+    }
+}
+
+struct Markers {
+    start: usize,
+    values: Vec<u64>,
+}
+
+impl Markers {
+    /// Reads the strictly increasing markers ending right before the token table, or
+    /// before the `kallsyms_seqs_of_names` of 6.2 and 6.3, trying both the `.long` and
+    /// the older pointer sized layout.
+    fn locate(image: &[u8], token_table: usize) -> anyhow::Result<Vec<Self>> {
+        let mut found = vec![];
+        for width in [8, 4] {
+            found.extend(Self::ending_at(image, token_table, width));
+        }
+        // 6.2 and 6.3: 3 bytes per symbol sit between them, padded to 8.
+        for width in [8, 4] {
+            let mut seqs = token_table - token_table % 8;
+            while seqs >= 8 && token_table - seqs < MAX_SEQS_SIZE {
+                seqs -= 8;
+                let size = token_table - seqs;
+                let Some(markers) = Self::ending_at(image, seqs, width) else {
+                    continue;
+                };
+                // The symbols the seqs hold must need as many markers as were read.
+                let (min, max) = ((size - 7).div_ceil(SEQ_SIZE), size / SEQ_SIZE);
+                let count = markers.values.len();
+                if (count - 1) * SYMBOLS_PER_MARKER < max && count * SYMBOLS_PER_MARKER >= min {
+                    found.push(markers);
+                    break;
+                }
+            }
+        }
+        if found.is_empty() {
+            Err(anyhow!(
+                "kallsyms_markers not found before 0x{:x}",
+                token_table
+            ))
+        } else {
+            Ok(found)
+        }
+    }
+
+    /// Reads the markers of `width` bytes ending before the 8 byte aligned `next` table.
+    fn ending_at(image: &[u8], next: usize, width: usize) -> Option<Self> {
+        let mut end = next - next % width;
+        // Skip alignment padding.
+        while end >= width && end + 8 > next && read(image, end - width, width) == 0 {
+            end -= width;
+        }
+        let mut values = vec![];
+        let mut cursor = end;
+        while cursor >= width {
+            cursor -= width;
+            let value = read(image, cursor, width);
+            if value > u32::MAX as u64 || values.last().is_some_and(|&last| value >= last) {
+                break;
+            }
+            values.push(value);
+            if value == 0 {
+                break;
+            }
+        }
+        if values.len() < 2 || values.last() != Some(&0) {
+            return None;
+        }
+        values.reverse();
+        Some(Markers {
+            start: end - values.len() * width,
+            values,
+        })
+    }
+}
+
+fn read(image: &[u8], offset: usize, width: usize) -> u64 {
+    match width {
+        8 => read_u64(image, offset).unwrap_or(u64::MAX),
+        _ => read_u32(image, offset).map_or(u64::MAX, |v| v as u64),
+    }
+}
+
+struct Names {
+    /// Offset of `kallsyms_num_syms`.
+    num_syms_offset: usize,
+    entries: Vec<String>,
+}
+
+impl Names {
+    fn locate(image: &[u8], tokens: &TokenTable, markers: &[Markers]) -> anyhow::Result<Self> {
+        for markers in markers {
+            let max = markers.values.len() * SYMBOLS_PER_MARKER;
+            let min = max - SYMBOLS_PER_MARKER + 1;
+            let lowest = markers.start.saturating_sub(MAX_NAMES_SIZE);
+            let mut cursor = markers.start & !3;
+            while cursor >= lowest + 4 {
+                cursor -= 4;
+                let num_syms = read_u32(image, cursor).unwrap_or(0) as usize;
+                if num_syms < min || num_syms > max {
+                    continue;
+                }
+                for names_start in [cursor + 4, cursor + 8] {
+                    if let Some(entries) =
+                        decode_names(image, names_start, num_syms, markers, tokens)
+                    {
+                        return Ok(Names {
+                            num_syms_offset: cursor,
+                            entries,
+                        });
+                    }
+                }
+            }
+        }
+        Err(anyhow!("kallsyms_num_syms not found"))
+    }
+}
+
+fn decode_names(
+    image: &[u8],
+    start: usize,
+    num_syms: usize,
+    markers: &Markers,
+    tokens: &TokenTable,
+) -> Option<Vec<String>> {
+    // Cheap check first: the second marker must land on a name boundary.
+    let mut cursor = start;
+    for _ in 0..SYMBOLS_PER_MARKER.min(num_syms) {
+        cursor = skip_name(image, cursor)?;
+    }
+    if num_syms > SYMBOLS_PER_MARKER && cursor - start != markers.values[1] as usize {
+        return None;
+    }
+
+    let mut entries = Vec::with_capacity(num_syms);
+    let mut cursor = start;
+    for idx in 0..num_syms {
+        if idx % SYMBOLS_PER_MARKER == 0
+            && markers.values.get(idx / SYMBOLS_PER_MARKER) != Some(&((cursor - start) as u64))
+        {
+            return None;
+        }
+        let (len, data) = name_len(image, cursor)?;
+        let mut name = vec![];
+        for &token in image.get(data..data + len)? {
+            name.extend_from_slice(&tokens.tokens[token as usize]);
+        }
+        entries.push(String::from_utf8_lossy(&name).into_owned());
+        cursor = data + len;
+    }
+    if cursor > markers.start {
+        return None;
+    }
+    Some(entries)
+}
+
+/// Returns the number of token indexes of the name at `offset` and where they start.
+/// Lengths above 0x7F take two bytes since 6.1.
+fn name_len(image: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let first = *image.get(offset)? as usize;
+    if first & 0x80 == 0 {
+        return Some((first, offset + 1));
+    }
+    let second = *image.get(offset + 1)? as usize;
+    Some(((first & 0x7F) | (second << 7), offset + 2))
+}
+
+fn skip_name(image: &[u8], offset: usize) -> Option<usize> {
+    let (len, data) = name_len(image, offset)?;
+    if len == 0 || data + len > image.len() {
+        return None;
+    }
+    Some(data + len)
+}
+
+/// Tries the address table layouts before `kallsyms_num_syms` and after the token index.
+fn locate_addresses(
+    image: &[u8],
+    names: &Names,
+    tokens: &TokenTable,
+) -> anyhow::Result<(Vec<u64>, Option<u64>)> {
+    let count = names.entries.len();
+    let num_syms = names.num_syms_offset;
+    let offsets_size = align_up(count * 4, 8);
+
+    // Up to 6.3: [offsets][relative_base][num_syms] or [addresses][num_syms]
+    if let Some(base_at) = num_syms.checked_sub(8) {
+        if let Some(start) = base_at.checked_sub(offsets_size) {
+            if let Some(found) = relative_addresses(image, start, base_at, count) {
+                return Ok(found);
+            }
+        }
+    }
+    if let Some(start) = num_syms.checked_sub(count * 8) {
+        if let Some(addresses) = absolute_addresses(image, start, count) {
+            return Ok((addresses, None));
+        }
+    }
+
+    // 6.4 onwards: [token_index][offsets][relative_base] or [token_index][addresses]
+    let start = align_up(tokens.end, 8);
+    if let Some(found) = relative_addresses(image, start, start + offsets_size, count) {
+        return Ok(found);
+    }
+    if let Some(addresses) = absolute_addresses(image, start, count) {
+        return Ok((addresses, None));
+    }
+    Err(anyhow!("kallsyms_addresses/kallsyms_offsets not found"))
+}
+
+fn relative_addresses(
+    image: &[u8],
+    start: usize,
+    base_at: usize,
+    count: usize,
+) -> Option<(Vec<u64>, Option<u64>)> {
+    let base = read_u64(image, base_at)?;
+    if !is_kernel_address(base) {
+        return None;
+    }
+    let addresses: Vec<u64> = (0..count)
+        .map(|i| read_u32(image, start + i * 4).map(|offset| base + offset as u64))
+        .collect::<Option<_>>()?;
+    is_sorted(&addresses).then_some((addresses, Some(base)))
+}
+
+fn absolute_addresses(image: &[u8], start: usize, count: usize) -> Option<Vec<u64>> {
+    let addresses: Vec<u64> = (0..count)
+        .map(|i| read_u64(image, start + i * 8))
+        .collect::<Option<_>>()?;
+    (addresses.iter().all(|&a| is_kernel_address(a)) && is_sorted(&addresses)).then_some(addresses)
+}
+
+fn is_sorted(addresses: &[u64]) -> bool {
+    addresses.windows(2).all(|pair| pair[0] <= pair[1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: u64 = 0xFFFFFF8008080000;
+
+    /// The kernel whose scripts/kallsyms.c lays the tables out.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Layout {
+        Linux6_1,
+        /// Adds `kallsyms_seqs_of_names` before the token table.
+        Linux6_2,
+        /// Moves the addresses and `kallsyms_seqs_of_names` after the token index.
+        Linux6_4,
+    }
+
+    /// Builds kallsyms tables the way scripts/kallsyms.c lays them out.
+    fn build(symbols: &[(&str, u64)], relative: bool, layout: Layout) -> Vec<u8> {
+        // Printable characters are their own token, the rest are two letter tokens.
+        let tokens: Vec<Vec<u8>> = (0..TOKEN_COUNT)
+            .map(|i| match i as u8 {
+                c @ 0x21..=0x7E => vec![c],
+                c => vec![b'a' + c % 26, b'a' + c / 26 % 26],
+            })
+            .collect();
+        let mut names = vec![];
+        let mut markers = vec![];
+        for (idx, (name, _)) in symbols.iter().enumerate() {
+            if idx % SYMBOLS_PER_MARKER == 0 {
+                markers.push(names.len() as u32);
+            }
+            let mut encoded = vec![];
+            let mut rest = name.as_bytes();
+            while !rest.is_empty() {
+                match tokens
+                    .iter()
+                    .position(|t| t.len() == 2 && rest.starts_with(t))
+                {
+                    Some(token) => {
+                        encoded.push(token as u8);
+                        rest = &rest[2..];
+                    }
+                    None => {
+                        encoded.push(rest[0]);
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            names.push(encoded.len() as u8);
+            names.extend(encoded);
+        }
+
+        let mut addresses = vec![];
+        for (_, address) in symbols {
+            if relative {
+                addresses.extend(((address - BASE) as u32).to_le_bytes());
+            } else {
+                addresses.extend(address.to_le_bytes());
+            }
+        }
+        if relative {
+            addresses.resize(align_up(addresses.len(), 8), 0);
+            addresses.extend(BASE.to_le_bytes());
+        }
+
+        // The symbol indexes in name order, big-endian.
+        let mut seqs = vec![];
+        let mut by_name: Vec<usize> = (0..symbols.len()).collect();
+        by_name.sort_by_key(|&idx| &symbols[idx].0[1..]);
+        for idx in by_name {
+            seqs.extend(&(idx as u32).to_be_bytes()[1..]);
+        }
+
+        let pad = |image: &mut Vec<u8>| image.resize(align_up(image.len(), 8), 0);
+        let mut image = vec![0xAA; 0x100];
+        if layout != Layout::Linux6_4 {
+            image.extend(&addresses);
+        }
+        image.extend((symbols.len() as u64).to_le_bytes());
+        image.extend(&names);
+        pad(&mut image);
+        for marker in &markers {
+            image.extend(marker.to_le_bytes());
+        }
+        pad(&mut image);
+        if layout == Layout::Linux6_2 {
+            image.extend(&seqs);
+            pad(&mut image);
+        }
+        let table = image.len();
+        let mut index = vec![];
+        for token in &tokens {
+            index.extend(((image.len() - table) as u16).to_le_bytes());
+            image.extend(token);
+            image.push(0);
+        }
+        pad(&mut image);
+        image.extend(index);
+        if layout == Layout::Linux6_4 {
+            pad(&mut image);
+            image.extend(&addresses);
+            pad(&mut image);
+            image.extend(&seqs);
+        }
+        image.extend([0x55; 0x40]);
+        image
+    }
+
+    fn symbols() -> Vec<(String, u64)> {
+        let mut symbols = vec![
+            ("T_text".to_string(), BASE),
+            ("Tdo_execveat_common.isra.0".to_string(), BASE + 0x1000),
+            ("tavc_denied".to_string(), BASE + 0x2000),
+        ];
+        for i in 0..600 {
+            symbols.push((format!("tsym_{}", i), BASE + 0x3000 + i * 4));
+        }
+        symbols
+    }
+
+    #[test]
+    fn test_parse_kallsyms() {
+        let symbols = symbols();
+        let symbols: Vec<(&str, u64)> = symbols.iter().map(|(n, a)| (n.as_str(), *a)).collect();
+        for layout in [Layout::Linux6_1, Layout::Linux6_2, Layout::Linux6_4] {
+            for relative in [true, false] {
+                let image = build(&symbols, relative, layout);
+                let kallsyms = Kallsyms::parse(&image).unwrap();
+                assert_eq!(kallsyms.symbols.len(), symbols.len(), "{:?}", layout);
+                assert_eq!(kallsyms.relative_base, relative.then_some(BASE));
+                assert_eq!(kallsyms.text_base, BASE);
+                assert_eq!(kallsyms.file_offset("do_execveat_common"), Some(0x1000));
+                let avc_denied = kallsyms.lookup("avc_denied").unwrap();
+                assert_eq!(avc_denied.kind, 't');
+                assert_eq!(
+                    kallsyms.lookup("sym_599").unwrap().address,
+                    BASE + 0x3000 + 599 * 4
+                );
+                assert!(kallsyms.lookup("missing").is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_kallsyms_error() {
+        assert!(Kallsyms::parse(&[0u8; 0x1000]).is_err());
+    }
+}
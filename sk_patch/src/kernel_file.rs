@@ -0,0 +1,141 @@
+//! Reads the kernel out of the file given to sk_patch and packs it back in the same form.
+
+use std::{
+    fmt, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    boot_image::BootImage, compression::Compression, elf::Elf, image_header::ImageHeader,
+    kallsyms::Kallsyms,
+};
+
+/// How the kernel is stored in the file.
+#[derive(Debug, Clone)]
+pub enum Container {
+    Raw,
+    BootImage(BootImage),
+    /// A vmlinux, the kernel is its flattened segments.
+    Elf(Elf),
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Container::Raw => write!(f, "raw kernel"),
+            Container::BootImage(boot_image) => write!(f, "{}", boot_image),
+            Container::Elf(elf) => write!(f, "{}", elf),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KernelFile {
+    pub path: PathBuf,
+    pub container: Container,
+    pub compression: Compression,
+    pub header: ImageHeader,
+    /// The decompressed kernel.
+    pub kernel: Vec<u8>,
+}
+
+impl KernelFile {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let bytes = fs::read(&path)?;
+        let container = if BootImage::is_boot_image(&bytes) {
+            Container::BootImage(BootImage::parse(&bytes)?)
+        } else if Elf::is_elf(&bytes) {
+            Container::Elf(Elf::parse(&bytes)?)
+        } else {
+            Container::Raw
+        };
+        let (compression, kernel) = match &container {
+            Container::Raw => Compression::decompress(&bytes)?,
+            Container::BootImage(boot_image) => Compression::decompress(boot_image.kernel())?,
+            Container::Elf(elf) => Compression::decompress(&elf.image())?,
+        };
+        let header =
+            ImageHeader::parse(&kernel).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(KernelFile {
+            path,
+            container,
+            compression,
+            header,
+            kernel,
+        })
+    }
+
+    /// Packs `kernel` in the form the file was read in.
+    pub fn pack(&self, kernel: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let kernel = self.compression.compress(kernel)?;
+        Ok(match &self.container {
+            Container::Raw => kernel,
+            Container::BootImage(boot_image) => boot_image.repack(&kernel),
+            Container::Elf(elf) => elf.repack(&kernel)?,
+        })
+    }
+
+    /// The `.symtab` of a vmlinux.
+    pub fn symbols(&self) -> Option<Kallsyms> {
+        match &self.container {
+            Container::Elf(elf) if !elf.symbols.is_empty() => Some(Kallsyms::from_symbols(
+                elf.symbols.clone(),
+                elf.base,
+                ".symtab",
+            )),
+            _ => None,
+        }
+    }
+
+    /// Where a kernel offset is in a vmlinux file.
+    pub fn elf_offset(&self, offset: usize) -> Option<usize> {
+        match &self.container {
+            Container::Elf(elf) => elf.to_file_offset(elf.base + offset as u64),
+            _ => None,
+        }
+    }
+
+    /// Kernel offsets of the `.text` section of a vmlinux.
+    pub fn text_section(&self) -> Option<Range<usize>> {
+        match &self.container {
+            Container::Elf(elf) => {
+                let text = elf.section(".text")?;
+                let start = text.address.checked_sub(elf.base)? as usize;
+                Some(start..start + text.size as usize)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{test_kernel, test_vmlinux, TempPath};
+
+    #[test]
+    fn test_open_vmlinux() {
+        let path = TempPath::new("open_vmlinux");
+        let base = 0xFFFFFFC008000000;
+        fs::write(&path, test_vmlinux(&test_kernel(0x2000), base)).unwrap();
+
+        let file = KernelFile::open(&path).unwrap();
+        assert!(matches!(file.container, Container::Elf(_)));
+        assert_eq!(file.header.image_size, 0x2000);
+        assert_eq!(file.text_section(), Some(0..0x2000));
+        assert_eq!(file.elf_offset(0x100), Some(0x1100));
+        let symbols = file.symbols().unwrap();
+        assert_eq!(symbols.source, ".symtab");
+        assert_eq!(symbols.file_offset("do_execveat_common"), Some(0x100));
+
+        let mut kernel = file.kernel.clone();
+        kernel[0x100..0x104].copy_from_slice(&0x14000000u32.to_le_bytes());
+        fs::write(&path, file.pack(&kernel).unwrap()).unwrap();
+        let patched = KernelFile::open(&path).unwrap();
+        assert_eq!(patched.kernel, kernel);
+    }
+}
@@ -0,0 +1,71 @@
+//! The version in the `linux_banner` string, e.g. `Linux version 5.10.43-android12-9 (...)`.
+
+use std::fmt;
+
+use crate::finder::find_bytes;
+
+const BANNER: &[u8] = b"Linux version ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        KernelVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Reads the first banner with a version in it, `/proc/version` has the same format.
+    pub fn find(image: &[u8]) -> Option<Self> {
+        find_bytes(image, BANNER)
+            .into_iter()
+            .find_map(|offset| Self::parse(&image[offset + BANNER.len()..]))
+    }
+
+    fn parse(text: &[u8]) -> Option<Self> {
+        let end = text
+            .iter()
+            .position(|&b| !(b.is_ascii_digit() || b == b'.'))
+            .unwrap_or(text.len());
+        let text = std::str::from_utf8(&text[..end]).ok()?;
+        let mut numbers = text.split('.').map(|number| number.parse::<u32>().ok());
+        let major = numbers.next()??;
+        let minor = numbers.next()??;
+        let patch = numbers.next().flatten().unwrap_or(0);
+        Some(KernelVersion::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_kernel_version() {
+        let mut image = vec![0u8; 0x100];
+        image.extend_from_slice(b"Linux version %s\0");
+        image.extend_from_slice(b"Linux version 5.10.43-android12-9-00001 (build@host)\0");
+        let version = KernelVersion::find(&image).unwrap();
+        assert_eq!(version, KernelVersion::new(5, 10, 43));
+        assert_eq!(version.to_string(), "5.10.43");
+        assert!(version < KernelVersion::new(6, 1, 0));
+        assert_eq!(
+            KernelVersion::find(b"Linux version 6.1\0"),
+            Some(KernelVersion::new(6, 1, 0))
+        );
+        assert_eq!(KernelVersion::find(&image[..0x100]), None);
+    }
+}
@@ -1,81 +1,745 @@
-use std::{env, io::stdin};
+use std::{
+    fs,
+    io::stdin,
+    path::{Path, PathBuf},
+};
 
 mod asm_helper;
+mod assembler;
+mod boot_image;
+mod cave;
+mod cli;
+mod compression;
+mod cred_layout;
+mod disassembler;
+mod elf;
+mod finder;
 mod hook;
+mod image_header;
+mod installed;
+mod kallsyms;
+mod kernel_file;
+mod kernel_version;
+mod manifest;
 mod patcher;
+mod privileges;
+mod profile;
+mod relocate;
+mod report;
+mod siphash;
+#[cfg(test)]
+mod test_util;
+mod uid_range;
 
 use anyhow::anyhow;
 pub use asm_helper::LINE_ENDLING;
-use hook::{AVCDeniedHook, DoExecveHook};
-use patcher::Patcher;
+use cave::{check_cave, find_caves, pick_cave, Cave, TextRange};
+use clap::Parser;
+use cli::{Args, Commands};
+use cred_layout::CredLayout;
+use finder::{
+    avc_denied::find_avc_denied,
+    best_confidence, check_hook_prologue,
+    do_execve::find_do_execve,
+    proc_pid_status::{find_task_offsets, Confidence, FieldOffset, TaskOffsets},
+    with_symbols, Candidate,
+};
+use hook::{
+    AVCDeniedHook, DoExecveHook, Filldir64Hook, Hook, OriginalAt, MAX_ALLOWED_UIDS, MAX_ROOT_KEYS,
+};
+use installed::{find_installed_hooks, verify_hooks};
+use kallsyms::Kallsyms;
+use kernel_file::{Container, KernelFile};
+use kernel_version::KernelVersion;
+use manifest::{sha256_hex, HookRecord, Manifest, TOOL_VERSION};
+use patcher::{hookers_size, Patcher};
+use profile::{Location, Profile};
 use rand::random;
 use regex::Regex;
+use report::PatchReport;
 use simple_logger::SimpleLogger;
+use uid_range::UidRange;
+
+/// Kernel symbols the do_execve hook can be placed on, newest first.
+const DO_EXECVE_SYMBOLS: &[&str] = &["do_execveat_common", "__do_execve_file"];
+const AVC_DENIED_SYMBOLS: &[&str] = &["avc_denied"];
+const FILLDIR64_SYMBOLS: &[&str] = &["filldir64"];
+const MAX_REPORTED_CAVES: usize = 8;
 
 fn main() -> anyhow::Result<()> {
     SimpleLogger::new().env().init().unwrap();
 
-    let mut args = env::args();
-    args.next(); // ignore self
-
-    let image_path = args.next().unwrap_or("raw_kernel".to_string());
-
-    let patcher = &mut Patcher::new(&image_path)?;
+    let args = Args::parse();
+    match args.commands {
+        Some(Commands::Find { image }) => find(&image),
+        Some(Commands::Verify { image }) => verify(&image),
+        Some(Commands::Unpatch {
+            image,
+            dry_run,
+            yes,
+        }) => unpatch(&image, dry_run, yes),
+        Some(Commands::Replay {
+            manifest,
+            image,
+            root_key,
+            yes,
+        }) => replay(&manifest, image, root_key, yes),
+        None => patch(args.patch.into_profile()?),
+    }
+}
 
-    let patch_start_offset = wait_input("请输入patch代码起始偏移值:", hex_to_usize);
+fn patch(profile: Profile) -> anyhow::Result<()> {
+    let non_interactive = profile.is_non_interactive();
+    let dry_run = profile.is_dry_run();
+    let manifest_key = profile.is_manifest_key();
+    let hide_su_dirs = profile.is_hide_su_dirs();
+    let key_digest = profile.is_key_digest();
+    let mut privileges = profile.privileges()?;
+    let image_path = profile.image.as_deref().unwrap_or("raw_kernel");
+    let patcher = &mut Patcher::new(image_path)?;
+    print_kernel_file(patcher.file());
+    let image = patcher.image().to_vec();
+    let kallsyms = load_symbols(patcher.file());
 
-    let root_key = {
-        let need_generate = wait_input("是否需要自动随机生成ROOT密匙?(Y/y)", confirm);
-        if need_generate {
-            generate_random_root_key()
-        } else {
-            wait_input(
-                "请输入ROOT密匙(48个字符的字符串,包含大小写字母和数字)",
-                valid_input_key,
-            )
+    let root_keys = match (profile.root_key, profile.generate_root_key) {
+        (Some(root_keys), _) => valid_root_keys(&root_keys)?,
+        (None, Some(true)) => vec![generate_random_root_key()],
+        (None, _) if non_interactive => vec![generate_random_root_key()],
+        (None, _) => {
+            let need_generate = wait_input("是否需要自动随机生成ROOT密匙?(Y/y)", confirm)?;
+            if need_generate {
+                vec![generate_random_root_key()]
+            } else {
+                vec![wait_input(
+                    "请输入ROOT密匙(48个字符的字符串,包含大小写字母和数字)",
+                    valid_input_key,
+                )?]
+            }
         }
     };
-    let mut next_offset = patch_start_offset;
-    next_offset = patcher.patch_root_key(&root_key, next_offset);
+    for root_key in &root_keys {
+        println!("ROOT密匙: {}", root_key);
+    }
+    let digest_key = key_digest.then(random::<[u64; 2]>);
+    if digest_key.is_some() {
+        println!("内核中只保存ROOT密匙的摘要");
+    }
+    let allowed_uids = parse_allowed_uids(profile.allow_uid.as_deref().unwrap_or_default())?;
+    if !allowed_uids.is_empty() {
+        let uids: Vec<_> = allowed_uids.iter().map(|uids| uids.to_string()).collect();
+        println!("仅允许以下uid使用ROOT密匙: {}", uids.join(" "));
+    }
 
-    let cred_offset = wait_input(
-        "请输入task_struct结构体里cred的十六进制偏移值:",
-        hex_to_usize,
-    );
-    let seccomp_offset = wait_input(
-        "请输入task_struct结构体里seccomp的十六进制偏移值:",
-        hex_to_usize,
-    );
+    let task_offsets = find_task_offsets(&image);
+    print_task_offsets(&task_offsets);
+    let cred_offset = offset_input(
+        "请输入task_struct结构体里cred的十六进制偏移值",
+        "cred-offset",
+        profile.cred_offset,
+        task_offsets.cred.as_ref(),
+        non_interactive,
+    )?;
+    let cred_layout = CredLayout::detect(&image);
+    println!("struct cred布局: {}", cred_layout);
+    let detected_seccomp = task_offsets.seccomp.as_ref();
+    let clear_seccomp = match profile.keep_seccomp {
+        Some(keep_seccomp) => !keep_seccomp,
+        None if non_interactive => true,
+        None if profile.seccomp_offset.is_some() || profile.thread_flags.is_some() => true,
+        // A detected offset makes clearing seccomp the default.
+        None if detected_seccomp.is_some() => wait_input(
+            "是否需要提升进程的seccomp?(N/n为否, 直接回车为是)",
+            |s| Ok(!s.eq_ignore_ascii_case("n")),
+        )?,
+        None => wait_input("是否需要提升进程的seccomp?(Y/y)", confirm)?,
+    };
+    let seccomp_offset = if clear_seccomp {
+        Some(offset_input(
+            "请输入task_struct结构体里seccomp的十六进制偏移值",
+            "seccomp-offset",
+            profile.seccomp_offset,
+            detected_seccomp,
+            non_interactive,
+        )?)
+    } else {
+        println!("不修改进程的seccomp");
+        None
+    };
+    if seccomp_offset.is_none() {
+        // The thread flags are only cleared along with seccomp.
+        privileges.thread_flags = 0;
+    }
+    if !privileges.is_root() {
+        println!("授予的权限: {}", privileges);
+    }
 
-    let do_execve_entry = wait_input("请输入do_execve函数的入口位置:", hex_to_usize);
-    let do_execve_hook = DoExecveHook {
-        root_key_size: root_key.len(),
-        hooker_entry: next_offset,
+    let do_execve_candidates =
+        with_symbols(find_do_execve(&image), kallsyms.as_ref(), DO_EXECVE_SYMBOLS);
+    print_candidates("do_execve", &do_execve_candidates);
+    let do_execve_entry = entry_input(
+        patcher.file(),
+        kallsyms.as_ref(),
+        "do_execve",
+        OriginalAt::Entry,
+        &do_execve_candidates,
+        profile.do_execve,
+        non_interactive,
+    )?;
+    let mut do_execve_hook = DoExecveHook {
+        root_keys,
+        allowed_uids,
+        digest_key,
+        privileges,
+        hooker_entry: 0,
         hookee_entry: do_execve_entry,
         cred_offset,
+        cred_layout,
         seccomp_offset,
     };
-    next_offset = patcher.patch_do_execve(do_execve_hook)?;
 
-    let avc_denied_entry = wait_input("请输入avc_denied函数的入口位置:", hex_to_usize);
-    let avc_denied_hook = AVCDeniedHook {
-        hooker_entry: next_offset,
+    let avc_denied_candidates = with_symbols(
+        find_avc_denied(&image),
+        kallsyms.as_ref(),
+        AVC_DENIED_SYMBOLS,
+    );
+    print_candidates("avc_denied", &avc_denied_candidates);
+    let avc_denied_entry = entry_input(
+        patcher.file(),
+        kallsyms.as_ref(),
+        "avc_denied",
+        OriginalAt::Exit,
+        &avc_denied_candidates,
+        profile.avc_denied,
+        non_interactive,
+    )?;
+    let mut avc_denied_hook = AVCDeniedHook {
+        hooker_entry: 0,
         hookee_entry: avc_denied_entry,
         cred_offset,
+        cred_layout,
+    };
+
+    let filldir64_hook = if hide_su_dirs {
+        // Only kallsyms knows where filldir64 is.
+        let filldir64_candidates = with_symbols(vec![], kallsyms.as_ref(), FILLDIR64_SYMBOLS);
+        print_candidates("filldir64", &filldir64_candidates);
+        let filldir64_entry = entry_input(
+            patcher.file(),
+            kallsyms.as_ref(),
+            "filldir64",
+            OriginalAt::Exit,
+            &filldir64_candidates,
+            profile.filldir64,
+            non_interactive,
+        )?;
+        let version = KernelVersion::find(&image);
+        if version.is_none() {
+            log::warn!("kernel version unknown, assuming filldir64 returns int");
+        }
+        Some(Filldir64Hook {
+            hooker_entry: 0,
+            hookee_entry: filldir64_entry,
+            cred_offset,
+            cred_layout,
+            returns_bool: Filldir64Hook::returns_bool_for(version),
+        })
+    } else {
+        None
     };
-    patcher.patch_avc_denied(avc_denied_hook)?;
 
-    let apply = wait_input("是否立即修补内核文件?(Y/y)", confirm);
+    let text_range = TextRange::of(&image, kallsyms.as_ref(), patcher.file().text_section());
+    let mut hooks: Vec<&dyn Hook> = vec![&do_execve_hook, &avc_denied_hook];
+    if let Some(filldir64_hook) = &filldir64_hook {
+        hooks.push(filldir64_hook);
+    }
+    let required_size = hookers_size(&hooks)?;
+    let caves = find_caves(&image, &text_range);
+    print_caves(&text_range, &caves, required_size);
+    let picked = pick_cave(&caves, required_size).map(|cave| cave.offset);
+    let patch_start_offset = given_or_detected(
+        profile
+            .cave_offset
+            .map(|cave| cave.resolve(kallsyms.as_ref()))
+            .transpose()?,
+        picked,
+        // A picked cave is all zero and inside the text, it is checked below either way.
+        None,
+        non_interactive,
+        "cave-offset",
+    )
+    .unwrap_or_else(|| {
+        wait_input(
+            &default_tips("请输入patch代码起始偏移值", picked),
+            |s| {
+                let offset = match (s, picked) {
+                    ("", Some(picked)) => picked,
+                    (s, _) => Location::Text(s.to_string()).resolve(kallsyms.as_ref())?,
+                };
+                check_cave(&image, &text_range, offset, required_size)?;
+                Ok(offset)
+            },
+        )
+    })?;
+    // Offsets given up front are not checked by the prompt.
+    check_cave(&image, &text_range, patch_start_offset, required_size)?;
+    print_location(
+        patcher.file(),
+        "patch代码起始",
+        patch_start_offset,
+        kallsyms.as_ref(),
+    );
+
+    let root_keys = do_execve_hook.root_keys.clone();
+    let allowed_uids = do_execve_hook.allowed_uids.clone();
+    do_execve_hook.hooker_entry = patch_start_offset;
+    avc_denied_hook.hooker_entry = patcher.patch_do_execve(do_execve_hook)?;
+    let avc_denied_hooker = avc_denied_hook.hooker_entry;
+    let end = patcher.patch_avc_denied(avc_denied_hook)?;
+    let mut hooks = vec![
+        HookRecord {
+            name: "do_execve".to_string(),
+            hookee_entry: do_execve_entry,
+            hooker_entry: patch_start_offset,
+            hooker_size: avc_denied_hooker - patch_start_offset,
+        },
+        HookRecord {
+            name: "avc_denied".to_string(),
+            hookee_entry: avc_denied_entry,
+            hooker_entry: avc_denied_hooker,
+            hooker_size: end - avc_denied_hooker,
+        },
+    ];
+    if let Some(mut filldir64_hook) = filldir64_hook {
+        filldir64_hook.hooker_entry = end;
+        let filldir64_entry = filldir64_hook.hookee_entry;
+        hooks.push(HookRecord {
+            name: "filldir64".to_string(),
+            hookee_entry: filldir64_entry,
+            hooker_entry: end,
+            hooker_size: patcher.patch_filldir64(filldir64_hook)? - end,
+        });
+    }
+
+    if dry_run || profile.report.is_some() {
+        let text_base = kallsyms.as_ref().map(|kallsyms| kallsyms.text_base);
+        let report = PatchReport::new(patcher.file(), patcher.patches(), text_base);
+        if let Some(path) = &profile.report {
+            report.write_json(Path::new(path))?;
+            println!("已写入修补报告: {}", path);
+        }
+        if dry_run {
+            print!("{}", report);
+            println!("dry-run模式, 未修改内核文件");
+            return Ok(());
+        }
+    }
+
+    let apply = match profile.apply {
+        Some(apply) => apply,
+        None if non_interactive => {
+            return Err(anyhow!("pass --apply or --discard in non-interactive mode"))
+        }
+        None => wait_input("是否立即修补内核文件?(Y/y)", confirm)?,
+    };
     if apply {
+        let input = fs::read(&patcher.file().path)?;
+        let output = patcher.apply_patches()?;
+        println!("已完成修补内核文件");
+        let manifest = Manifest {
+            tool_version: TOOL_VERSION.to_string(),
+            image: file_name(&patcher.file().path),
+            input_sha256: sha256_hex(&input),
+            output_sha256: sha256_hex(&output),
+            root_keys_sha256: root_keys
+                .iter()
+                .map(|root_key| sha256_hex(root_key.as_bytes()))
+                .collect(),
+            root_keys: if manifest_key { root_keys } else { vec![] },
+            allowed_uids: allowed_uids.iter().map(|uids| uids.to_string()).collect(),
+            digest_key: digest_key.map(|[k0, k1]| format!("{:016x}{:016x}", k0, k1)),
+            privileges,
+            cred_offset,
+            seccomp_offset,
+            hooks,
+        };
+        let manifest_path = Manifest::path_for(&patcher.file().path);
+        manifest.write_json(&manifest_path)?;
+        println!("已写入修补清单: {}", manifest_path.display());
+    } else {
+        println!("已放弃修改内核文件")
+    }
+
+    Ok(())
+}
+
+fn find(image_path: &str) -> anyhow::Result<()> {
+    let file = KernelFile::open(image_path)?;
+    print_kernel_file(&file);
+    let kallsyms = load_symbols(&file);
+    let text_range = TextRange::of(&file.kernel, kallsyms.as_ref(), file.text_section());
+    let image = file.kernel;
+    print_task_offsets(&find_task_offsets(&image));
+    println!("struct cred布局: {}", CredLayout::detect(&image));
+    let do_execve_candidates =
+        with_symbols(find_do_execve(&image), kallsyms.as_ref(), DO_EXECVE_SYMBOLS);
+    print_candidates("do_execve", &do_execve_candidates);
+    let avc_denied_candidates = with_symbols(
+        find_avc_denied(&image),
+        kallsyms.as_ref(),
+        AVC_DENIED_SYMBOLS,
+    );
+    print_candidates("avc_denied", &avc_denied_candidates);
+    print_caves(&text_range, &find_caves(&image, &text_range), 0);
+    Ok(())
+}
+
+fn verify(image_path: &str) -> anyhow::Result<()> {
+    let file = KernelFile::open(image_path)?;
+    print_kernel_file(&file);
+    let hooks = find_installed_hooks(&file.kernel)?;
+    if hooks.is_empty() {
+        return Err(anyhow!("no SKRoot hooks found in {}", image_path));
+    }
+    println!("已安装的SKRoot补丁:");
+    for hook in &hooks {
+        println!("    {}", hook);
+    }
+    for root_key in hooks.iter().flat_map(|hook| &hook.root_keys) {
+        println!("ROOT密匙: {}", root_key);
+    }
+    for hook in hooks.iter().filter(|hook| hook.key_before > 0) {
+        println!(
+            "旧版sk_patch安装的补丁, ROOT密匙保存在patch代码之前的0x{:x}",
+            hook.start()
+        );
+    }
+    for hook in hooks.iter().filter(|hook| hook.key_digests > 0) {
+        println!("内核中只保存了{}个ROOT密匙的摘要", hook.key_digests);
+    }
+    let print_offset = |name: &str, offset: Option<usize>| {
+        if let Some(offset) = offset {
+            println!("task_struct结构体里{}的偏移值: 0x{:x}", name, offset);
+        }
+    };
+    print_offset("cred", hooks.iter().find_map(|hook| hook.cred_offset));
+    print_offset("seccomp", hooks.iter().find_map(|hook| hook.seccomp_offset));
+
+    let problems = verify_hooks(&hooks);
+    if problems.is_empty() {
+        println!("SKRoot补丁检查通过");
+        return Ok(());
+    }
+    println!("SKRoot补丁存在问题:");
+    for problem in &problems {
+        println!("    {}", problem);
+    }
+    Err(anyhow!(
+        "{} problem(s) found in the installed hooks",
+        problems.len()
+    ))
+}
+
+fn unpatch(image_path: &str, dry_run: bool, yes: bool) -> anyhow::Result<()> {
+    let patcher = &mut Patcher::new(image_path)?;
+    print_kernel_file(patcher.file());
+    let hooks = patcher.unpatch()?;
+    if hooks.is_empty() {
+        println!("未发现已安装的SKRoot补丁");
+        return Ok(());
+    }
+    println!("已安装的SKRoot补丁:");
+    for hook in &hooks {
+        println!("    {}", hook);
+    }
+
+    if dry_run {
+        let text_base = load_symbols(patcher.file()).map(|kallsyms| kallsyms.text_base);
+        print!(
+            "{}",
+            PatchReport::new(patcher.file(), patcher.patches(), text_base)
+        );
+        println!("dry-run模式, 未修改内核文件");
+        return Ok(());
+    }
+    if yes || wait_input("是否立即还原内核文件?(Y/y)", confirm)? {
         patcher.apply_patches()?;
-        println!("已完成修补内核文件")
+        println!("已完成还原内核文件")
     } else {
         println!("已放弃修改内核文件")
     }
+    Ok(())
+}
+
+fn replay(
+    manifest_path: &Path,
+    image: Option<String>,
+    root_keys: Vec<String>,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let manifest = Manifest::load(manifest_path)?;
+    println!(
+        "修补清单: {} (sk_patch {})",
+        manifest_path.display(),
+        manifest.tool_version
+    );
+    let patched_path = manifest_path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(&manifest.image);
+    // The result goes to the given image, or back to the patched one when it is made
+    // from its backup.
+    let (image_path, output_path) = match image {
+        Some(image) => (PathBuf::from(&image), PathBuf::from(image)),
+        None => (stock_image_for(&patched_path), patched_path),
+    };
+    let patcher = &mut Patcher::new(&image_path.to_string_lossy())?;
+    print_kernel_file(patcher.file());
+    let root_keys = match (root_keys.is_empty(), manifest.root_keys.is_empty()) {
+        (false, _) => valid_root_keys(&root_keys)?,
+        (true, false) => valid_root_keys(&manifest.root_keys)?,
+        (true, true) => (0..manifest.root_keys_sha256.len())
+            .map(|index| {
+                wait_input(
+                    &format!(
+                        "请输入第{}个ROOT密匙(48个字符的字符串,包含大小写字母和数字)",
+                        index + 1
+                    ),
+                    |s| {
+                        let root_key = valid_input_key(s)?;
+                        manifest.check_root_key(index, &root_key)?;
+                        Ok(root_key)
+                    },
+                )
+            })
+            .collect::<anyhow::Result<_>>()?,
+    };
+    for hook in &manifest.hooks {
+        println!(
+            "{}: 入口0x{:x}, patch代码0x{:x}, 0x{:x}字节",
+            hook.name, hook.hookee_entry, hook.hooker_entry, hook.hooker_size
+        );
+    }
 
+    let output = manifest.replay(patcher, &root_keys)?;
+    println!("重新生成的内核与修补清单一致");
+    if yes || wait_input("是否立即修补内核文件?(Y/y)", confirm)? {
+        if output_path == image_path {
+            patcher.apply_patches()?;
+        } else {
+            fs::write(&output_path, output)?;
+        }
+        println!("已完成修补内核文件: {}", output_path.display())
+    } else {
+        println!("已放弃修改内核文件")
+    }
     Ok(())
 }
 
+/// The stock image of `patched_path`: patching rewrites the image in place and keeps the
+/// stock one as its `.bak`.
+fn stock_image_for(patched_path: &Path) -> PathBuf {
+    let mut backup = patched_path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    if backup.exists() {
+        backup
+    } else {
+        patched_path.to_path_buf()
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+fn print_kernel_file(file: &KernelFile) {
+    println!("内核文件格式: {} ({})", file.container, file.compression);
+    println!("内核镜像头: {}", file.header);
+    if let Some(version) = KernelVersion::find(&file.kernel) {
+        println!("内核版本: {}", version);
+    }
+    if let Container::BootImage(boot_image) = &file.container {
+        println!("内核命令行: {}", boot_image.cmdline());
+    }
+}
+
+fn print_caves(text_range: &TextRange, caves: &[Cave], required_size: usize) {
+    println!("内核代码段范围: {}", text_range);
+    if required_size > 0 {
+        println!("patch代码需要0x{:x}字节", required_size);
+    }
+    if caves.is_empty() {
+        println!("未能在内核代码段内找到空白区域");
+        return;
+    }
+    println!("内核代码段内的空白区域:");
+    for cave in caves.iter().take(MAX_REPORTED_CAVES) {
+        println!("    {}", cave);
+    }
+}
+
+fn print_candidates(name: &str, candidates: &[Candidate]) {
+    if candidates.is_empty() {
+        println!("未能自动定位{}函数", name);
+        return;
+    }
+    println!("{}函数可能的入口位置:", name);
+    for candidate in candidates {
+        println!("    {}", candidate);
+    }
+}
+
+fn print_task_offsets(offsets: &TaskOffsets) {
+    let print = |name: &str, offset: &Option<FieldOffset>| match offset {
+        Some(offset) => println!("task_struct结构体里{}的偏移值: {}", name, offset),
+        None => println!("未能自动获取task_struct结构体里{}的偏移值", name),
+    };
+    print("cred", &offsets.cred);
+    print("seccomp", &offsets.seccomp);
+}
+
+/// Reads the `.symtab` of a vmlinux, or recovers kallsyms from the kernel.
+fn load_symbols(file: &KernelFile) -> Option<Kallsyms> {
+    if let Some(symtab) = file.symbols() {
+        println!(
+            "已读取ELF符号表: {}个符号, _text=0x{:x}",
+            symtab.symbols.len(),
+            symtab.text_base
+        );
+        return Some(symtab);
+    }
+    match Kallsyms::parse(&file.kernel) {
+        Ok(kallsyms) => {
+            println!(
+                "已解析kallsyms符号表: {}个符号, _text=0x{:x}",
+                kallsyms.symbols.len(),
+                kallsyms.text_base
+            );
+            if let Some(relative_base) = kallsyms.relative_base {
+                println!("kallsyms_relative_base=0x{:x}", relative_base);
+            }
+            Some(kallsyms)
+        }
+        Err(e) => {
+            log::warn!("kallsyms not recovered: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Takes the value given up front, or in non-interactive mode the detected one unless
+/// there is a `doubt` about it. `None` means it has to be asked for.
+fn given_or_detected(
+    given: Option<usize>,
+    detected: Option<usize>,
+    doubt: Option<String>,
+    non_interactive: bool,
+    option: &str,
+) -> Option<anyhow::Result<usize>> {
+    match (given, detected, doubt) {
+        (Some(given), _, _) => Some(Ok(given)),
+        (None, Some(detected), None) if non_interactive => Some(Ok(detected)),
+        (None, Some(detected), Some(doubt)) if non_interactive => Some(Err(anyhow!(
+            "{} 0x{:x} was detected {}, check it and pass it with --{}",
+            option,
+            detected,
+            doubt,
+            option
+        ))),
+        (None, None, _) if non_interactive => Some(Err(anyhow!(
+            "{} could not be detected, pass it with --{}",
+            option,
+            option
+        ))),
+        (None, _, _) => None,
+    }
+}
+
+fn confidence_doubt(confidence: Confidence) -> Option<String> {
+    match confidence {
+        Confidence::High => None,
+        confidence => Some(format!("only with {:?} confidence", confidence)),
+    }
+}
+
+fn offset_input(
+    tips: &str,
+    option: &str,
+    given: Option<Location>,
+    detected: Option<&FieldOffset>,
+    non_interactive: bool,
+) -> anyhow::Result<usize> {
+    let given = given.map(|offset| offset.offset()).transpose()?;
+    let doubt = detected.and_then(|detected| confidence_doubt(detected.confidence));
+    let detected = detected.map(|detected| detected.value);
+    given_or_detected(given, detected, doubt, non_interactive, option)
+        .unwrap_or_else(|| wait_hex_input(tips, detected))
+}
+
+fn entry_input(
+    file: &KernelFile,
+    kallsyms: Option<&Kallsyms>,
+    name: &str,
+    original_at: OriginalAt,
+    candidates: &[Candidate],
+    given: Option<Location>,
+    non_interactive: bool,
+) -> anyhow::Result<usize> {
+    let given = given.map(|entry| entry.resolve(kallsyms)).transpose()?;
+    let default = candidates.first().map(|best| best.entry);
+    let doubt =
+        default.and_then(
+            |entry| match check_hook_prologue(&file.kernel, entry, original_at) {
+                Err(e) => Some(format!("but {:#}", e)),
+                Ok(_) => best_confidence(candidates).and_then(confidence_doubt),
+            },
+        );
+    let option = name.replace('_', "-");
+    let entry = given_or_detected(given, default, doubt, non_interactive, &option).unwrap_or_else(
+        || {
+            let tips = match kallsyms {
+                Some(_) => format!("请输入{}函数的入口位置或符号名", name),
+                None => format!("请输入{}函数的入口位置", name),
+            };
+            wait_input(&default_tips(&tips, default), |s| match (s, default) {
+                ("", Some(default)) => Ok(default),
+                (s, _) => Location::Text(s.to_string()).resolve(kallsyms),
+            })
+        },
+    )?;
+    if let Err(e) = check_hook_prologue(&file.kernel, entry, original_at) {
+        log::warn!("{} entry 0x{:x}: {:?}", name, entry, e);
+    }
+    print_location(file, &format!("{}函数入口", name), entry, kallsyms);
+    Ok(entry)
+}
+
+fn print_location(file: &KernelFile, name: &str, offset: usize, kallsyms: Option<&Kallsyms>) {
+    let mut location = format!("{}: 内核偏移0x{:x}", name, offset);
+    if let Some(kallsyms) = kallsyms {
+        location += &format!(", 虚拟地址0x{:x}", kallsyms.to_address(offset));
+    }
+    if let Some(elf_offset) = file.elf_offset(offset) {
+        location += &format!(", ELF文件偏移0x{:x}", elf_offset);
+    }
+    println!("{}", location);
+}
+
+fn default_tips(tips: &str, default: Option<usize>) -> String {
+    match default {
+        Some(default) => format!("{}(直接回车使用0x{:x}):", tips, default),
+        None => format!("{}:", tips),
+    }
+}
+
+fn wait_hex_input(tips: &str, default: Option<usize>) -> anyhow::Result<usize> {
+    wait_input(&default_tips(tips, default), |s| match (s, default) {
+        ("", Some(default)) => Ok(default),
+        (s, _) => hex_to_usize(s),
+    })
+}
+
 fn hex_to_usize(hex: &str) -> anyhow::Result<usize> {
     let prefix = "0x";
     let hex = if hex.starts_with(prefix) {
@@ -92,7 +756,7 @@ fn confirm(s: &str) -> anyhow::Result<bool> {
 
 fn valid_input_key(input_key: &str) -> anyhow::Result<String> {
     let input_key = input_key.trim();
-    let regex = Regex::new(r"[0-9a-zA-Z]{48}")?;
+    let regex = Regex::new(r"^[0-9a-zA-Z]{48}$")?;
     if regex.is_match(input_key) {
         Ok(input_key.to_string())
     } else {
@@ -100,7 +764,34 @@ fn valid_input_key(input_key: &str) -> anyhow::Result<String> {
     }
 }
 
-fn wait_input<P, R>(tips: &str, parser: P) -> R
+fn valid_root_keys(root_keys: &[String]) -> anyhow::Result<Vec<String>> {
+    if root_keys.len() > MAX_ROOT_KEYS {
+        return Err(anyhow!("at most {} root keys are supported", MAX_ROOT_KEYS));
+    }
+    let root_keys = root_keys
+        .iter()
+        .map(|root_key| valid_input_key(root_key))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (i, root_key) in root_keys.iter().enumerate() {
+        if root_keys[..i].contains(root_key) {
+            return Err(anyhow!("root key {} is given twice", root_key));
+        }
+    }
+    Ok(root_keys)
+}
+
+fn parse_allowed_uids(allowed_uids: &[String]) -> anyhow::Result<Vec<UidRange>> {
+    if allowed_uids.len() > MAX_ALLOWED_UIDS {
+        return Err(anyhow!(
+            "at most {} uid ranges are supported",
+            MAX_ALLOWED_UIDS
+        ));
+    }
+    allowed_uids.iter().map(|uids| uids.parse()).collect()
+}
+
+/// Asks until `parser` accepts the input, failing once stdin is closed.
+fn wait_input<P, R>(tips: &str, parser: P) -> anyhow::Result<R>
 where
     P: Fn(&str) -> anyhow::Result<R>,
     R: Sized,
@@ -109,6 +800,7 @@ where
         println!("{}", tips);
         let buf = &mut String::new();
         match stdin().read_line(buf) {
+            Ok(0) => return Err(anyhow!("stdin closed while waiting for input")),
             Ok(_) => (),
             Err(e) => {
                 eprintln!("{:?}", e);
@@ -117,7 +809,7 @@ where
         };
         match parser(buf.trim()) {
             Ok(result) => {
-                break result;
+                break Ok(result);
             }
             Err(e) => {
                 eprintln!("{:?}", e);
@@ -144,9 +836,11 @@ mod test {
 
     use regex::Regex;
 
+    use std::fs;
+
     use crate::{
-        aarch64, asm_helper::asm_to_be_bytes, generate_random_root_key,
-        wait_input,
+        aarch64, asm_helper::asm_to_bytes, generate_random_root_key, given_or_detected,
+        stock_image_for, test_util::TempPath, valid_input_key, valid_root_keys, wait_input,
     };
 
     #[test]
@@ -157,7 +851,7 @@ mod test {
             "MOV X0, X0";
         };
         let bytes = u32::from_str_radix("E00300AA", 16).unwrap().to_be_bytes();
-        assert_eq!(&bytes as &[u8], &asm_to_be_bytes(&asm_text).unwrap());
+        assert_eq!(&bytes as &[u8], &asm_to_bytes(&asm_text).unwrap());
     }
 
     #[test]
@@ -167,9 +861,63 @@ mod test {
         assert!(regex.is_match(&ramdom_root_key));
     }
 
+    #[test]
+    fn test_valid_root_keys() {
+        let key = "aB3".repeat(16);
+        assert_eq!(valid_input_key(&format!(" {}\n", key)).unwrap(), key);
+        for bad in [
+            format!("{}a", key),
+            format!("{}\"", key),
+            format!("\"\nMOV X0, #0\n{}", key),
+            format!("{}\n.asciz \"", key),
+            key[1..].to_string(),
+            key.replace('a', "-"),
+        ] {
+            assert!(valid_input_key(&bad).is_err(), "{:?}", bad);
+        }
+        assert!(valid_root_keys(&[key.clone(), key]).is_err());
+    }
+
+    #[test]
+    fn test_given_or_detected() {
+        let doubt = || Some("only with Low confidence".to_string());
+        assert_eq!(
+            given_or_detected(Some(0x618), Some(0x610), doubt(), true, "cred-offset")
+                .unwrap()
+                .unwrap(),
+            0x618
+        );
+        assert_eq!(
+            given_or_detected(None, Some(0x610), None, true, "cred-offset")
+                .unwrap()
+                .unwrap(),
+            0x610
+        );
+        let e = given_or_detected(None, Some(0x610), doubt(), true, "cred-offset")
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("--cred-offset"));
+        assert!(given_or_detected(None, None, None, true, "cred-offset")
+            .unwrap()
+            .is_err());
+        assert!(given_or_detected(None, Some(0x610), doubt(), false, "cred-offset").is_none());
+    }
+
+    #[test]
+    fn test_stock_image_for() {
+        let dir = TempPath::new("stock_image_for");
+        fs::create_dir_all(&dir).unwrap();
+        let patched = dir.join("boot.img");
+        let backup = dir.join("boot.img.bak");
+        fs::remove_file(&backup).ok();
+        assert_eq!(stock_image_for(&patched), patched);
+        fs::write(&backup, b"stock").unwrap();
+        assert_eq!(stock_image_for(&patched), backup);
+    }
+
     #[test]
     fn test_wait_input() {
         use crate::hex_to_usize;
-        wait_input("hex_test", hex_to_usize);
+        wait_input("hex_test", hex_to_usize).ok();
     }
 }
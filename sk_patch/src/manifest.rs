@@ -0,0 +1,294 @@
+//! Records what a patch run did, so the same stock image can be patched again
+//! into an identical kernel.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cred_layout::CredLayout,
+    hook::{AVCDeniedHook, DoExecveHook, Filldir64Hook},
+    kernel_version::KernelVersion,
+    patcher::Patcher,
+    privileges::Privileges,
+    report::hex_bytes,
+};
+
+pub const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex_bytes(&Sha256::digest(bytes))
+}
+
+/// Where a hook was placed, all offsets are into the decompressed kernel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookRecord {
+    pub name: String,
+    pub hookee_entry: usize,
+    pub hooker_entry: usize,
+    pub hooker_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    /// The image file name.
+    pub image: String,
+    /// SHA-256 of the stock file.
+    pub input_sha256: String,
+    /// SHA-256 of the patched file.
+    pub output_sha256: String,
+    /// SHA-256 of each root key, the keys themselves are only stored when asked for.
+    pub root_keys_sha256: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub root_keys: Vec<String>,
+    /// Uid ranges the do_execve hook grants root to, anyone when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_uids: Vec<String>,
+    /// The SipHash key of the root key digests, as two hex u64s, when the kernel holds
+    /// digests instead of the keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_key: Option<String>,
+    /// What the do_execve hook grants, full root when left out.
+    #[serde(default, skip_serializing_if = "Privileges::is_root")]
+    pub privileges: Privileges,
+    pub cred_offset: usize,
+    /// Left out when the do_execve hook does not clear seccomp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp_offset: Option<usize>,
+    pub hooks: Vec<HookRecord>,
+}
+
+impl Manifest {
+    /// The manifest written alongside `image`.
+    pub fn path_for(image: &Path) -> PathBuf {
+        let mut path = image.as_os_str().to_owned();
+        path.push(".manifest.json");
+        PathBuf::from(path)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read manifest {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn hook(&self, name: &str) -> anyhow::Result<&HookRecord> {
+        self.hooks
+            .iter()
+            .find(|hook| hook.name == name)
+            .ok_or_else(|| anyhow!("the manifest has no {} hook", name))
+    }
+
+    /// Checks the `index`th root key against its fingerprint.
+    pub fn check_root_key(&self, index: usize, root_key: &str) -> anyhow::Result<()> {
+        match self.root_keys_sha256.get(index) {
+            Some(sha256) if *sha256 == sha256_hex(root_key.as_bytes()) => Ok(()),
+            Some(_) => Err(anyhow!(
+                "root key {} does not match the manifest fingerprint",
+                index
+            )),
+            None => Err(anyhow!(
+                "the manifest has {} root key(s)",
+                self.root_keys_sha256.len()
+            )),
+        }
+    }
+
+    pub fn digest_key(&self) -> anyhow::Result<Option<[u64; 2]>> {
+        let Some(digest_key) = &self.digest_key else {
+            return Ok(None);
+        };
+        let half = |range| {
+            digest_key
+                .get(range)
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow!("bad digest key {} in the manifest", digest_key))
+        };
+        if digest_key.len() != 32 {
+            return Err(anyhow!("bad digest key {} in the manifest", digest_key));
+        }
+        Ok(Some([half(0..16)?, half(16..32)?]))
+    }
+
+    /// Plans the recorded hooks on the stock image opened by `patcher` and returns
+    /// the patched file, checked against the recorded output hash.
+    pub fn replay(&self, patcher: &mut Patcher, root_keys: &[String]) -> anyhow::Result<Vec<u8>> {
+        let input_sha256 = sha256_hex(&fs::read(&patcher.file().path)?);
+        if input_sha256 == self.output_sha256 {
+            return Err(anyhow!(
+                "the image is already patched as the manifest records"
+            ));
+        }
+        if input_sha256 != self.input_sha256 {
+            return Err(anyhow!(
+                "the image is not the stock image of the manifest, its SHA-256 is {}",
+                input_sha256
+            ));
+        }
+        if root_keys.len() != self.root_keys_sha256.len() {
+            return Err(anyhow!(
+                "the manifest has {} root key(s), {} given",
+                self.root_keys_sha256.len(),
+                root_keys.len()
+            ));
+        }
+        for (index, root_key) in root_keys.iter().enumerate() {
+            self.check_root_key(index, root_key)?;
+        }
+
+        let cred_layout = CredLayout::detect(patcher.image());
+        let do_execve = self.hook("do_execve")?;
+        let avc_denied = self.hook("avc_denied")?;
+        patcher.patch_do_execve(DoExecveHook {
+            root_keys: root_keys.to_vec(),
+            allowed_uids: self
+                .allowed_uids
+                .iter()
+                .map(|uids| uids.parse())
+                .collect::<anyhow::Result<_>>()?,
+            digest_key: self.digest_key()?,
+            privileges: self.privileges,
+            hooker_entry: do_execve.hooker_entry,
+            hookee_entry: do_execve.hookee_entry,
+            cred_offset: self.cred_offset,
+            cred_layout,
+            seccomp_offset: self.seccomp_offset,
+        })?;
+        patcher.patch_avc_denied(AVCDeniedHook {
+            hooker_entry: avc_denied.hooker_entry,
+            hookee_entry: avc_denied.hookee_entry,
+            cred_offset: self.cred_offset,
+            cred_layout,
+        })?;
+        if let Ok(filldir64) = self.hook("filldir64") {
+            patcher.patch_filldir64(Filldir64Hook {
+                hooker_entry: filldir64.hooker_entry,
+                hookee_entry: filldir64.hookee_entry,
+                cred_offset: self.cred_offset,
+                cred_layout,
+                returns_bool: Filldir64Hook::returns_bool_for(KernelVersion::find(patcher.image())),
+            })?;
+        }
+
+        let patched = patcher.patched_file()?;
+        let output_sha256 = sha256_hex(&patched);
+        if output_sha256 != self.output_sha256 {
+            return Err(anyhow!(
+                "the replayed image differs from the manifest output, its SHA-256 is {} (manifest written by sk_patch {}, this is {})",
+                output_sha256,
+                self.tool_version,
+                TOOL_VERSION
+            ));
+        }
+        Ok(patched)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{do_execve_hook, test_kernel, TempPath};
+
+    #[test]
+    fn test_replay_manifest() {
+        let image_path = TempPath::new("replay_manifest");
+        let mut image = test_kernel(0x2000);
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let root_keys = vec!["c".repeat(48)];
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let avc_denied_entry = patcher
+            .patch_do_execve(DoExecveHook {
+                root_keys: root_keys.clone(),
+                ..do_execve_hook()
+            })
+            .unwrap();
+        let end = patcher
+            .patch_avc_denied(AVCDeniedHook {
+                hooker_entry: avc_denied_entry,
+                hookee_entry: 0x200,
+                cred_offset: 0x618,
+                cred_layout: CredLayout::DEFAULT,
+            })
+            .unwrap();
+        let patched = patcher.apply_patches().unwrap();
+        fs::remove_file(image_path.backup()).unwrap();
+
+        let manifest = Manifest {
+            tool_version: TOOL_VERSION.to_string(),
+            image: image_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            input_sha256: sha256_hex(&image),
+            output_sha256: sha256_hex(&patched),
+            root_keys_sha256: vec![sha256_hex(root_keys[0].as_bytes())],
+            root_keys: vec![],
+            allowed_uids: vec![],
+            digest_key: None,
+            privileges: Privileges::ROOT,
+            cred_offset: 0x618,
+            seccomp_offset: Some(0x800),
+            hooks: vec![
+                HookRecord {
+                    name: "do_execve".to_string(),
+                    hookee_entry: 0x100,
+                    hooker_entry: 0x1000,
+                    hooker_size: avc_denied_entry - 0x1000,
+                },
+                HookRecord {
+                    name: "avc_denied".to_string(),
+                    hookee_entry: 0x200,
+                    hooker_entry: avc_denied_entry,
+                    hooker_size: end - avc_denied_entry,
+                },
+            ],
+        };
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert!(!json.contains("\"root_keys\""));
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let e = manifest.replay(patcher, &root_keys).unwrap_err();
+        assert!(e.to_string().contains("already patched"));
+
+        fs::write(&image_path, &image).unwrap();
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        assert!(manifest.replay(patcher, &["d".repeat(48)]).is_err());
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        assert_eq!(manifest.replay(patcher, &root_keys).unwrap(), patched);
+
+        assert_eq!(manifest.digest_key().unwrap(), None);
+        let manifest = Manifest {
+            digest_key: Some("0706050403020100f0e0d0c0b0a09080".to_string()),
+            ..manifest
+        };
+        assert_eq!(
+            manifest.digest_key().unwrap(),
+            Some([0x0706050403020100, 0xf0e0d0c0b0a09080])
+        );
+        let manifest = Manifest {
+            digest_key: Some("07060504".to_string()),
+            ..manifest
+        };
+        assert!(manifest.digest_key().is_err());
+        assert_eq!(
+            Manifest::path_for(Path::new("out/boot.img")),
+            Path::new("out/boot.img.manifest.json")
+        );
+    }
+}
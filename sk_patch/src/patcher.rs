@@ -1,31 +1,39 @@
 use anyhow::{anyhow, Ok};
-use std::{
-    cell::RefCell,
-    fmt,
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
-};
+use std::{fmt, fs, ops::Range};
 
 use log::trace;
 
-use crate::{aarch64, asm_helper::asm_to_be_bytes, hook::*, LINE_ENDLING};
+use crate::{
+    aarch64,
+    asm_helper::{asm_to_assembly, asm_to_bytes},
+    hook::*,
+    installed::{find_installed_hooks, InstalledHook},
+    kernel_file::KernelFile,
+    relocate::{check_displaced, check_landing_pad, relocate},
+    LINE_ENDLING,
+};
 
 pub struct Patcher {
-    image: RefCell<File>,
-    image_path: PathBuf,
+    file: KernelFile,
     patches: Vec<PatchInfo>,
 }
 
-struct PatchInfo {
-    offset: usize,
-    bytes: Vec<u8>,
+pub struct PatchInfo {
+    /// What is patched, e.g. `do_execve hooker`.
+    pub name: String,
+    pub offset: usize,
+    pub bytes: Vec<u8>,
     check: bool,
+    /// Ranges of `bytes` holding data rather than instructions.
+    pub data: Vec<Range<usize>>,
+    /// Where in `bytes` the hookee's first instruction went, and the hookee entry.
+    pub relocated: Option<(usize, usize)>,
 }
 
 impl fmt::Debug for PatchInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PatchInfo")
+            .field("name", &self.name)
             .field("offset", &self.offset)
             .field("bytes_size", &self.bytes.len())
             .finish()
@@ -34,219 +42,384 @@ impl fmt::Debug for PatchInfo {
 
 impl Patcher {
     pub fn new(image_path: &str) -> anyhow::Result<Self> {
-        let image_path = PathBuf::from(image_path);
-        let image_file = File::options().read(true).write(true).open(&image_path)?;
-        trace!(
-            "image_path: {}, file metadata: {:#?}",
-            image_path.to_string_lossy(),
-            image_file.metadata()?
-        );
+        let file = KernelFile::open(image_path)?;
+        trace!("image_path: {}, container: {}", image_path, file.container);
         Ok(Self {
-            image: RefCell::new(image_file),
-            image_path,
+            file,
             patches: vec![],
         })
     }
 
-    pub fn patch_root_key(&mut self, root_key: &str, offset: usize) -> usize {
-        trace!("> patch_root_key");
-        let bytes = root_key.as_bytes();
-        let root_key_size = bytes.len();
-        self.add_patch(offset, bytes.to_vec(), true);
-        
-        offset + root_key_size
+    /// The kernel being patched, unpacked from its container.
+    pub fn image(&self) -> &[u8] {
+        &self.file.kernel
+    }
+
+    pub fn file(&self) -> &KernelFile {
+        &self.file
+    }
+
+    /// The patches planned so far, in the order they are applied.
+    pub fn patches(&self) -> &[PatchInfo] {
+        &self.patches
     }
 
     pub fn patch_do_execve(&mut self, hook: DoExecveHook) -> anyhow::Result<usize> {
         trace!("> patch_do_execve hook: {:#?}", hook);
-        let jump_back_addr = hook.hookee_entry + 4;
-        let jump_back_relative_offset = jump_back_addr as i64 - (hook.hooker_entry as i64 + 39 * 4);
-        let hooker_asm_text = aarch64! {
-            "MOV X0, X0";                                   // Reserved for hookee's first instruction
-            "STP X7, X8, [sp, #-16]!";
-            "STP X9, X10, [sp, #-16]!";
-            "STP X11, X12, [sp, #-16]!";
-            "MOV X7, 0xFFFFFFFFFFFFF001";                   // X7 = (unsigned long)(-MAX_ERRNO)
-            "CMP X1, X7";                                   // compare X1 and X7
-            "BCS #120";                                     // if X1 > X7 goto end
-            "LDR X7, [X1]";                                 // X7 = *X1
-            "CBZ X7, #112";                                 // if X7 == 0 goto end
-            "ADR X8, #-84";                                 // X8 = &root_key
-            "MOV X9, #0";                                   // X9 = 0
-            "LDRB W10, [X7, X9]";                           // W10 = *(X7 + X9)	#label1
-            "CBZ W10, #96";                                 // if W10 == 0 goto end
-            "LDRB W11, [X8, X9]";                           // W11 = *(X8 + X9)
-            "CBZ W11, #88";                                 // if W11 == 0 goto end
-            "CMP W10, W11";                                 // compare W10 and W11
-            "B.NE #80";                                     // if W10 != W11 goto end
-            "ADD X9, X9, 1";                                // X9 += 1
-            "CMP X9, #{}", hook.root_key_size;              // compare X9 and strlen(root_key)
-            "BLT #-32";                                     // if X9 < strlen(root_key) goto #label1
-            "MRS X8, SP_EL0";                               // X8 = (struct task_struct *) current_thread_info()
-            "LDR X10, [X8, #{}]", hook.cred_offset;         // X10 = X8->cred
-            "MOV X7, #4";                                   // X7 = 4
-            "MOV W9, WZR";                                  // W9 = 0
-            "STR W9, [X10, X7]";                            // *(X10 + X7) = W9	#label2
-            "ADD X7, X7, 4";                                // X7 += 4
-            "CMP X7, #40";                                  // compare X7 and 40
-            "BLT #-12";                                     // if X7 < 40 goto label2
-            "MOV W9, 0xFFFFFFFF";                           // W9 = 0xFFFFFFFF
-            "CMP X7, #80";                                  // compare X7 and 80
-            "BLT #-24";                                     // if X7 < 80 goto label2
-            "LDXR W10, [X8]";                               // W10 = *X8
-            "BIC W10, W10,#0xFFF";                          // X10 = X10 & ~(0xFFF)
-            "STXR W11, W10, [X8]";                          // *X8 = W10
-            "STR WZR, [X8, #{}]", hook.seccomp_offset;      // X8->seccomp.mode = 0
-            "STR XZR, [X8, #{}]", hook.seccomp_offset + 8;  // X8->seccomp.filter = 0
-            "LDP X11, X12, [sp], #16";                      // end
-            "LDP X9, X10, [sp], #16";
-            "LDP X7, X8, [sp], #16";
-            "B #{}", jump_back_relative_offset;
-        };
-
-        let hooker_size = self.add_hooker_patch(&hook, 0, asm_to_be_bytes(&hooker_asm_text)?)?;
-        self.add_hookee_patch(&hook)?;
-        let next_offset = hook.hooker_entry + hooker_size;
-        Ok(next_offset)
+        if hook.root_keys.is_empty() {
+            return Err(anyhow!("the do_execve hook needs a root key"));
+        }
+        self.patch_hook(&hook.spec())
     }
 
     pub fn patch_avc_denied(&mut self, hook: AVCDeniedHook) -> anyhow::Result<usize> {
         trace!("> patch_avc_denied hook: {:#?}", hook);
-        let jump_back_addr = hook.hookee_entry + 4;
-        let jump_back_relative_offset = jump_back_addr as i64 - (hook.hooker_entry as i64 + 28 * 4);
-        let hooker_asm_text = aarch64! {
-            "STP X9, X10, [sp, #-16]!";
-            "STP X7, X8, [sp, #-16]!";
-            "MRS X7, SP_EL0";			            // X7 = (struct task_struct *) current_thread_info()
-            "LDR X7, [X7, #{}]", hook.cred_offset;  // X7 = X7->cred
-            "CBZ X7, #84";			                // if X7 == 0 goto end1
-            "MOV X8, #4";				            // X8 = 4
-            "MOV W9, WZR";			                // W9 = 0
-            "LDR W10, [X7, X8]";		            // W10 = *(X7 + X8)	#label1
-            "CMP W10, W9";			                // compare W10 and W9
-            "B.NE #64"; 				            // if W10 != W9 goto end1
-            "ADD X8, X8, 4";			            // X8 += 4
-            "CMP X8, #36";			                // compare X8 and 36
-            "BLT #-20";				                // if X8 < 36 goto label1
-            "ADD X8, X8, 12";			            // X8 += 12
-            "MOV X9, 0x3FFFFFFFFF";	                // X9 = 0x3FFFFFFFFF
-            "LDR X10, [X7, X8]";		            // X10 = *(X7 + X8)	#label2
-            "ADD X8, X8, 8";			            // X8 += 8
-            "CMP X10, X9";			                // compare X10 and X9
-            "B.CC #28";				                // if X10 < X9 goto end1
-            "CMP X8, #72";			                // compare X8 and 72
-            "BLT #-20";				                // if X8 < 72 goto label2
-            "LDP X9, X10, [sp], #16";	            // end2
-            "LDP X7, X8, [sp], #16";
-            "MOV W0, WZR";
-            "RET";
-            "LDP X9, X10, [sp], #16";               // end1
-            "LDP X7, X8, [sp], #16";
-            "MOV X0, X0"; 			                // Reserved for hookee's first instruction
-            "B #{}", jump_back_relative_offset;
-        };
+        self.patch_hook(&hook.spec())
+    }
 
-        let hooker_size =
-            self.add_hooker_patch(&hook, 27 * 4, asm_to_be_bytes(&hooker_asm_text)?)?;
-        self.add_hookee_patch(&hook)?;
-        let next_offset = hook.hooker_entry + hooker_size;
-        Ok(next_offset)
+    pub fn patch_filldir64(&mut self, hook: Filldir64Hook) -> anyhow::Result<usize> {
+        trace!("> patch_filldir64 hook: {:#?}", hook);
+        self.patch_hook(&hook.spec())
     }
 
-    pub fn apply_patches(&mut self) -> anyhow::Result<()> {
-        self.backup_image()?;
+    /// Plans the hooker and the branch to it, returns the offset right after the hooker.
+    pub fn patch_hook(&mut self, spec: &HookSpec) -> anyhow::Result<usize> {
+        let hooker_size = self.add_hooker_patch(spec)?;
+        self.add_hookee_patch(spec)?;
+        Ok(spec.hooker_entry + hooker_size)
+    }
+
+    /// Plans restoring the hookee entries of the installed hooks and zeroing their hookers.
+    pub fn unpatch(&mut self) -> anyhow::Result<Vec<InstalledHook>> {
+        let hooks = find_installed_hooks(&self.file.kernel)?;
+        for hook in &hooks {
+            self.patches.push(PatchInfo {
+                name: format!("{} entry", hook.name),
+                offset: hook.hookee_entry,
+                bytes: hook.original.to_le_bytes().to_vec(),
+                check: false,
+                data: vec![],
+                relocated: None,
+            });
+            self.patches.push(PatchInfo {
+                name: format!("{} hooker", hook.name),
+                offset: hook.start(),
+                bytes: vec![0; hook.end() - hook.start()],
+                check: false,
+                data: vec![],
+                relocated: None,
+            });
+        }
+        Ok(hooks)
+    }
+
+    /// The file contents with the planned patches made.
+    pub fn patched_file(&self) -> anyhow::Result<Vec<u8>> {
+        let mut image = self.file.kernel.clone();
         for patch in &self.patches {
             trace!("patch: {:#?}", patch);
-            self.write_bytes(patch.offset, &patch.bytes, patch.check)?;
+            write_bytes(&mut image, patch.offset, &patch.bytes, patch.check)?;
         }
-        self.flush()?;
-        Ok(())
+        self.file.pack(&image)
     }
 
-    fn add_hooker_patch(
-        &mut self,
-        hook: &impl Hook,
-        reserved_start: usize,
-        mut hooker_bytes: Vec<u8>,
-    ) -> anyhow::Result<usize> {
-        let backup_hookee_entry_bytes = self.read_bytes(hook.hookee_entry(), 4)?;
+    /// Backs up the file and writes the patches to it, returns the written contents.
+    pub fn apply_patches(&mut self) -> anyhow::Result<Vec<u8>> {
+        // Packed first, so nothing is written when the patches do not fit the file.
+        let bytes = self.patched_file()?;
+        self.backup_image()?;
+        fs::write(&self.file.path, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// Assembles the hooker at its entry and places the hookee's first instruction,
+    /// relocated if it is PC-relative, at its `original:` label.
+    fn add_hooker_patch(&mut self, hook: &HookSpec) -> anyhow::Result<usize> {
+        let assembly = asm_to_assembly(&hook.asm(), hook.hooker_entry)?;
+        let reserved_start = assembly.offset_of("original")?;
+        let mut hooker_bytes = assembly.bytes;
+        let backup_hookee_entry_bytes = self.read_bytes(hook.hookee_entry, 4)?;
         trace!(
             "backup_hookee_entry_bytes: {:?}",
             &backup_hookee_entry_bytes
         );
+        let original = u32::from_le_bytes(backup_hookee_entry_bytes.try_into().unwrap());
+        check_displaced(original, hook.hookee_entry, hook.original_at)
+            .map_err(|e| anyhow!("{} entry: {}", hook.name, e))?;
+        if hook.called_indirectly {
+            check_landing_pad(original, hook.hookee_entry)
+                .map_err(|e| anyhow!("{} entry: {}", hook.name, e))?;
+        }
+        let relocated = relocate(
+            original,
+            hook.hookee_entry,
+            hook.hooker_entry + reserved_start,
+        )
+        .map_err(|e| anyhow!("{} entry: {}", hook.name, e))?;
         hooker_bytes.splice(
             reserved_start..(reserved_start + 4),
-            backup_hookee_entry_bytes,
+            relocated.to_le_bytes(),
         );
         let hooker_size = hooker_bytes.len();
-        self.add_patch(hook.hooker_entry(), hooker_bytes, true);
+        self.patches.push(PatchInfo {
+            name: format!("{} hooker", hook.name),
+            offset: hook.hooker_entry,
+            bytes: hooker_bytes,
+            check: true,
+            data: assembly.data,
+            relocated: Some((reserved_start, hook.hookee_entry)),
+        });
         Ok(hooker_size)
     }
 
-    fn add_hookee_patch(&mut self, hook: &impl Hook) -> anyhow::Result<()> {
-        let jump_to_hooker_relative_offset =
-            hook.hooker_entry() as i64 - hook.hookee_entry() as i64;
+    fn add_hookee_patch(&mut self, hook: &HookSpec) -> anyhow::Result<()> {
+        let jump_to_hooker_relative_offset = hook.hooker_entry as i64 - hook.hookee_entry as i64;
         let jump_to_hooker_asm_text = aarch64!("B #{}", jump_to_hooker_relative_offset);
-        let jump_to_hooker_be_bytes = asm_to_be_bytes(&jump_to_hooker_asm_text)?;
-        self.add_patch(hook.hookee_entry(), jump_to_hooker_be_bytes, false);
-        Ok(())
-    }
-
-    fn add_patch(&mut self, offset: usize, bytes: Vec<u8>, check: bool) {
+        let jump_to_hooker_bytes = asm_to_bytes(&jump_to_hooker_asm_text)?;
         self.patches.push(PatchInfo {
-            offset,
-            bytes,
-            check,
-        })
+            name: format!("{} entry", hook.name),
+            offset: hook.hookee_entry,
+            bytes: jump_to_hooker_bytes,
+            check: false,
+            data: vec![],
+            relocated: None,
+        });
+        Ok(())
     }
 
     fn backup_image(&self) -> anyhow::Result<()> {
-        let image_file_name = self.image_path.file_name().unwrap().to_string_lossy();
-        let image_parent_dir = self.image_path.parent().unwrap();
+        let image_path = &self.file.path;
+        let image_file_name = image_path.file_name().unwrap().to_string_lossy();
+        let image_parent_dir = image_path.parent().unwrap();
         let backup_file_name = image_file_name + ".bak";
         let backup_image_path = image_parent_dir.join(backup_file_name.to_string());
         if !backup_image_path.exists() {
             println!(
                 "Backup image: {} -> {}",
-                &self.image_path.to_string_lossy(),
+                &image_path.to_string_lossy(),
                 &backup_image_path.to_string_lossy()
             );
-            fs::copy(&self.image_path, backup_image_path)?;
+            fs::copy(image_path, backup_image_path)?;
         }
         Ok(())
     }
 
     fn read_bytes(&self, offset: usize, size: usize) -> anyhow::Result<Vec<u8>> {
-        let mut image = self.image.borrow_mut();
-        image.seek(SeekFrom::Start(offset as u64))?;
-        let mut buf = vec![0u8; size];
-        image.read_exact(&mut buf)?;
-        Ok(buf)
-    }
-
-    fn write_bytes(&self, offset: usize, buf: &[u8], check: bool) -> anyhow::Result<usize> {
-        fn ensure_all_zero(image: &mut File, size: usize) -> anyhow::Result<()> {
-            let bak_pos = image.stream_position()?;
-            let mut tmp = vec![0u8; size];
-            image.read_exact(&mut tmp)?;
-            if tmp.iter().any(|&b| b != 0) {
-                return Err(anyhow!("Image offset: {} has non-zero data", bak_pos));
-            }
-            image.seek(SeekFrom::Start(bak_pos))?;
-            Ok(())
-        }
+        self.file
+            .kernel
+            .get(offset..offset + size)
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(|| anyhow!("Image offset: {} is out of range", offset))
+    }
+}
 
-        let image = &mut self.image.borrow_mut();
-        image.seek(SeekFrom::Start(offset as u64))?;
-        if check {
-            let buf_size = buf.len();
-            ensure_all_zero(image, buf_size)?;
-        }
-        trace!("write offset: {}, size: {}", offset, buf.len());
-        Ok(image.write(buf)?)
+fn write_bytes(image: &mut [u8], offset: usize, buf: &[u8], check: bool) -> anyhow::Result<usize> {
+    let target = image
+        .get_mut(offset..offset + buf.len())
+        .ok_or_else(|| anyhow!("Image offset: {} is out of range", offset))?;
+    if check && target.iter().any(|&b| b != 0) {
+        return Err(anyhow!("Image offset: {} has non-zero data", offset));
+    }
+    trace!("write offset: {}, size: {}", offset, buf.len());
+    target.copy_from_slice(buf);
+    Ok(buf.len())
+}
+
+/// Bytes the hookers take once assembled, root keys included.
+pub fn hookers_size(hooks: &[&dyn Hook]) -> anyhow::Result<usize> {
+    hooks
+        .iter()
+        .map(|hook| {
+            let spec = hook.spec();
+            Ok(asm_to_assembly(&spec.asm(), spec.hooker_entry)?.bytes.len())
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        cred_layout::CredLayout,
+        test_util::{do_execve_hook, test_kernel, TempPath},
+    };
+
+    fn word_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn branch_target(inst: u32, pc: usize) -> usize {
+        assert_eq!(inst >> 26, 0b000101, "not a B instruction: {:#x}", inst);
+        let imm26 = ((inst << 6) as i32) >> 6;
+        (pc as i64 + imm26 as i64 * 4) as usize
     }
 
-    fn flush(&self) -> anyhow::Result<()> {
-        Ok(self.image.borrow_mut().flush()?)
+    #[test]
+    fn test_patch_hooks_layout() {
+        let image_path = TempPath::new("hooks_layout");
+        let mut image = test_kernel(0x2000);
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let root_key = "a".repeat(48);
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let do_execve = do_execve_hook();
+        let do_execve_assembly = asm_to_assembly(&do_execve.spec().asm(), 0x1000).unwrap();
+        let next_offset = patcher.patch_do_execve(do_execve).unwrap();
+        assert_eq!(next_offset % 4, 0);
+        let avc_denied = AVCDeniedHook {
+            hooker_entry: next_offset,
+            hookee_entry: 0x200,
+            cred_offset: 0x618,
+            cred_layout: CredLayout::DEFAULT,
+        };
+        let avc_denied_original = asm_to_assembly(&avc_denied.spec().asm(), next_offset)
+            .unwrap()
+            .offset_of("original")
+            .unwrap();
+        let end = patcher.patch_avc_denied(avc_denied).unwrap();
+
+        let do_execve_hooker = &patcher.patches[0].bytes;
+        let original = do_execve_assembly.offset_of("original").unwrap();
+        assert_eq!(word_at(do_execve_hooker, original), 0xD10103FF);
+        // `B jump_back` ends the instructions, the root key follows it.
+        let root_key_at = do_execve_assembly.offset_of("root_key").unwrap();
+        let jump_back_at = root_key_at - 4;
+        assert_eq!(
+            branch_target(
+                word_at(do_execve_hooker, jump_back_at),
+                0x1000 + jump_back_at
+            ),
+            0x104
+        );
+        assert_eq!(
+            &do_execve_hooker[root_key_at..root_key_at + 48],
+            root_key.as_bytes()
+        );
+        assert_eq!(
+            branch_target(word_at(&patcher.patches[1].bytes, 0), 0x100),
+            0x1000
+        );
+
+        let avc_denied_hooker = &patcher.patches[2].bytes;
+        assert_eq!(avc_denied_hooker.len(), end - next_offset);
+        assert_eq!(word_at(avc_denied_hooker, avc_denied_original), 0xA9BF7BFD);
+        // With OriginalAt::Exit, `B jump_back` comes right after the original.
+        let jump_back_at = avc_denied_original + 4;
+        assert_eq!(
+            branch_target(
+                word_at(avc_denied_hooker, jump_back_at),
+                next_offset + jump_back_at
+            ),
+            0x204
+        );
+        assert_eq!(
+            branch_target(word_at(&patcher.patches[3].bytes, 0), 0x200),
+            next_offset
+        );
+    }
+
+    #[test]
+    fn test_refuse_branch_before_body() {
+        let image_path = TempPath::new("branch_before_body");
+        let mut image = test_kernel(0x2000);
+        // B #0x40 at both entries.
+        image[0x100..0x104].copy_from_slice(&0x14000010u32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0x14000010u32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let e = patcher.patch_do_execve(do_execve_hook()).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("branches away before the hook body runs"));
+        let e = patcher
+            .patch_do_execve(DoExecveHook {
+                root_keys: vec![],
+                ..do_execve_hook()
+            })
+            .unwrap_err();
+        assert!(e.to_string().contains("needs a root key"));
+        // The avc_denied hooker runs it last, relocated.
+        patcher
+            .patch_avc_denied(AVCDeniedHook {
+                hooker_entry: 0x1000,
+                hookee_entry: 0x200,
+                cred_offset: 0x618,
+                cred_layout: CredLayout::DEFAULT,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_refuse_filldir64_landing_pad() {
+        let image_path = TempPath::new("filldir64_landing_pad");
+        let mut image = test_kernel(0x2000);
+        // BTI c at the filldir64 entry, PACIASP at the avc_denied one.
+        image[0x300..0x304].copy_from_slice(&0xD503245Fu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xD503233Fu32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let e = patcher
+            .patch_filldir64(Filldir64Hook {
+                hooker_entry: 0x1000,
+                hookee_entry: 0x300,
+                cred_offset: 0x618,
+                cred_layout: CredLayout::DEFAULT,
+                returns_bool: true,
+            })
+            .unwrap_err();
+        assert!(e.to_string().contains("landing pad of indirect calls"));
+        // avc_denied is called directly, its landing pad is no concern.
+        patcher
+            .patch_avc_denied(AVCDeniedHook {
+                hooker_entry: 0x1000,
+                hookee_entry: 0x200,
+                cred_offset: 0x618,
+                cred_layout: CredLayout::DEFAULT,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unpatch() {
+        let image_path = TempPath::new("unpatch");
+        let mut image = test_kernel(0x2000);
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        image[0x200..0x204].copy_from_slice(&0xA9BF7BFDu32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let root_key = "b".repeat(48);
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let next_offset = patcher
+            .patch_do_execve(DoExecveHook {
+                root_keys: vec![root_key.clone()],
+                ..do_execve_hook()
+            })
+            .unwrap();
+        patcher
+            .patch_avc_denied(AVCDeniedHook {
+                hooker_entry: next_offset,
+                hookee_entry: 0x200,
+                cred_offset: 0x618,
+                cred_layout: CredLayout::DEFAULT,
+            })
+            .unwrap();
+        patcher.apply_patches().unwrap();
+        fs::remove_file(image_path.backup()).unwrap();
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        let hooks = patcher.unpatch().unwrap();
+        assert_eq!(hooks.len(), 2);
+        assert_eq!(hooks[0].hooker_size, next_offset - 0x1000);
+        assert_eq!(hooks[0].root_keys, vec![root_key]);
+        assert_eq!(hooks[1].hookee_entry, 0x200);
+        assert_eq!(hooks[1].original, 0xA9BF7BFD);
+        patcher.apply_patches().unwrap();
+        assert_eq!(fs::read(&image_path).unwrap(), image);
     }
 }
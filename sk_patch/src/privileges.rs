@@ -0,0 +1,49 @@
+//! What the do_execve hook grants: the ids and capabilities written into the cred of
+//! current and the thread flags cleared along with its seccomp.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Privileges {
+    /// Written to uid, suid, euid and fsuid.
+    pub uid: u32,
+    /// Written to gid, sgid, egid and fsgid.
+    pub gid: u32,
+    /// Written to the inheritable, permitted, effective, bounding and ambient sets.
+    pub capabilities: u64,
+    /// Bits cleared in `thread_info.flags` along with seccomp, none when it is kept. The
+    /// low ones hold TIF_SECCOMP and the syscall tracing flags.
+    pub thread_flags: u32,
+}
+
+impl Privileges {
+    /// Full root, what the hook has always granted.
+    pub const ROOT: Privileges = Privileges {
+        uid: 0,
+        gid: 0,
+        capabilities: u64::MAX,
+        thread_flags: 0xFFF,
+    };
+
+    pub fn is_root(&self) -> bool {
+        *self == Privileges::ROOT
+    }
+}
+
+impl Default for Privileges {
+    fn default() -> Self {
+        Privileges::ROOT
+    }
+}
+
+impl fmt::Display for Privileges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uid {}, gid {}, capabilities 0x{:x}, thread flags 0x{:x}",
+            self.uid, self.gid, self.capabilities, self.thread_flags
+        )
+    }
+}
@@ -0,0 +1,298 @@
+//! Patch inputs given up front, from command-line options or a TOML/JSON profile.
+//!
+//! Anything left unset is detected or asked for interactively.
+
+use std::{fs, path::Path};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Deserializer};
+
+use crate::{hex_to_usize, kallsyms::Kallsyms, privileges::Privileges};
+
+/// Start of the arm64 kernel half of the address space, for any VA_BITS.
+const KERNEL_SPACE: u64 = 0xFFFF_0000_0000_0000;
+
+/// A file offset written either as a number or as a string holding a hex offset or,
+/// for code locations, a kernel virtual address or a kallsyms symbol name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Location {
+    Offset(usize),
+    Text(String),
+}
+
+impl Location {
+    /// Resolves a plain offset, the kallsyms symbol names are not accepted.
+    pub fn offset(&self) -> anyhow::Result<usize> {
+        match self {
+            Location::Offset(offset) => Ok(*offset),
+            Location::Text(text) => hex_to_usize(text),
+        }
+    }
+
+    /// Resolves an offset, a kernel virtual address or a kallsyms symbol name.
+    pub fn resolve(&self, kallsyms: Option<&Kallsyms>) -> anyhow::Result<usize> {
+        match (self, kallsyms) {
+            (Location::Text(name), Some(kallsyms)) if kallsyms.lookup(name).is_some() => kallsyms
+                .file_offset(name)
+                .ok_or_else(|| anyhow!("{} is not inside the Image", name)),
+            _ => {
+                let offset = self.offset()?;
+                if (offset as u64) < KERNEL_SPACE {
+                    return Ok(offset);
+                }
+                let kallsyms = kallsyms.ok_or_else(|| {
+                    anyhow!(
+                        "0x{:x} is a kernel virtual address, which needs kallsyms to be converted to a file offset",
+                        offset
+                    )
+                })?;
+                kallsyms
+                    .to_file_offset(offset as u64)
+                    .ok_or_else(|| anyhow!("0x{:x} is below the Image", offset))
+            }
+        }
+    }
+}
+
+/// Accepts a single string as well as a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(
+        Option::<OneOrMany>::deserialize(deserializer)?.map(|value| match value {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub image: Option<String>,
+    pub cave_offset: Option<Location>,
+    /// One root key or a list of them.
+    #[serde(deserialize_with = "one_or_many")]
+    pub root_key: Option<Vec<String>>,
+    /// Uid ranges allowed to use the root keys, see [`crate::uid_range`].
+    #[serde(deserialize_with = "one_or_many")]
+    pub allow_uid: Option<Vec<String>>,
+    /// Store a keyed digest of the root keys in the kernel instead of the keys.
+    pub key_digest: Option<bool>,
+    /// The ids granted instead of root's, the gid defaults to the uid.
+    pub grant_uid: Option<u32>,
+    pub grant_gid: Option<u32>,
+    /// Hex mask written to every capability set.
+    pub capabilities: Option<String>,
+    /// Leave seccomp and the thread flags of the granted process in place.
+    pub keep_seccomp: Option<bool>,
+    /// Hex mask of the thread flags cleared along with seccomp, none are with `keep_seccomp`.
+    pub thread_flags: Option<String>,
+    pub generate_root_key: Option<bool>,
+    pub cred_offset: Option<Location>,
+    pub seccomp_offset: Option<Location>,
+    pub do_execve: Option<Location>,
+    pub avc_denied: Option<Location>,
+    /// Hook filldir64 to hide the `su_*` directories from processes which are not root,
+    /// wherever they are.
+    pub hide_su_dirs: Option<bool>,
+    pub filldir64: Option<Location>,
+    pub apply: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// Path of the JSON patch report.
+    pub report: Option<String>,
+    /// Store the root key in the manifest, not only its fingerprint.
+    pub manifest_key: Option<bool>,
+    /// Use the detected value for anything unset instead of prompting.
+    pub non_interactive: Option<bool>,
+}
+
+impl Profile {
+    /// Loads a profile, as JSON for `.json` files and as TOML otherwise.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read profile {}: {}", path.display(), e))?;
+        let profile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        Ok(profile)
+    }
+
+    /// Fills the values unset in `self` from `fallback`.
+    pub fn or(self, fallback: Profile) -> Profile {
+        Profile {
+            image: self.image.or(fallback.image),
+            cave_offset: self.cave_offset.or(fallback.cave_offset),
+            root_key: self.root_key.or(fallback.root_key),
+            allow_uid: self.allow_uid.or(fallback.allow_uid),
+            key_digest: self.key_digest.or(fallback.key_digest),
+            grant_uid: self.grant_uid.or(fallback.grant_uid),
+            grant_gid: self.grant_gid.or(fallback.grant_gid),
+            capabilities: self.capabilities.or(fallback.capabilities),
+            keep_seccomp: self.keep_seccomp.or(fallback.keep_seccomp),
+            thread_flags: self.thread_flags.or(fallback.thread_flags),
+            generate_root_key: self.generate_root_key.or(fallback.generate_root_key),
+            cred_offset: self.cred_offset.or(fallback.cred_offset),
+            seccomp_offset: self.seccomp_offset.or(fallback.seccomp_offset),
+            do_execve: self.do_execve.or(fallback.do_execve),
+            avc_denied: self.avc_denied.or(fallback.avc_denied),
+            hide_su_dirs: self.hide_su_dirs.or(fallback.hide_su_dirs),
+            filldir64: self.filldir64.or(fallback.filldir64),
+            apply: self.apply.or(fallback.apply),
+            dry_run: self.dry_run.or(fallback.dry_run),
+            report: self.report.or(fallback.report),
+            manifest_key: self.manifest_key.or(fallback.manifest_key),
+            non_interactive: self.non_interactive.or(fallback.non_interactive),
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.unwrap_or(false)
+    }
+
+    /// The privileges the do_execve hook grants, full root for anything unset.
+    pub fn privileges(&self) -> anyhow::Result<Privileges> {
+        let root = Privileges::ROOT;
+        let thread_flags = match (&self.thread_flags, self.keep_seccomp) {
+            (Some(mask), Some(true)) => {
+                return Err(anyhow!(
+                    "thread flags {} are cleared along with seccomp, which is kept",
+                    mask
+                ))
+            }
+            (None, Some(true)) => 0,
+            (Some(mask), _) => u32::try_from(hex_to_usize(mask)?)
+                .map_err(|_| anyhow!("thread flags {} do not fit in 32 bits", mask))?,
+            (None, _) => root.thread_flags,
+        };
+        Ok(Privileges {
+            uid: self.grant_uid.unwrap_or(root.uid),
+            gid: self.grant_gid.or(self.grant_uid).unwrap_or(root.gid),
+            capabilities: match &self.capabilities {
+                Some(mask) => hex_to_usize(mask)? as u64,
+                None => root.capabilities,
+            },
+            thread_flags,
+        })
+    }
+
+    pub fn is_key_digest(&self) -> bool {
+        self.key_digest.unwrap_or(false)
+    }
+
+    pub fn is_hide_su_dirs(&self) -> bool {
+        self.hide_su_dirs.unwrap_or(false)
+    }
+
+    pub fn is_manifest_key(&self) -> bool {
+        self.manifest_key.unwrap_or(false)
+    }
+
+    pub fn is_non_interactive(&self) -> bool {
+        self.non_interactive.unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let from_toml: Profile = toml::from_str(
+            r#"
+                image = "boot_kernel"
+                cave_offset = 0x2010
+                generate_root_key = true
+                cred_offset = "0x730"
+                seccomp_offset = "ab0"
+                do_execve = "do_execveat_common"
+                apply = true
+            "#,
+        )
+        .unwrap();
+        let from_json: Profile = serde_json::from_str(
+            r#"{
+                "image": "boot_kernel",
+                "cave_offset": 8208,
+                "generate_root_key": true,
+                "cred_offset": "0x730",
+                "seccomp_offset": "ab0",
+                "do_execve": "do_execveat_common",
+                "apply": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(from_toml, from_json);
+        assert_eq!(from_toml.cave_offset.unwrap().offset().unwrap(), 0x2010);
+        assert_eq!(from_toml.cred_offset.unwrap().offset().unwrap(), 0x730);
+        assert_eq!(from_toml.seccomp_offset.unwrap().offset().unwrap(), 0xAB0);
+        assert!(from_toml.do_execve.unwrap().offset().is_err());
+        assert_eq!(from_toml.avc_denied, None);
+        assert!(toml::from_str::<Profile>("cave = 1").is_err());
+        let keys: Profile = toml::from_str(r#"root_key = ["a", "b"]"#).unwrap();
+        assert_eq!(keys.root_key, Some(vec!["a".to_string(), "b".to_string()]));
+        let key: Profile = serde_json::from_str(r#"{"root_key": "a"}"#).unwrap();
+        assert_eq!(key.root_key, Some(vec!["a".to_string()]));
+        assert!(key.privileges().unwrap().is_root());
+        let shell: Profile = toml::from_str(
+            r#"
+                grant_uid = 2000
+                capabilities = "0x1c0"
+                keep_seccomp = true
+            "#,
+        )
+        .unwrap();
+        let privileges = shell.privileges().unwrap();
+        assert_eq!((privileges.uid, privileges.gid), (2000, 2000));
+        assert_eq!(privileges.capabilities, 0x1C0);
+        assert_eq!(shell.keep_seccomp, Some(true));
+        assert_eq!(privileges.thread_flags, 0);
+        let flags: Profile = toml::from_str(
+            r#"
+                keep_seccomp = true
+                thread_flags = "0x100"
+            "#,
+        )
+        .unwrap();
+        assert!(flags
+            .privileges()
+            .unwrap_err()
+            .to_string()
+            .contains("seccomp, which is kept"));
+        let address = Location::Text("0xffffffc008010000".to_string());
+        assert!(address
+            .resolve(None)
+            .unwrap_err()
+            .to_string()
+            .contains("kallsyms"));
+    }
+
+    #[test]
+    fn test_profile_or() {
+        let options = Profile {
+            cred_offset: Some(Location::Offset(0x738)),
+            apply: Some(false),
+            ..Default::default()
+        };
+        let profile = Profile {
+            image: Some("raw_kernel".to_string()),
+            cred_offset: Some(Location::Offset(0x730)),
+            apply: Some(true),
+            ..Default::default()
+        };
+        let merged = options.or(profile);
+        assert_eq!(merged.image.as_deref(), Some("raw_kernel"));
+        assert_eq!(merged.cred_offset, Some(Location::Offset(0x738)));
+        assert_eq!(merged.apply, Some(false));
+        assert!(!merged.is_non_interactive());
+    }
+}
@@ -0,0 +1,195 @@
+//! Moves a PC-relative instruction to another address, keeping what it refers to.
+//!
+//! The hookee's first instruction runs from the hooker's `original:` slot, so branches,
+//! `ADR`/`ADRP` and literal loads get their offset re-encoded for the new address.
+
+use anyhow::anyhow;
+
+use crate::{
+    disassembler::{decode, Inst},
+    hook::OriginalAt,
+};
+
+/// Where the PC-relative immediate of an instruction is.
+enum Imm {
+    /// A signed offset in words, `len` bits from bit `lo`.
+    Words { lo: u32, len: u32 },
+    /// The 21 bit offset of `ADR` split into `immlo` and `immhi`, in pages for `ADRP`.
+    Adr { page: bool },
+}
+
+impl Imm {
+    fn of(word: u32) -> Option<Imm> {
+        if word & 0x7C000000 == 0x14000000 {
+            // B, BL
+            Some(Imm::Words { lo: 0, len: 26 })
+        } else if word & 0xFF000010 == 0x54000000
+            || word & 0x7E000000 == 0x34000000
+            || word & 0x3B000000 == 0x18000000
+        {
+            // B.cond, CBZ/CBNZ, LDR (literal) of any register kind and PRFM (literal)
+            Some(Imm::Words { lo: 5, len: 19 })
+        } else if word & 0x7E000000 == 0x36000000 {
+            // TBZ/TBNZ
+            Some(Imm::Words { lo: 5, len: 14 })
+        } else if word & 0x1F000000 == 0x10000000 {
+            Some(Imm::Adr {
+                page: word >> 31 == 1,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn len(&self) -> u32 {
+        match self {
+            Imm::Words { len, .. } => *len,
+            Imm::Adr { .. } => 21,
+        }
+    }
+
+    fn get(&self, word: u32) -> i64 {
+        let raw = match self {
+            Imm::Words { lo, len } => (word >> lo) & ((1 << len) - 1),
+            Imm::Adr { .. } => ((word >> 5) & 0x7FFFF) << 2 | (word >> 29) & 3,
+        };
+        let unused = 64 - self.len();
+        ((raw as i64) << unused) >> unused
+    }
+
+    fn set(&self, word: u32, imm: i64) -> Option<u32> {
+        let half = 1i64 << (self.len() - 1);
+        if imm < -half || imm >= half {
+            return None;
+        }
+        let imm = imm as u32;
+        Some(match self {
+            Imm::Words { lo, len } => {
+                let mask = ((1 << len) - 1) << lo;
+                (word & !mask) | ((imm << lo) & mask)
+            }
+            Imm::Adr { .. } => (word & !0x60FFFFE0) | (imm & 3) << 29 | ((imm >> 2) & 0x7FFFF) << 5,
+        })
+    }
+}
+
+/// Checks that `word`, the hookee's first instruction at `pc`, still does its work from
+/// the `original:` slot at `original_at`. Run before the body, a branch which may be taken
+/// leaves the hooker and the hook never runs; calls come back and are fine.
+pub fn check_displaced(word: u32, pc: usize, original_at: OriginalAt) -> anyhow::Result<()> {
+    let inst = decode(word, pc as u64);
+    let leaves = match inst {
+        Inst::Branch { link, .. } => !link,
+        Inst::BranchCond { .. } | Inst::CompareBranch { .. } | Inst::TestBranch { .. } => true,
+        // BR and RET, BLR returns.
+        Inst::BranchReg { opc, .. } => opc != 1,
+        _ => false,
+    };
+    if leaves && original_at == OriginalAt::Entry {
+        return Err(anyhow!(
+            "first instruction at 0x{:x} ({}) branches away before the hook body runs",
+            pc,
+            inst
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `word`, the first instruction at `pc` of a hookee only called through
+/// function pointers, is not their landing pad: the `B` replacing it is none, so every
+/// call faults on a kernel built with `CONFIG_ARM64_BTI_KERNEL`.
+pub fn check_landing_pad(word: u32, pc: usize) -> anyhow::Result<()> {
+    let inst = decode(word, pc as u64);
+    if inst.is_landing_pad() {
+        return Err(anyhow!(
+            "first instruction at 0x{:x} ({}) is the landing pad of indirect calls",
+            pc,
+            inst
+        ));
+    }
+    Ok(())
+}
+
+/// Re-encodes `word`, placed at file offset `from`, to run at `to` with the same target.
+/// Instructions which are not PC-relative are returned unchanged.
+pub fn relocate(word: u32, from: usize, to: usize) -> anyhow::Result<u32> {
+    let imm = match Imm::of(word) {
+        Some(imm) => imm,
+        None => return Ok(word),
+    };
+    let (from, to) = (from as i64, to as i64);
+    let moved = match imm {
+        Imm::Words { .. } => (from - to) / 4,
+        Imm::Adr { page: false } => from - to,
+        Imm::Adr { page: true } => (from >> 12) - (to >> 12),
+    };
+    imm.set(word, imm.get(word) + moved).ok_or_else(|| {
+        anyhow!(
+            "{} at 0x{:x} cannot be relocated to 0x{:x}, its target is out of reach",
+            decode(word, from as u64),
+            from,
+            to
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble_at;
+
+    #[test]
+    fn test_relocate() {
+        let code = assemble_at(
+            "ADRP X0, #0x10000\nADR X1, #0x80\nB #0x100\nBL #-0x100\nB.NE #0x20\nCBZ X2, #0x40\nTBNZ W3, #5, #0x10\nLDR X4, #0x200\nSTP X29, X30, [SP, #-16]!",
+            0x10000,
+        )
+        .unwrap()
+        .bytes;
+        for (i, word) in code.chunks(4).enumerate() {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            let from = 0x10000 + i * 4;
+            for to in [0x10800, 0xC000, 0x12FF8] {
+                let relocated = relocate(word, from, to).unwrap();
+                assert_eq!(
+                    decode(relocated, to as u64).to_string(),
+                    decode(word, from as u64).to_string()
+                );
+            }
+        }
+
+        let tbnz = u32::from_le_bytes(code[24..28].try_into().unwrap());
+        let e = relocate(tbnz, 0x10018, 0x200000).unwrap_err();
+        assert!(e.to_string().contains("out of reach"));
+        assert!(relocate(0x14000000, 0x8000000, 0).is_err());
+    }
+
+    #[test]
+    fn test_check_displaced() {
+        let code = assemble_at("B #0x100\nB.NE #0x20\nRET\nBL #0x100\nADRP X0, #0x1000", 0)
+            .unwrap()
+            .bytes;
+        let word = |i: usize| u32::from_le_bytes(code[i * 4..i * 4 + 4].try_into().unwrap());
+        for i in 0..3 {
+            let e = check_displaced(word(i), i * 4, OriginalAt::Entry).unwrap_err();
+            assert!(e.to_string().contains("branches away"));
+            assert!(check_displaced(word(i), i * 4, OriginalAt::Exit).is_ok());
+        }
+        assert!(check_displaced(word(3), 12, OriginalAt::Entry).is_ok());
+        assert!(check_displaced(word(4), 16, OriginalAt::Entry).is_ok());
+    }
+
+    #[test]
+    fn test_check_landing_pad() {
+        // BTI c, PACIASP and a plain prologue.
+        let code = assemble_at("HINT #34\nPACIASP\nSTP X29, X30, [SP, #-16]!", 0)
+            .unwrap()
+            .bytes;
+        let word = |i: usize| u32::from_le_bytes(code[i * 4..i * 4 + 4].try_into().unwrap());
+        for i in 0..2 {
+            let e = check_landing_pad(word(i), i * 4).unwrap_err();
+            assert!(e.to_string().contains("landing pad"));
+        }
+        assert!(check_landing_pad(word(2), 8).is_ok());
+    }
+}
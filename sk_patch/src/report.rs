@@ -0,0 +1,226 @@
+//! Dry-run report of the planned patches, with the code before and after each of them.
+
+use std::{fmt, fs, path::Path};
+
+use serde::{Serialize, Serializer};
+
+use crate::{disassembler::decode, kernel_file::KernelFile, patcher::PatchInfo};
+
+fn hex_address<S: Serializer>(address: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match address {
+        Some(address) => serializer.serialize_str(&format!("0x{:x}", address)),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// One disassembled instruction, or one run of data.
+#[derive(Debug, Serialize)]
+pub struct Line {
+    pub offset: usize,
+    pub bytes: String,
+    pub text: String,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08x}  {:8}  {}", self.offset, self.bytes, self.text)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedPatch {
+    pub name: String,
+    pub offset: usize,
+    /// Kernel virtual address, known when kallsyms gave the address of `_text`.
+    #[serde(serialize_with = "hex_address")]
+    pub address: Option<u64>,
+    pub original: String,
+    pub patched: String,
+    /// Empty when the original bytes are zero filled.
+    pub before: Vec<Line>,
+    /// Empty when the patch zero fills, as unpatching does with the hookers.
+    pub after: Vec<Line>,
+}
+
+/// Disassembles `bytes` placed at file offset `offset`, printing `data` ranges as such.
+fn disassemble(bytes: &[u8], offset: usize, patch: Option<&PatchInfo>) -> Vec<Line> {
+    let data = patch.map_or(&[][..], |patch| &patch.data[..]);
+    let relocated = patch.and_then(|patch| patch.relocated);
+    let mut lines = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if let Some(range) = data.iter().find(|range| range.contains(&pos)) {
+            let data = &bytes[range.clone()];
+            let text = match std::str::from_utf8(data) {
+                Ok(text) if data.iter().all(|&b| b == 0 || b.is_ascii_graphic()) => {
+                    format!(".ascii {:?}", text)
+                }
+                _ => format!("0x{:x} data bytes", data.len()),
+            };
+            lines.push(Line {
+                offset: offset + pos,
+                bytes: hex_bytes(data),
+                text,
+            });
+            pos = range.end;
+            continue;
+        }
+        let chunk = &bytes[pos..(pos + 4).min(bytes.len())];
+        let text = match chunk.try_into() {
+            Ok(word) => {
+                let word = u32::from_le_bytes(word);
+                let mut text = decode(word, (offset + pos) as u64).to_string();
+                if let Some((at, from)) = relocated {
+                    if at == pos {
+                        text += &format!("    // relocated from 0x{:x}", from);
+                    }
+                }
+                text
+            }
+            Err(_) => ".byte".to_string(),
+        };
+        lines.push(Line {
+            offset: offset + pos,
+            bytes: hex_bytes(chunk),
+            text,
+        });
+        pos += chunk.len();
+    }
+    lines
+}
+
+impl PlannedPatch {
+    pub fn new(image: &[u8], patch: &PatchInfo, text_base: Option<u64>) -> Self {
+        let end = (patch.offset + patch.bytes.len()).min(image.len());
+        let original = &image[patch.offset.min(end)..end];
+        let before = if original.iter().all(|&b| b == 0) {
+            vec![]
+        } else {
+            disassemble(original, patch.offset, None)
+        };
+        let after = if patch.bytes.iter().all(|&b| b == 0) {
+            vec![]
+        } else {
+            disassemble(&patch.bytes, patch.offset, Some(patch))
+        };
+        PlannedPatch {
+            name: patch.name.clone(),
+            offset: patch.offset,
+            address: text_base.map(|base| base + patch.offset as u64),
+            original: hex_bytes(original),
+            patched: hex_bytes(&patch.bytes),
+            before,
+            after,
+        }
+    }
+}
+
+impl fmt::Display for PlannedPatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: file offset 0x{:x}", self.name, self.offset)?;
+        if let Some(address) = self.address {
+            write!(f, ", VA 0x{:x}", address)?;
+        }
+        writeln!(f, ", 0x{:x} bytes", self.patched.len() / 2)?;
+        if self.before.is_empty() {
+            writeln!(f, "  before: zero filled")?;
+        } else {
+            writeln!(f, "  before:")?;
+            for line in &self.before {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+        if self.after.is_empty() {
+            writeln!(f, "  after: zero filled")?;
+        } else {
+            writeln!(f, "  after:")?;
+            for line in &self.after {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchReport {
+    pub image: String,
+    pub format: String,
+    pub patches: Vec<PlannedPatch>,
+}
+
+impl PatchReport {
+    pub fn new(file: &KernelFile, patches: &[PatchInfo], text_base: Option<u64>) -> Self {
+        PatchReport {
+            image: file.path.to_string_lossy().into_owned(),
+            format: format!("{} ({})", file.container, file.compression),
+            patches: patches
+                .iter()
+                .map(|patch| PlannedPatch::new(&file.kernel, patch, text_base))
+                .collect(),
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Patch plan for {} [{}]", self.image, self.format)?;
+        for patch in &self.patches {
+            write!(f, "{}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        patcher::Patcher,
+        test_util::{do_execve_hook, test_kernel, TempPath},
+    };
+
+    #[test]
+    fn test_patch_report() {
+        let image_path = TempPath::new("patch_report");
+        let mut image = test_kernel(0x2000);
+        image[0x100..0x104].copy_from_slice(&0xD10103FFu32.to_le_bytes());
+        fs::write(&image_path, &image).unwrap();
+
+        let patcher = &mut Patcher::new(image_path.to_str().unwrap()).unwrap();
+        patcher.patch_do_execve(do_execve_hook()).unwrap();
+        let report = PatchReport::new(patcher.file(), patcher.patches(), Some(0xFFFFFFC008000000));
+
+        let hooker = &report.patches[0];
+        assert_eq!(hooker.name, "do_execve hooker");
+        assert_eq!(hooker.address, Some(0xFFFFFFC008001000));
+        assert!(hooker.before.is_empty());
+        assert_eq!(
+            hooker.after[0].text,
+            "SUB SP, SP, #0x40    // relocated from 0x100"
+        );
+        // The root key, its terminator and the alignment padding after it.
+        let key = hooker.after.last().unwrap();
+        assert_eq!(
+            key.text,
+            format!(".ascii {:?}", "a".repeat(48) + "\0\0\0\0")
+        );
+
+        let entry = &report.patches[1];
+        assert_eq!(entry.original, "ff0301d1");
+        assert_eq!(entry.before[0].text, "SUB SP, SP, #0x40");
+        assert_eq!(entry.after[0].text, "B 0x1000");
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["patches"][1]["address"], "0xffffffc008000100");
+    }
+}
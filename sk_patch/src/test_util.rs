@@ -0,0 +1,166 @@
+//! Inputs shared by the tests of several modules.
+
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    cred_layout::CredLayout,
+    elf::{EM_AARCH64, PHDR_SIZE, PT_LOAD, SHDR_SIZE, SHF_EXECINSTR, SHT_SYMTAB, SYM_SIZE},
+    hook::DoExecveHook,
+    image_header::{HEADER_SIZE, MAGIC, MAGIC_OFFSET},
+    privileges::Privileges,
+};
+
+/// A zero filled kernel of `size` bytes with a 4K page Image header.
+pub fn test_kernel(size: usize) -> Vec<u8> {
+    let mut kernel = vec![0u8; size];
+    kernel[8..16].copy_from_slice(&0x80000u64.to_le_bytes());
+    kernel[16..24].copy_from_slice(&(size as u64).to_le_bytes());
+    kernel[24..32].copy_from_slice(&0xAu64.to_le_bytes());
+    kernel[MAGIC_OFFSET..HEADER_SIZE - 4].copy_from_slice(MAGIC);
+    kernel
+}
+
+/// A vmlinux with `.text` and `.data` segments and a few symbols.
+pub fn test_vmlinux(text: &[u8], base: u64) -> Vec<u8> {
+    let shstrtab = b"\0.text\0.data\0.symtab\0.strtab\0.shstrtab\0";
+    let strtab = b"\0_text\0do_execveat_common\0jiffies\0";
+    let text_offset = 0x1000;
+    let data_offset = text_offset + text.len();
+    let data = [0x5Au8; 0x100];
+    let symtab_offset = data_offset + data.len();
+    let symbols: &[(u32, u8, u16, u64)] = &[
+        (0, 0, 0, 0),
+        (1, 0x10, 1, base),
+        (7, 0x12, 1, base + 0x100),
+        (26, 0x11, 2, base + 0x20000),
+    ];
+    let strtab_offset = symtab_offset + symbols.len() * SYM_SIZE;
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let shoff = shstrtab_offset + shstrtab.len();
+
+    let mut elf = vec![0u8; shoff + 6 * SHDR_SIZE];
+    let put = |elf: &mut Vec<u8>, offset: usize, value: &[u8]| {
+        elf[offset..offset + value.len()].copy_from_slice(value)
+    };
+    put(&mut elf, 0, &[0x7F, b'E', b'L', b'F', 2, 1, 1]);
+    put(&mut elf, 18, &EM_AARCH64.to_le_bytes());
+    put(&mut elf, 32, &64u64.to_le_bytes());
+    put(&mut elf, 40, &(shoff as u64).to_le_bytes());
+    put(&mut elf, 56, &2u16.to_le_bytes());
+    put(&mut elf, 60, &6u16.to_le_bytes());
+    put(&mut elf, 62, &5u16.to_le_bytes());
+    for (idx, (offset, address, size)) in [
+        (text_offset, base, text.len()),
+        (data_offset, base + 0x20000, data.len()),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let phdr = 64 + idx * PHDR_SIZE;
+        put(&mut elf, phdr, &PT_LOAD.to_le_bytes());
+        put(&mut elf, phdr + 8, &(offset as u64).to_le_bytes());
+        put(&mut elf, phdr + 16, &address.to_le_bytes());
+        put(&mut elf, phdr + 32, &(size as u64).to_le_bytes());
+    }
+    put(&mut elf, text_offset, text);
+    put(&mut elf, data_offset, &data);
+    for (idx, &(name, info, shndx, value)) in symbols.iter().enumerate() {
+        let sym = symtab_offset + idx * SYM_SIZE;
+        put(&mut elf, sym, &name.to_le_bytes());
+        put(&mut elf, sym + 4, &[info]);
+        put(&mut elf, sym + 6, &shndx.to_le_bytes());
+        put(&mut elf, sym + 8, &value.to_le_bytes());
+    }
+    put(&mut elf, strtab_offset, strtab);
+    put(&mut elf, shstrtab_offset, shstrtab);
+    let sections: &[(u32, u32, u64, u64, usize, usize, u32)] = &[
+        (0, 0, 0, 0, 0, 0, 0),
+        (1, 1, SHF_EXECINSTR | 2, base, text_offset, text.len(), 0),
+        (7, 1, 3, base + 0x20000, data_offset, data.len(), 0),
+        (
+            13,
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab_offset,
+            symbols.len() * SYM_SIZE,
+            4,
+        ),
+        (21, 3, 0, 0, strtab_offset, strtab.len(), 0),
+        (29, 3, 0, 0, shstrtab_offset, shstrtab.len(), 0),
+    ];
+    for (idx, &(name, kind, flags, address, offset, size, link)) in sections.iter().enumerate() {
+        let shdr = shoff + idx * SHDR_SIZE;
+        put(&mut elf, shdr, &name.to_le_bytes());
+        put(&mut elf, shdr + 4, &kind.to_le_bytes());
+        put(&mut elf, shdr + 8, &flags.to_le_bytes());
+        put(&mut elf, shdr + 16, &address.to_le_bytes());
+        put(&mut elf, shdr + 24, &(offset as u64).to_le_bytes());
+        put(&mut elf, shdr + 32, &(size as u64).to_le_bytes());
+        put(&mut elf, shdr + 40, &link.to_le_bytes());
+    }
+    elf
+}
+
+/// A do_execve hook of one root key at 0x100 with its hooker at 0x1000, tests
+/// override the fields they are about.
+pub fn do_execve_hook() -> DoExecveHook {
+    DoExecveHook {
+        root_keys: vec!["a".repeat(48)],
+        allowed_uids: vec![],
+        digest_key: None,
+        privileges: Privileges::ROOT,
+        hooker_entry: 0x1000,
+        hookee_entry: 0x100,
+        cred_offset: 0x618,
+        cred_layout: CredLayout::DEFAULT,
+        seccomp_offset: Some(0x800),
+    }
+}
+
+/// A path in the temp dir unique to the test run, the file or directory there and
+/// the `.bak` backup next to it are removed when dropped, whether the test passed or not.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        TempPath(env::temp_dir().join(format!("sk_patch_test_{}_{}", name, process::id())))
+    }
+
+    /// Where the patcher backs the file up.
+    pub fn backup(&self) -> PathBuf {
+        let mut path = self.0.as_os_str().to_owned();
+        path.push(".bak");
+        PathBuf::from(path)
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            fs::remove_dir_all(&self.0).ok();
+        } else {
+            fs::remove_file(&self.0).ok();
+        }
+        fs::remove_file(self.backup()).ok();
+    }
+}
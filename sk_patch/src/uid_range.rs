@@ -0,0 +1,126 @@
+//! Uids allowed to use the root keys, written as a uid, a `first-last` range or an
+//! Android app name such as `u0_a123`, where `*` matches any user or any app.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+
+/// Uids of Android user `n` are `n * AID_USER_OFFSET + app id`.
+pub const AID_USER_OFFSET: u32 = 100000;
+const AID_APP_START: u32 = 10000;
+const AID_APP_END: u32 = 19999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidRange {
+    pub first: u32,
+    pub last: u32,
+    /// Whether the range applies to the app id of every user, `uid % AID_USER_OFFSET`.
+    pub per_user: bool,
+}
+
+impl FromStr for UidRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let number = |s: &str| {
+            s.parse::<u32>()
+                .map_err(|_| anyhow!("{} is not a uid, a uid range or an app like u0_a123", s))
+        };
+        let range = if let Some((user, app)) = s.strip_prefix('u').and_then(|s| s.split_once("_a"))
+        {
+            let (first, last) = match app {
+                "*" => (AID_APP_START, AID_APP_END),
+                app => {
+                    let app = number(app)?;
+                    if app > AID_APP_END - AID_APP_START {
+                        return Err(anyhow!("{} is past the last app id", s));
+                    }
+                    (AID_APP_START + app, AID_APP_START + app)
+                }
+            };
+            match user {
+                "*" => UidRange {
+                    first,
+                    last,
+                    per_user: true,
+                },
+                user => {
+                    let base = number(user)?
+                        .checked_mul(AID_USER_OFFSET)
+                        .ok_or_else(|| anyhow!("{} is past the last user", s))?;
+                    UidRange {
+                        first: base + first,
+                        last: base + last,
+                        per_user: false,
+                    }
+                }
+            }
+        } else {
+            let (first, last) = s.split_once('-').unwrap_or((s, s));
+            UidRange {
+                first: number(first)?,
+                last: number(last)?,
+                per_user: false,
+            }
+        };
+        if range.first > range.last {
+            return Err(anyhow!("{} is an empty uid range", s));
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for UidRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.per_user {
+            return match (self.first, self.last) {
+                (AID_APP_START, AID_APP_END) => write!(f, "u*_a*"),
+                (first, last) if first == last => write!(f, "u*_a{}", first - AID_APP_START),
+                (first, last) => write!(f, "app ids {}-{}", first, last),
+            };
+        }
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uid_range() {
+        let parse = |s: &str| s.parse::<UidRange>().unwrap();
+        assert_eq!(
+            parse("2000"),
+            UidRange {
+                first: 2000,
+                last: 2000,
+                per_user: false
+            }
+        );
+        assert_eq!(
+            (parse("10000-10099").first, parse("10000-10099").last),
+            (10000, 10099)
+        );
+        assert_eq!(parse("u10_a123").first, 1010123);
+        assert_eq!(
+            parse("u0_a*"),
+            UidRange {
+                first: 10000,
+                last: 19999,
+                per_user: false
+            }
+        );
+        assert!(parse("u*_a123").per_user);
+        for s in ["2000", "10000-10099", "u*_a123", "u*_a*"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+        for s in ["", "shell", "20-10", "u0_a10000", "u0_", "u99999_a1"] {
+            assert!(s.parse::<UidRange>().is_err(), "{}", s);
+        }
+    }
+}